@@ -0,0 +1,425 @@
+// server configuration, loaded once at startup
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::config::pricing::PriceTable;
+use crate::config::redaction::RedactionRules;
+use crate::config::routing::RoutingTable;
+use crate::error::IronError;
+use crate::prompts::library::{PromptLibrary, DEFAULT_PROMPTS_DIR};
+use crate::prompts::profile::DEFAULT_PROFILE;
+
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+pub const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_ANTHROPIC_MODEL: &str = "claude-sonnet-4-5";
+pub const DEFAULT_ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com";
+pub const DEFAULT_LOCAL_MODEL: &str = "llama3.2";
+// the provider keys from_env reads; the assistant's commands run without them
+pub const API_KEY_VARS: &[&str] = &["OPENAI_API_KEY", "ANTHROPIC_API_KEY", "IRON_LOCAL_API_KEY"];
+// relative to $HOME
+pub const DEFAULT_SESSION_FILE: &str = ".iron/session.json";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    OpenAi,
+    Anthropic,
+    // a second openai-compatible server (ollama, llama.cpp, vllm), e.g. for cheap auxiliary calls
+    Local,
+    Mock,
+}
+
+impl ProviderKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "openai" => Some(ProviderKind::OpenAi),
+            "anthropic" => Some(ProviderKind::Anthropic),
+            "local" => Some(ProviderKind::Local),
+            "mock" => Some(ProviderKind::Mock),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProviderSettings {
+    pub api_key: Option<String>,
+    pub model: String,
+    pub base_url: String,
+    // only consulted for openai-compatible servers, where local models often lack these
+    pub supports_tools: bool,
+    pub supports_streaming: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmConfig {
+    // provider used by sessions that haven't picked one
+    pub provider: ProviderKind,
+    pub openai: ProviderSettings,
+    pub anthropic: ProviderSettings,
+    // only set up when IRON_LOCAL_BASE_URL is
+    pub local: Option<ProviderSettings>,
+    pub retry: RetryConfig,
+    // record or replay provider traffic, for deterministic test runs
+    pub cassette: Option<CassetteConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    // talk to the providers and write everything down
+    Record,
+    // never talk to the providers, answer from the cassette
+    Replay,
+}
+
+#[derive(Debug, Clone)]
+pub struct CassetteConfig {
+    pub path: PathBuf,
+    pub mode: CassetteMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    // total tries per call, including the first one
+    pub max_attempts: u32,
+    // the first backoff; doubles on every retry, with jitter
+    pub base_delay_ms: u64,
+    // no single wait is longer than this. a Retry-After beyond it fails the call instead of stalling the chat
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn settings(&self, kind: ProviderKind) -> Option<&ProviderSettings> {
+        match kind {
+            ProviderKind::OpenAi => Some(&self.openai),
+            ProviderKind::Anthropic => Some(&self.anthropic),
+            ProviderKind::Local => self.local.as_ref(),
+            ProviderKind::Mock => None,
+        }
+    }
+
+    pub fn settings_mut(&mut self, kind: ProviderKind) -> Option<&mut ProviderSettings> {
+        match kind {
+            ProviderKind::OpenAi => Some(&mut self.openai),
+            ProviderKind::Anthropic => Some(&mut self.anthropic),
+            ProviderKind::Local => self.local.as_mut(),
+            ProviderKind::Mock => None,
+        }
+    }
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            provider: ProviderKind::OpenAi,
+            openai: ProviderSettings {
+                api_key: None,
+                model: DEFAULT_MODEL.to_string(),
+                base_url: DEFAULT_OPENAI_BASE_URL.to_string(),
+                supports_tools: true,
+                supports_streaming: true,
+            },
+            anthropic: ProviderSettings {
+                api_key: None,
+                model: DEFAULT_ANTHROPIC_MODEL.to_string(),
+                base_url: DEFAULT_ANTHROPIC_BASE_URL.to_string(),
+                supports_tools: true,
+                supports_streaming: true,
+            },
+            local: None,
+            retry: RetryConfig::default(),
+            cassette: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContextConfig {
+    // overrides the model's own context window, e.g. for a local server started with a small -c
+    pub max_context_tokens: Option<usize>,
+    // kept free for the model's reply
+    pub reserved_output_tokens: usize,
+    // command outputs longer than this get their middle cut out once the context is over budget
+    pub max_output_tokens: usize,
+    // older history gets summarized once the context fills this much of the budget; 0 turns it off
+    pub compact_at_percent: usize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            max_context_tokens: None,
+            reserved_output_tokens: 4_096,
+            max_output_tokens: 4_000,
+            compact_at_percent: 75,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecConfig {
+    // how long a command may run when neither it nor the session asks for something else
+    pub command_timeout_secs: u64,
+    // the most any command or session can ask for
+    pub max_command_timeout_secs: u64,
+    // between SIGTERM and SIGKILL
+    pub kill_grace_ms: u64,
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        Self {
+            command_timeout_secs: 120,
+            max_command_timeout_secs: 1_800,
+            kill_grace_ms: 2_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AppConfig {
+    pub llm: LlmConfig,
+    pub context: ContextConfig,
+    pub prices: PriceTable,
+    pub prompts: PromptLibrary,
+    // where auxiliary calls (mock user, summaries) go instead of the session's model
+    pub routes: RoutingTable,
+    // secrets masked in everything sent to a provider
+    pub redaction: RedactionRules,
+    pub exec: ExecConfig,
+    // where the chat's profile and preferences are kept across restarts; None keeps them in memory only
+    pub session_file: Option<PathBuf>,
+}
+
+impl AppConfig {
+    // reads IRON_* variables and provider api keys from the environment; dotenv should already be loaded
+    pub fn from_env() -> Result<Self, IronError> {
+        let mut llm = LlmConfig::default();
+
+        if let Ok(provider) = env::var("IRON_LLM_PROVIDER") {
+            llm.provider = ProviderKind::parse(&provider).ok_or_else(|| {
+                IronError::Config(format!("Unknown IRON_LLM_PROVIDER: {}", provider))
+            })?;
+        }
+
+        // an empty key is how you say "this local server doesn't need one"
+        llm.openai.api_key = env_key("OPENAI_API_KEY");
+        if let Ok(model) = env::var("IRON_OPENAI_MODEL") {
+            llm.openai.model = model;
+        }
+        if let Ok(base_url) = env::var("OPENAI_BASE_URL") {
+            llm.openai.base_url = base_url;
+        }
+        if let Some(tools) = env_flag("IRON_OPENAI_TOOLS")? {
+            llm.openai.supports_tools = tools;
+        }
+        if let Some(streaming) = env_flag("IRON_OPENAI_STREAMING")? {
+            llm.openai.supports_streaming = streaming;
+        }
+
+        // and for anthropic it's a missing one, which gets a clearer error than the api's 401
+        llm.anthropic.api_key = env_key("ANTHROPIC_API_KEY");
+        if let Ok(model) = env::var("IRON_ANTHROPIC_MODEL") {
+            llm.anthropic.model = model;
+        }
+        if let Ok(base_url) = env::var("ANTHROPIC_BASE_URL") {
+            llm.anthropic.base_url = base_url;
+        }
+
+        // no api key: local servers usually don't check one
+        if let Ok(base_url) = env::var("IRON_LOCAL_BASE_URL") {
+            llm.local = Some(ProviderSettings {
+                api_key: env_key("IRON_LOCAL_API_KEY"),
+                model: env::var("IRON_LOCAL_MODEL")
+                    .unwrap_or_else(|_| DEFAULT_LOCAL_MODEL.to_string()),
+                base_url,
+                supports_tools: true,
+                supports_streaming: true,
+            });
+        }
+
+        // IRON_LLM_MODEL is shorthand for "the model of the default provider"
+        if let Ok(model) = env::var("IRON_LLM_MODEL") {
+            if let Some(settings) = llm.settings_mut(llm.provider) {
+                settings.model = model;
+            }
+        }
+
+        if let Some(attempts) = env_usize("IRON_LLM_MAX_ATTEMPTS")? {
+            // zero attempts would mean never calling the model at all
+            llm.retry.max_attempts = attempts.max(1) as u32;
+        }
+
+        // replay is the default, so pointing at a cassette never overwrites it by accident
+        if let Ok(path) = env::var("IRON_CASSETTE") {
+            let mode = match env::var("IRON_CASSETTE_MODE").as_deref() {
+                Ok("record") => CassetteMode::Record,
+                Ok("replay") | Err(_) => CassetteMode::Replay,
+                Ok(other) => {
+                    return Err(IronError::Config(format!(
+                        "IRON_CASSETTE_MODE must be record or replay, got {}",
+                        other
+                    )))
+                }
+            };
+            llm.cassette = Some(CassetteConfig {
+                path: PathBuf::from(path),
+                mode,
+            });
+        }
+
+        let mut context = ContextConfig::default();
+        if let Some(tokens) = env_usize("IRON_CONTEXT_TOKENS")? {
+            context.max_context_tokens = Some(tokens);
+        }
+        if let Some(tokens) = env_usize("IRON_MAX_OUTPUT_TOKENS")? {
+            context.max_output_tokens = tokens;
+        }
+        if let Some(percent) = env_usize("IRON_COMPACT_AT_PERCENT")? {
+            context.compact_at_percent = percent;
+        }
+
+        let mut exec = ExecConfig::default();
+        if let Some(secs) = env_usize("IRON_COMMAND_TIMEOUT_SECS")? {
+            exec.command_timeout_secs = secs as u64;
+        }
+        if let Some(secs) = env_usize("IRON_MAX_COMMAND_TIMEOUT_SECS")? {
+            exec.max_command_timeout_secs = secs as u64;
+        }
+        if let Some(ms) = env_usize("IRON_KILL_GRACE_MS")? {
+            exec.kill_grace_ms = ms as u64;
+        }
+        if exec.command_timeout_secs == 0 || exec.max_command_timeout_secs == 0 {
+            return Err(IronError::Config(
+                "command timeouts must be positive".into(),
+            ));
+        }
+
+        let mut prices = PriceTable::default();
+        if let Ok(path) = env::var("IRON_MODEL_PRICES") {
+            prices.load_overrides(&path)?;
+        }
+
+        let mut routes = RoutingTable::default();
+        if let Ok(path) = env::var("IRON_MODEL_ROUTES") {
+            routes.load(&path)?;
+        }
+        for (source, route) in routes.routes() {
+            if let Some(kind) = route.provider {
+                if kind != ProviderKind::Mock && llm.settings(kind).is_none() {
+                    return Err(IronError::Config(format!(
+                        "The {:?} route uses the {:?} provider, which isn't configured",
+                        source, kind
+                    )));
+                }
+            }
+        }
+
+        let mut redaction = RedactionRules::default();
+        if let Some(enabled) = env_flag("IRON_REDACTION")? {
+            redaction.enabled = enabled;
+        }
+        if let Ok(path) = env::var("IRON_REDACTION_PATTERNS") {
+            redaction.load_patterns(&path)?;
+        }
+
+        let prompts_dir = env::var("IRON_PROMPTS_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_PROMPTS_DIR));
+        let default_profile =
+            env::var("IRON_PROMPT_PROFILE").unwrap_or_else(|_| DEFAULT_PROFILE.to_string());
+        let prompts = PromptLibrary::load(&prompts_dir, &default_profile)?;
+
+        // an empty IRON_SESSION_FILE turns saving off
+        let session_file = match env::var("IRON_SESSION_FILE") {
+            Ok(path) if path.is_empty() => None,
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => env::var("HOME")
+                .ok()
+                .map(|home| Path::new(&home).join(DEFAULT_SESSION_FILE)),
+        };
+
+        Ok(Self {
+            llm,
+            context,
+            prices,
+            prompts,
+            routes,
+            redaction,
+            exec,
+            session_file,
+        })
+    }
+}
+
+// set but empty counts as unset, e.g. `ANTHROPIC_API_KEY=` left over in a .env file
+fn env_key(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|key| !key.is_empty())
+}
+
+fn env_flag(name: &str) -> Result<Option<bool>, IronError> {
+    match env::var(name) {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(Some(true)),
+            "0" | "false" | "no" | "off" => Ok(Some(false)),
+            _ => Err(IronError::Config(format!(
+                "{} must be true or false, got {}",
+                name, value
+            ))),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+fn env_usize(name: &str) -> Result<Option<usize>, IronError> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| IronError::Config(format!("{} must be a number, got {}", name, value))),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every test gets variables of its own, since tests run in parallel in one process
+    #[test]
+    fn empty_keys_count_as_unset() {
+        env::set_var("IRON_TEST_EMPTY_KEY", "");
+        env::set_var("IRON_TEST_SET_KEY", "sk-test");
+        assert_eq!(env_key("IRON_TEST_EMPTY_KEY"), None);
+        assert_eq!(env_key("IRON_TEST_SET_KEY"), Some("sk-test".to_string()));
+        assert_eq!(env_key("IRON_TEST_MISSING_KEY"), None);
+    }
+
+    #[test]
+    fn flags_take_the_usual_spellings() {
+        for (value, expected) in [("1", true), ("Yes", true), (" on ", true), ("false", false)] {
+            env::set_var("IRON_TEST_FLAG", value);
+            assert_eq!(
+                env_flag("IRON_TEST_FLAG").unwrap(),
+                Some(expected),
+                "{}",
+                value
+            );
+        }
+        env::set_var("IRON_TEST_BAD_FLAG", "maybe");
+        assert!(env_flag("IRON_TEST_BAD_FLAG").is_err());
+        assert_eq!(env_flag("IRON_TEST_MISSING_FLAG").unwrap(), None);
+    }
+}
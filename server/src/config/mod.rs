@@ -0,0 +1,5 @@
+// exports config
+pub mod app_config;
+pub mod pricing;
+pub mod redaction;
+pub mod routing;
@@ -0,0 +1,228 @@
+// per-model token prices, so usage can be shown in dollars
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::error::IronError;
+use crate::llm::provider::TokenUsage;
+
+// usd per million tokens
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+    // prompt cache reads; None means they cost the same as regular input
+    #[serde(default)]
+    pub cached_input_per_mtok: Option<f64>,
+    // prompt cache writes, which anthropic charges extra for; None means the regular input price
+    #[serde(default)]
+    pub cache_write_per_mtok: Option<f64>,
+}
+
+impl ModelPrice {
+    const fn new(input_per_mtok: f64, output_per_mtok: f64, cached_input_per_mtok: f64) -> Self {
+        Self {
+            input_per_mtok,
+            output_per_mtok,
+            cached_input_per_mtok: Some(cached_input_per_mtok),
+            cache_write_per_mtok: None,
+        }
+    }
+
+    const fn with_cache_writes(mut self, cache_write_per_mtok: f64) -> Self {
+        self.cache_write_per_mtok = Some(cache_write_per_mtok);
+        self
+    }
+
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        let cached = usage.cached_prompt_tokens.min(usage.prompt_tokens);
+        let written = usage
+            .cache_write_prompt_tokens
+            .min(usage.prompt_tokens - cached);
+        let uncached = usage.prompt_tokens - cached - written;
+        let cached_price = self.cached_input_per_mtok.unwrap_or(self.input_per_mtok);
+        let write_price = self.cache_write_per_mtok.unwrap_or(self.input_per_mtok);
+        (uncached as f64 * self.input_per_mtok
+            + cached as f64 * cached_price
+            + written as f64 * write_price
+            + usage.completion_tokens as f64 * self.output_per_mtok)
+            / 1_000_000.0
+    }
+}
+
+// list prices at the time of writing; override or extend them with IRON_MODEL_PRICES. a variant priced
+// differently from its family (o3-mini vs o3) needs an entry of its own, or it's billed as the family.
+// anthropic's cache writes are the 5-minute ones, 1.25x input
+const DEFAULT_PRICES: &[(&str, ModelPrice)] = &[
+    ("gpt-4o-mini", ModelPrice::new(0.15, 0.60, 0.075)),
+    ("gpt-4o", ModelPrice::new(2.50, 10.00, 1.25)),
+    ("gpt-4.1-nano", ModelPrice::new(0.10, 0.40, 0.025)),
+    ("gpt-4.1-mini", ModelPrice::new(0.40, 1.60, 0.10)),
+    ("gpt-4.1", ModelPrice::new(2.00, 8.00, 0.50)),
+    ("gpt-5-nano", ModelPrice::new(0.05, 0.40, 0.005)),
+    ("gpt-5-mini", ModelPrice::new(0.25, 2.00, 0.025)),
+    ("gpt-5", ModelPrice::new(1.25, 10.00, 0.125)),
+    ("o4-mini", ModelPrice::new(1.10, 4.40, 0.275)),
+    ("o3-mini", ModelPrice::new(1.10, 4.40, 0.55)),
+    ("o3", ModelPrice::new(2.00, 8.00, 0.50)),
+    ("o1-mini", ModelPrice::new(1.10, 4.40, 0.55)),
+    ("o1", ModelPrice::new(15.00, 60.00, 7.50)),
+    (
+        "claude-haiku-4-5",
+        ModelPrice::new(1.00, 5.00, 0.10).with_cache_writes(1.25),
+    ),
+    (
+        "claude-3-5-haiku",
+        ModelPrice::new(0.80, 4.00, 0.08).with_cache_writes(1.00),
+    ),
+    (
+        "claude-sonnet-4",
+        ModelPrice::new(3.00, 15.00, 0.30).with_cache_writes(3.75),
+    ),
+    (
+        "claude-3-7-sonnet",
+        ModelPrice::new(3.00, 15.00, 0.30).with_cache_writes(3.75),
+    ),
+    (
+        "claude-opus-4-5",
+        ModelPrice::new(5.00, 25.00, 0.50).with_cache_writes(6.25),
+    ),
+    (
+        "claude-opus-4",
+        ModelPrice::new(15.00, 75.00, 1.50).with_cache_writes(18.75),
+    ),
+];
+
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    // keyed by model name prefix, so dated snapshots (gpt-4o-2024-08-06) share their family's price. a
+    // prefix only matches whole dash-separated segments, so o1 isn't the price of o10
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        Self {
+            prices: DEFAULT_PRICES
+                .iter()
+                .map(|(model, price)| (model.to_string(), *price))
+                .collect(),
+        }
+    }
+}
+
+impl PriceTable {
+    // a json object of model prefix -> ModelPrice, merged over the defaults
+    pub fn load_overrides(&mut self, path: &str) -> Result<(), IronError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| IronError::Config(format!("Failed to read {}: {}", path, e)))?;
+        let overrides: HashMap<String, ModelPrice> = serde_json::from_str(&text)
+            .map_err(|e| IronError::Config(format!("Invalid price table {}: {}", path, e)))?;
+        self.prices.extend(overrides);
+        Ok(())
+    }
+
+    // the longest matching prefix wins, so gpt-4o-mini isn't priced as gpt-4o
+    pub fn price_for(&self, model: &str) -> Option<&ModelPrice> {
+        self.prices
+            .iter()
+            .filter(|(prefix, _)| {
+                model
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| price)
+    }
+
+    // None for models we don't know the price of (local models, mostly)
+    pub fn cost(&self, model: &str, usage: &TokenUsage) -> Option<f64> {
+        self.price_for(model).map(|price| price.cost(usage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt: u64, cached: u64, written: u64, completion: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: prompt,
+            completion_tokens: completion,
+            cached_prompt_tokens: cached,
+            cache_write_prompt_tokens: written,
+        }
+    }
+
+    fn price_of(model: &str) -> Option<ModelPrice> {
+        PriceTable::default().price_for(model).copied()
+    }
+
+    #[test]
+    fn variants_get_their_own_price_and_snapshots_their_familys() {
+        assert_eq!(price_of("o3-mini").unwrap().input_per_mtok, 1.10);
+        assert_eq!(price_of("o3-mini-2025-01-31").unwrap().input_per_mtok, 1.10);
+        assert_eq!(price_of("o3-2025-04-16").unwrap().input_per_mtok, 2.00);
+        assert_eq!(price_of("gpt-4o-mini").unwrap().input_per_mtok, 0.15);
+        assert_eq!(price_of("gpt-4o-2024-08-06").unwrap().input_per_mtok, 2.50);
+        assert_eq!(
+            price_of("claude-opus-4-5-20251101").unwrap().input_per_mtok,
+            5.00
+        );
+        assert_eq!(
+            price_of("claude-opus-4-1-20250805").unwrap().input_per_mtok,
+            15.00
+        );
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_segments() {
+        assert!(price_of("o10").is_none());
+        assert!(price_of("gpt-4omni").is_none());
+        assert!(price_of("llama3.1:8b").is_none());
+    }
+
+    #[test]
+    fn cache_reads_and_writes_have_their_own_rates() {
+        let price = price_of("claude-sonnet-4-5").unwrap();
+        // 1M uncached, 2M read from the cache, 1M written to it, 1M out
+        let cost = price.cost(&usage(4_000_000, 2_000_000, 1_000_000, 1_000_000));
+        assert!(
+            (cost - (3.00 + 2.0 * 0.30 + 3.75 + 15.00)).abs() < 1e-9,
+            "{}",
+            cost
+        );
+
+        // without a cache write rate, writes cost what input does
+        let price = price_of("gpt-4o").unwrap();
+        let cost = price.cost(&usage(2_000_000, 0, 1_000_000, 0));
+        assert!((cost - 5.00).abs() < 1e-9, "{}", cost);
+    }
+
+    #[test]
+    fn overrides_are_merged_over_the_defaults() {
+        let path = std::env::temp_dir().join(format!("iron-prices-{}.json", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"{"o3": {"input_per_mtok": 1.0, "output_per_mtok": 4.0}, "llama": {"input_per_mtok": 0.0, "output_per_mtok": 0.0}}"#,
+        )
+        .unwrap();
+        let mut prices = PriceTable::default();
+        prices.load_overrides(path.to_str().unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(prices.price_for("o3").unwrap().input_per_mtok, 1.0);
+        assert_eq!(
+            prices.cost("llama-3-8b", &usage(1000, 0, 0, 1000)),
+            Some(0.0)
+        );
+        assert_eq!(prices.price_for("o3-mini").unwrap().input_per_mtok, 1.10);
+    }
+
+    #[test]
+    fn unreadable_overrides_are_a_config_error() {
+        let mut prices = PriceTable::default();
+        let err = prices.load_overrides("/no/such/prices.json").unwrap_err();
+        assert!(matches!(err, IronError::Config(_)), "{}", err);
+    }
+}
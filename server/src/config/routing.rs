@@ -0,0 +1,112 @@
+// which provider and model auxiliary calls go to, so the mock user and summaries can use something cheap
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::config::app_config::ProviderKind;
+use crate::error::IronError;
+use crate::llm::provider::SamplingParams;
+use crate::state::usage::UsageSource;
+
+// anything left out comes from the session: its provider, that provider's model
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct ModelRoute {
+    #[serde(default)]
+    pub provider: Option<ProviderKind>,
+    #[serde(default)]
+    pub model: Option<String>,
+    // a routed call doesn't use the session's sampling preferences, they're tuned for its model
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    routes: HashMap<UsageSource, ModelRoute>,
+}
+
+impl RoutingTable {
+    // a json object of call source -> ModelRoute, e.g.
+    // {"mock_user": {"provider": "local", "model": "llama3.2"}, "compaction": {"provider": "openai", "model": "gpt-4o-mini"}}
+    pub fn load(&mut self, path: &str) -> Result<(), IronError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| IronError::Config(format!("Failed to read {}: {}", path, e)))?;
+        let routes: HashMap<UsageSource, ModelRoute> = serde_json::from_str(&text)
+            .map_err(|e| IronError::Config(format!("Invalid model routes {}: {}", path, e)))?;
+        // the assistant follows the session's preferences; a route would silently override them
+        if routes.contains_key(&UsageSource::Assistant) {
+            return Err(IronError::Config(format!(
+                "{} routes the assistant, whose model is picked with IRON_LLM_PROVIDER, IRON_LLM_MODEL or the session's preferences",
+                path
+            )));
+        }
+        self.routes.extend(routes);
+        Ok(())
+    }
+
+    pub fn route(&self, source: UsageSource) -> Option<&ModelRoute> {
+        self.routes.get(&source)
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = (&UsageSource, &ModelRoute)> {
+        self.routes.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the routes in a file of their own, loaded over an empty table
+    fn load(json: &str) -> Result<RoutingTable, IronError> {
+        let path = std::env::temp_dir().join(format!("iron-routes-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, json).unwrap();
+        let mut routes = RoutingTable::default();
+        let loaded = routes.load(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        loaded.map(|_| routes)
+    }
+
+    #[test]
+    fn routes_load_with_their_sampling() {
+        let routes = load(
+            r#"{"mock_user": {"provider": "local", "model": "llama3.2", "temperature": 0.2},
+                "compaction": {"model": "gpt-4o-mini"}}"#,
+        )
+        .unwrap();
+
+        let mock_user = routes.route(UsageSource::MockUser).unwrap();
+        assert_eq!(mock_user.provider, Some(ProviderKind::Local));
+        assert_eq!(mock_user.model.as_deref(), Some("llama3.2"));
+        assert_eq!(mock_user.sampling.temperature, Some(0.2));
+        let compaction = routes.route(UsageSource::Compaction).unwrap();
+        assert_eq!(compaction.provider, None);
+        assert_eq!(compaction.sampling, SamplingParams::default());
+        assert!(routes.route(UsageSource::Assistant).is_none());
+    }
+
+    #[test]
+    fn unknown_call_sources_and_providers_are_rejected() {
+        for json in [
+            r#"{"summarizer": {"model": "gpt-4o-mini"}}"#,
+            r#"{"compaction": {"provider": "gemini"}}"#,
+        ] {
+            assert!(matches!(load(json), Err(IronError::Config(_))), "{}", json);
+        }
+    }
+
+    #[test]
+    fn the_assistant_cant_be_routed() {
+        match load(r#"{"assistant": {"model": "gpt-4o-mini"}}"#) {
+            Err(IronError::Config(message)) => assert!(message.contains("routes the assistant")),
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_missing_file_is_a_config_error() {
+        let mut routes = RoutingTable::default();
+        let err = routes.load("/no/such/routes.json").unwrap_err();
+        assert!(matches!(err, IronError::Config(_)), "{}", err);
+    }
+}
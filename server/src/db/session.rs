@@ -0,0 +1,108 @@
+// what a chat keeps across server restarts: which chat it is, its prompt profile and its preferences.
+// one json file, rewritten whole on every change
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::error::IronError;
+use crate::state::app_state::UserChatPreferences;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavedSession {
+    pub chat_id: Uuid,
+    pub profile: String,
+    pub preferences: UserChatPreferences,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    path: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    // None when nothing was saved yet
+    pub fn load(&self) -> Result<Option<SavedSession>, IronError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(self.error("read", e)),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| self.error("parse", e))
+    }
+
+    // written next to the old file and renamed over it, so a crash mid-write leaves the old one intact
+    pub fn save(&self, session: &SavedSession) -> Result<(), IronError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| self.error("create the directory of", e))?;
+        }
+        let text = serde_json::to_string_pretty(session).map_err(|e| self.error("serialize", e))?;
+        let partial = self.path.with_extension("json.partial");
+        fs::write(&partial, text).map_err(|e| self.error("write", e))?;
+        fs::rename(&partial, &self.path).map_err(|e| self.error("write", e))
+    }
+
+    fn error(&self, action: &str, err: impl std::fmt::Display) -> IronError {
+        IronError::Storage(format!(
+            "Failed to {} session file {}: {}",
+            action,
+            self.path.display(),
+            err
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> SessionStore {
+        let dir = std::env::temp_dir().join(format!("iron-session-test-{}", Uuid::new_v4()));
+        SessionStore::new(dir.join(name))
+    }
+
+    #[test]
+    fn nothing_saved_yet_loads_as_none() {
+        assert!(store("session.json").load().unwrap().is_none());
+    }
+
+    #[test]
+    fn saved_session_loads_back() {
+        let store = store("session.json");
+        let session = SavedSession {
+            chat_id: Uuid::new_v4(),
+            profile: "ops".to_string(),
+            preferences: UserChatPreferences {
+                depth: 2,
+                autorun_all: true,
+                ..UserChatPreferences::default()
+            },
+        };
+        store.save(&session).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.chat_id, session.chat_id);
+        assert_eq!(loaded.profile, "ops");
+        assert_eq!(loaded.preferences.depth, 2);
+        assert!(loaded.preferences.autorun_all);
+        let _ = fs::remove_dir_all(store.path.parent().unwrap());
+    }
+
+    #[test]
+    fn unreadable_file_is_a_storage_error() {
+        let store = store("session.json");
+        fs::create_dir_all(store.path.parent().unwrap()).unwrap();
+        fs::write(&store.path, "{not json").unwrap();
+
+        let err = store.load().unwrap_err();
+        assert!(matches!(err, IronError::Storage(_)), "{}", err);
+        let _ = fs::remove_dir_all(store.path.parent().unwrap());
+    }
+}
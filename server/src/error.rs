@@ -0,0 +1,105 @@
+// crate-wide error type; anything that goes wrong while handling a chat ends up as one of these
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+
+use crate::handlers::handler::ResponseStatus;
+use crate::llm::provider::ProviderError;
+
+#[derive(Debug)]
+pub enum IronError {
+    // missing or unreadable configuration: env vars, prompt files, unregistered providers
+    Config(String),
+    // the llm provider failed, after whatever retries it was allowed
+    Provider(ProviderError),
+    // a cli command couldn't be run, or its output couldn't be read
+    Execution(String),
+    // a frame couldn't be parsed, serialized or sent over a websocket
+    Protocol(String),
+    // chat state couldn't be read or written
+    Storage(String),
+}
+
+// which part of the system failed, as the FE sees it
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Config,
+    Provider,
+    Execution,
+    Protocol,
+    Storage,
+}
+
+impl IronError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            IronError::Config(_) => ErrorKind::Config,
+            IronError::Provider(_) => ErrorKind::Provider,
+            IronError::Execution(_) => ErrorKind::Execution,
+            IronError::Protocol(_) => ErrorKind::Protocol,
+            IronError::Storage(_) => ErrorKind::Storage,
+        }
+    }
+
+    // tells the FE whether the user should wait, fix their setup, or just try again
+    pub fn status(&self) -> ResponseStatus {
+        match self {
+            IronError::Provider(ProviderError::RateLimited { .. }) => ResponseStatus::RateLimited,
+            IronError::Provider(ProviderError::Unavailable { .. }) => {
+                ResponseStatus::ProviderUnavailable
+            }
+            IronError::Provider(ProviderError::QuotaExhausted(_)) => ResponseStatus::QuotaExhausted,
+            IronError::Provider(ProviderError::Auth(_) | ProviderError::Config(_)) => {
+                ResponseStatus::AuthFailed
+            }
+            _ => ResponseStatus::Failure,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            IronError::Provider(err) => err.retry_after(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IronError::Config(msg) => write!(f, "config error: {}", msg),
+            IronError::Provider(err) => write!(f, "{}", err),
+            IronError::Execution(msg) => write!(f, "command execution error: {}", msg),
+            IronError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            IronError::Storage(msg) => write!(f, "chat state error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for IronError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IronError::Provider(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProviderError> for IronError {
+    fn from(err: ProviderError) -> Self {
+        IronError::Provider(err)
+    }
+}
+
+impl From<serde_json::Error> for IronError {
+    fn from(err: serde_json::Error) -> Self {
+        IronError::Protocol(err.to_string())
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for IronError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        IronError::Protocol(err.to_string())
+    }
+}
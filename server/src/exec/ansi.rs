@@ -0,0 +1,177 @@
+// turns what a program wrote to a terminal into the text the terminal would end up showing: colors and
+// other escape sequences dropped, progress bars collapsed to their last state
+use std::iter::Peekable;
+use std::str::Chars;
+
+pub fn plain_text(raw: &str) -> String {
+    let mut lines = Vec::new();
+    let mut line: Vec<char> = Vec::new();
+    // where the next character lands; \r and backspace move it back over what's already there
+    let mut cursor = 0;
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                if let Some(erase) = escape_sequence(&mut chars) {
+                    erase.apply(&mut line, cursor);
+                }
+            }
+            '\n' => {
+                lines.push(line.drain(..).collect::<String>());
+                cursor = 0;
+            }
+            '\r' => cursor = 0,
+            '\x08' => cursor = cursor.saturating_sub(1),
+            '\t' => put(&mut line, &mut cursor, c),
+            c if c.is_control() => {}
+            c => put(&mut line, &mut cursor, c),
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line.into_iter().collect());
+    }
+    lines
+        .iter()
+        .map(|line| line.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn put(line: &mut Vec<char>, cursor: &mut usize, c: char) {
+    match line.get_mut(*cursor) {
+        Some(existing) => *existing = c,
+        None => line.push(c),
+    }
+    *cursor += 1;
+}
+
+// the only sequences that change what's left on screen once the cursor moves on; the rest is styling
+enum Erase {
+    ToEndOfLine,
+    Line,
+}
+
+impl Erase {
+    fn apply(self, line: &mut Vec<char>, cursor: usize) {
+        match self {
+            Erase::ToEndOfLine => line.truncate(cursor),
+            // the cursor stays put, so what follows is written after blanks
+            Erase::Line => {
+                line.clear();
+                line.resize(cursor, ' ');
+            }
+        }
+    }
+}
+
+// consumes the sequence after an ESC
+fn escape_sequence(chars: &mut Peekable<Chars>) -> Option<Erase> {
+    match chars.next()? {
+        // CSI: parameters, intermediates, then a final byte in @..~
+        '[' => {
+            let mut params = String::new();
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    return match (c, params.as_str()) {
+                        ('K', "" | "0") => Some(Erase::ToEndOfLine),
+                        ('K', "2") => Some(Erase::Line),
+                        _ => None,
+                    };
+                }
+                params.push(c);
+            }
+            None
+        }
+        // OSC (window titles, hyperlinks) and the other string sequences, ended by BEL or ESC \
+        ']' | 'P' | '_' | '^' | 'X' => {
+            while let Some(c) = chars.next() {
+                if c == '\x07' {
+                    break;
+                }
+                if c == '\x1b' && chars.peek() == Some(&'\\') {
+                    chars.next();
+                    break;
+                }
+            }
+            None
+        }
+        // charset selection and the like take one more character
+        ' '..='/' => {
+            chars.next();
+            None
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        assert_eq!(plain_text("hello\nworld"), "hello\nworld");
+        assert_eq!(plain_text("a\tb"), "a\tb");
+        assert_eq!(plain_text(""), "");
+    }
+
+    #[test]
+    fn colors_and_styling_are_dropped() {
+        assert_eq!(
+            plain_text("\x1b[1;32mPASS\x1b[0m src/lib.rs\n\x1b[31mFAIL\x1b[m"),
+            "PASS src/lib.rs\nFAIL"
+        );
+    }
+
+    #[test]
+    fn carriage_return_redraws_the_line() {
+        assert_eq!(plain_text("10%\r50%\r100%\ndone"), "100%\ndone");
+        // a shorter redraw leaves the tail of the longer one, as a terminal would
+        assert_eq!(plain_text("downloading\rok"), "okwnloading");
+    }
+
+    #[test]
+    fn erase_sequences_clear_what_is_on_screen() {
+        assert_eq!(plain_text("downloading\r\x1b[Kok"), "ok");
+        assert_eq!(plain_text("downloading\r\x1b[0Kok"), "ok");
+        // erasing the whole line keeps the cursor where it was
+        assert_eq!(plain_text("abcdef\x1b[2Kxy"), "      xy");
+        assert_eq!(
+            plain_text("progress 3/4\x1b[2K\rprogress 4/4"),
+            "progress 4/4"
+        );
+    }
+
+    #[test]
+    fn backspace_moves_back_over_the_line() {
+        assert_eq!(plain_text("abc\x08\x08XY"), "aXY");
+        assert_eq!(plain_text("\x08\x08a"), "a");
+    }
+
+    #[test]
+    fn string_sequences_are_dropped_whichever_way_they_end() {
+        assert_eq!(plain_text("\x1b]0;window title\x07prompt$"), "prompt$");
+        assert_eq!(
+            plain_text("\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\"),
+            "link"
+        );
+        assert_eq!(plain_text("\x1bPq#0;2;0;0;0\x1b\\after"), "after");
+    }
+
+    #[test]
+    fn charset_escapes_and_other_controls_are_dropped() {
+        assert_eq!(plain_text("\x1b(Bplain\x1b)0"), "plain");
+        assert_eq!(plain_text("bell\x07 and\x0c feed"), "bell and feed");
+    }
+
+    #[test]
+    fn trailing_spaces_are_trimmed_from_each_line() {
+        assert_eq!(plain_text("a   \r\nb  "), "a\nb");
+    }
+
+    #[test]
+    fn unterminated_escape_is_dropped() {
+        assert_eq!(plain_text("ok\x1b["), "ok");
+        assert_eq!(plain_text("ok\x1b"), "ok");
+    }
+}
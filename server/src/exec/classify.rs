@@ -0,0 +1,314 @@
+// tells read-only commands from ones that may change something, from the command line itself rather than
+// from what the model says about it. anything it can't vouch for counts as modify, so a miss here means
+// asking the user, not running something the profile or preferences wouldn't have let through
+use crate::llm::tools::CommandKind;
+
+// programs that only read or print, whatever arguments they get
+const READ_ONLY_PROGRAMS: &[&str] = &[
+    "[",
+    "basename",
+    "cat",
+    "cd",
+    "cmp",
+    "cut",
+    "df",
+    "diff",
+    "dirname",
+    "du",
+    "echo",
+    "egrep",
+    "false",
+    "fgrep",
+    "free",
+    "grep",
+    "head",
+    "id",
+    "jq",
+    "less",
+    "ls",
+    "md5sum",
+    "nl",
+    "printenv",
+    "printf",
+    "ps",
+    "pwd",
+    "readlink",
+    "realpath",
+    "rg",
+    "sha1sum",
+    "sha256sum",
+    "stat",
+    "tail",
+    "test",
+    "tr",
+    "true",
+    "type",
+    "uname",
+    "uptime",
+    "wc",
+    "which",
+    "whoami",
+];
+
+// find is read-only unless it's told to run, delete or write something
+const FIND_ACTIONS: &[&str] = &[
+    "-exec", "-execdir", "-ok", "-okdir", "-delete", "-fls", "-fprint", "-fprint0", "-fprintf",
+];
+
+const GIT_READ_ONLY: &[&str] = &[
+    "blame",
+    "describe",
+    "diff",
+    "grep",
+    "log",
+    "ls-files",
+    "rev-parse",
+    "shortlog",
+    "show",
+    "status",
+];
+
+pub fn classify_command(command: &str) -> CommandKind {
+    match simple_commands(command) {
+        Some(commands)
+            if !commands.is_empty() && commands.iter().all(|words| reads_only(words)) =>
+        {
+            CommandKind::ReadOnly
+        }
+        _ => CommandKind::Modify,
+    }
+}
+
+// splits a command line at pipes and separators into the words of each command. None for anything that
+// would take a real shell to tell what runs or gets written: substitutions, subshells, heredocs and
+// redirections into anything but /dev/null
+fn simple_commands(command: &str) -> Option<Vec<Vec<String>>> {
+    let mut commands = Vec::new();
+    let mut words = Vec::new();
+    // None until a word starts, so '' still counts as one
+    let mut word: Option<String> = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '`' => return None,
+                        '$' if chars.peek() == Some(&'(') => return None,
+                        '\\' => word.push(chars.next()?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            '`' | '(' | ')' => return None,
+            '$' if chars.peek() == Some(&'(') => return None,
+            '>' | '<' => {
+                // a file descriptor right before it is part of the redirection, e.g. 2>
+                match word.take() {
+                    Some(fd) if fd.chars().all(|c| c.is_ascii_digit()) => {}
+                    Some(other) => words.push(other),
+                    None => {}
+                }
+                if c == '<' && matches!(chars.peek(), Some('<') | Some('(')) {
+                    return None;
+                }
+                if chars.peek() == Some(&'>') {
+                    chars.next();
+                }
+                let duplicates = chars.peek() == Some(&'&');
+                if duplicates {
+                    chars.next();
+                }
+                while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    chars.next();
+                }
+                let mut target = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "|;&<>()".contains(c) {
+                        break;
+                    }
+                    target.push(c);
+                    chars.next();
+                }
+                let harmless = c == '<'
+                    || target == "/dev/null"
+                    || (duplicates && target.chars().all(|c| c.is_ascii_digit() || c == '-'));
+                if target.is_empty() || !harmless {
+                    return None;
+                }
+            }
+            '|' | ';' | '&' | '\n' => {
+                words.extend(word.take());
+                if !words.is_empty() {
+                    commands.push(std::mem::take(&mut words));
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    if !words.is_empty() {
+        commands.push(words);
+    }
+    Some(commands)
+}
+
+fn reads_only(words: &[String]) -> bool {
+    // FOO=bar in front only sets the environment of the command after it
+    let mut words = words
+        .iter()
+        .map(String::as_str)
+        .skip_while(|word| is_assignment(word));
+    let Some(program) = words.next() else {
+        return false;
+    };
+    let args: Vec<&str> = words.collect();
+    // /bin/ls is still ls
+    let program = program.rsplit('/').next().unwrap_or(program);
+    match program {
+        "find" => !args.iter().any(|arg| FIND_ACTIONS.contains(arg)),
+        "sort" => !args.iter().any(|arg| {
+            arg.starts_with("--output")
+                || arg.starts_with("--compress-program")
+                || (arg.starts_with('-') && !arg.starts_with("--") && arg.contains('o'))
+        }),
+        // --pre runs a program over every file searched
+        "rg" => !args.iter().any(|arg| arg.starts_with("--pre")),
+        "git" => git_reads_only(&args),
+        program => READ_ONLY_PROGRAMS.contains(&program),
+    }
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+fn git_reads_only(args: &[&str]) -> bool {
+    if args.iter().any(|arg| arg.starts_with("--output")) {
+        return false;
+    }
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            // config can name a program for git to run (core.fsmonitor, diff.external, core.pager...)
+            "-c" => return false,
+            option if option.starts_with("--config-env") => return false,
+            // takes the next word as its value
+            "-C" => {
+                args.next();
+            }
+            option if option.starts_with('-') => {}
+            // -O opens the matching files in a program of the caller's choosing
+            "grep" => {
+                return !args.any(|arg| {
+                    (arg.starts_with("-O") && !arg.starts_with("--"))
+                        || arg.starts_with("--open-files-in-pager")
+                })
+            }
+            subcommand => return GIT_READ_ONLY.contains(&subcommand),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(command: &str) -> CommandKind {
+        classify_command(command)
+    }
+
+    #[test]
+    fn reading_commands_are_read_only() {
+        for command in [
+            "ls -la src",
+            "cat Cargo.toml | grep tokio | wc -l",
+            "cd server && git status",
+            "git -C server log --oneline -5",
+            "find . -name '*.rs' -newer Cargo.toml",
+            "RUST_LOG=debug printenv RUST_LOG",
+            "grep -rn 'a > b' src 2>/dev/null",
+            "ls missing 2>&1 | head",
+            "/usr/bin/du -sh target; df -h",
+            "git grep -n TODO",
+            "rg -n TODO src",
+        ] {
+            assert_eq!(kind(command), CommandKind::ReadOnly, "{}", command);
+        }
+    }
+
+    #[test]
+    fn anything_that_writes_or_runs_something_else_is_modify() {
+        for command in [
+            "rm -rf target",
+            "echo hi > notes.txt",
+            "cat a >> b",
+            "ls && rm a",
+            "find . -name '*.tmp' -delete",
+            "find . -exec rm {} ;",
+            "sort -o sorted.txt input",
+            "git commit -am wip",
+            "git branch -D old",
+            "git diff --output=patch.diff",
+            "git -c core.fsmonitor=./hook status",
+            "git -c diff.external=./prog diff",
+            "git --config-env=core.pager=PAGER log",
+            "git grep -O foo",
+            "git grep -Oprog foo",
+            "git grep --open-files-in-pager=prog foo",
+            "rg --pre ./prog foo",
+            "rg --pre=./prog foo",
+            "rg --pre-glob '*.gz' foo",
+            "sort --compress-program=prog input",
+            "sudo ls",
+            "FOO=bar",
+            "",
+        ] {
+            assert_eq!(kind(command), CommandKind::Modify, "{}", command);
+        }
+    }
+
+    #[test]
+    fn substitutions_and_subshells_are_modify() {
+        for command in [
+            "echo $(rm -rf target)",
+            "echo \"$(touch x)\"",
+            "ls `rm x`",
+            "(cd /tmp && ls)",
+            "diff <(ls a) <(ls b)",
+            "cat <<EOF",
+        ] {
+            assert_eq!(kind(command), CommandKind::Modify, "{}", command);
+        }
+    }
+
+    #[test]
+    fn quoting_is_respected() {
+        assert_eq!(kind("echo 'rm -rf / > x'"), CommandKind::ReadOnly);
+        assert_eq!(kind("echo \"a; b | c\""), CommandKind::ReadOnly);
+        assert_eq!(kind("echo '$(date)'"), CommandKind::ReadOnly);
+        // an unterminated quote isn't something we can read
+        assert_eq!(kind("echo 'oops"), CommandKind::Modify);
+    }
+}
@@ -0,0 +1,8 @@
+// exports command execution
+pub mod ansi;
+pub mod classify;
+#[cfg(unix)]
+pub mod pty;
+pub mod result;
+pub mod runner;
+pub mod shell;
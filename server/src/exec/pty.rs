@@ -0,0 +1,142 @@
+// a pseudo-terminal for commands that only behave properly with a TTY: the command gets the slave as its
+// stdin/stdout/stderr, the server reads and writes the master
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::exec::runner::TerminalSize;
+
+pub struct Pty {
+    pub master: Arc<AsyncFd<OwnedFd>>,
+    pub slave: OwnedFd,
+}
+
+impl Pty {
+    pub fn open(size: TerminalSize) -> io::Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let winsize = winsize(size);
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &winsize,
+            )
+        };
+        if opened == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // owned right away, so they're closed on every error path below
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        // neither should leak into the command beyond the stdio it's given
+        set_flag(&master, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        set_flag(&slave, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        set_flag(&master, libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK)?;
+        // the fd is owned by the AsyncFd from here on, so it stays open for as long as it's registered
+        let master = unsafe { AsyncFd::register(master)? };
+        Ok(Self {
+            master: Arc::new(master),
+            slave,
+        })
+    }
+}
+
+// the kernel tells the command's foreground process group with SIGWINCH
+pub fn resize(master: &AsyncFd<OwnedFd>, size: TerminalSize) -> io::Result<()> {
+    let winsize = winsize(size);
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// what the user typed, as if they'd typed it into the command's terminal
+pub async fn write_all(master: &AsyncFd<OwnedFd>, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let mut guard = master.writable().await?;
+        match guard.try_io(|fd| {
+            let written =
+                unsafe { libc::write(fd.as_raw_fd(), bytes.as_ptr().cast(), bytes.len()) };
+            if written < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(written as usize)
+        }) {
+            Ok(written) => bytes = &bytes[written?..],
+            // not writable after all; wait for the next readiness event
+            Err(_would_block) => continue,
+        }
+    }
+    Ok(())
+}
+
+// reads what the command printed. once every copy of the slave is closed, linux fails the read with EIO,
+// which ends it like eof would
+pub struct PtyReader(pub Arc<AsyncFd<OwnedFd>>);
+
+impl AsyncRead for PtyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let read = guard.try_io(|fd| {
+                let read = unsafe {
+                    libc::read(fd.as_raw_fd(), unfilled.as_mut_ptr().cast(), unfilled.len())
+                };
+                if read < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(read as usize)
+            });
+            match read {
+                Ok(Ok(read)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+// runs in the forked child before exec: a new session with the pty (already its stdin) as the
+// controlling terminal. the session's id is the command's pid, so killing the group still works
+pub fn take_controlling_terminal() -> io::Result<()> {
+    unsafe {
+        if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn winsize(size: TerminalSize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn set_flag(fd: &OwnedFd, get: libc::c_int, set: libc::c_int, flag: libc::c_int) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), get);
+        if flags == -1 || libc::fcntl(fd.as_raw_fd(), set, flags | flag) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+// everything a finished command left behind, and how the model gets to see it
+use serde::{Deserialize, Serialize};
+use std::process::{ExitStatus, Output};
+use std::time::Duration;
+
+use crate::exec::ansi::plain_text;
+use crate::exec::runner::KillReason;
+
+// past this share of control characters, output is treated as binary rather than text
+const BINARY_CONTROL_RATIO: f64 = 0.1;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CommandResult {
+    pub stdout: String,
+    pub stderr: String,
+    // None when the command didn't exit on its own, e.g. it was killed by a signal
+    pub exit_code: Option<i32>,
+    // the signal that terminated the command, on unix
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+    // some bytes weren't valid utf-8 and were replaced
+    pub lossy: bool,
+    // a stream looked like binary data, so its text was left out
+    pub binary: bool,
+    // set when the command didn't end on its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub killed: Option<KillReason>,
+    // it ran in a pseudo-terminal, so stdout holds both streams as the terminal showed them
+    #[serde(default)]
+    pub terminal: bool,
+}
+
+impl CommandResult {
+    pub fn from_output(output: Output, duration: Duration) -> Self {
+        Self::from_parts(
+            &output.stdout,
+            &output.stderr,
+            output.status.code(),
+            signal(&output.status),
+            duration,
+        )
+    }
+
+    pub fn from_parts(
+        stdout: &[u8],
+        stderr: &[u8],
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        duration: Duration,
+    ) -> Self {
+        let stdout = decode(stdout);
+        let stderr = decode(stderr);
+        Self {
+            exit_code,
+            signal,
+            duration_ms: duration.as_millis() as u64,
+            lossy: stdout.lossy || stderr.lossy,
+            binary: stdout.binary || stderr.binary,
+            stdout: stdout.text,
+            stderr: stderr.text,
+            killed: None,
+            terminal: false,
+        }
+    }
+
+    // a terminal has one stream, full of escape sequences and progress bars redrawn in place; the model
+    // gets what was left on screen
+    pub fn from_terminal(output: Output, duration: Duration) -> Self {
+        let mut result = Self::from_output(output, duration);
+        if !result.binary {
+            result.stdout = plain_text(&result.stdout);
+        }
+        result.terminal = true;
+        result
+    }
+
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    // what the model sees as the command's output: how it ended, then each stream that said anything
+    pub fn render(&self) -> String {
+        let mut sections = vec![self.status_line()];
+        if self.lossy {
+            sections.push("(some bytes weren't valid UTF-8 and were replaced)".to_string());
+        }
+        let stdout = if self.terminal { "terminal" } else { "stdout" };
+        for (name, text) in [(stdout, &self.stdout), ("stderr", &self.stderr)] {
+            if !text.is_empty() {
+                sections.push(format!("<{}>\n{}\n</{}>", name, text.trim_end(), name));
+            }
+        }
+        if self.stdout.is_empty() && self.stderr.is_empty() {
+            sections.push("(no output)".to_string());
+        }
+        sections.join("\n")
+    }
+
+    fn status_line(&self) -> String {
+        let seconds = self.duration_ms as f64 / 1000.0;
+        match self.killed {
+            Some(KillReason::TimedOut { after_secs }) => {
+                return format!(
+                "[timed out after {}s and was killed; the output is what it printed until then]",
+                after_secs
+            )
+            }
+            Some(KillReason::Cancelled) => {
+                return format!(
+                "[cancelled by the user after {:.2}s; the output is what it printed until then]",
+                seconds
+            )
+            }
+            None => {}
+        }
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => format!("[exit code {} after {:.2}s]", code, seconds),
+            (None, Some(signal)) => {
+                format!("[killed by signal {} after {:.2}s]", signal, seconds)
+            }
+            (None, None) => format!("[ended without an exit code after {:.2}s]", seconds),
+        }
+    }
+}
+
+struct Decoded {
+    text: String,
+    lossy: bool,
+    binary: bool,
+}
+
+fn decode(bytes: &[u8]) -> Decoded {
+    if looks_binary(bytes) {
+        return Decoded {
+            text: format!("(binary data, {} bytes, not shown)", bytes.len()),
+            lossy: false,
+            binary: true,
+        };
+    }
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(text) => Decoded {
+            text,
+            lossy: false,
+            binary: false,
+        },
+        Err(_) => Decoded {
+            text: String::from_utf8_lossy(bytes).into_owned(),
+            lossy: true,
+            binary: false,
+        },
+    }
+}
+
+// a nul byte, or lots of control characters that aren't whitespace or terminal escapes
+fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(8_192)];
+    if sample.is_empty() {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\n' | b'\r' | b'\t' | 0x1b | 0x08 | 0x0c))
+        .count();
+    control as f64 / sample.len() as f64 > BINARY_CONTROL_RATIO
+}
+
+#[cfg(unix)]
+pub fn signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub fn signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn output(raw_status: i32, stdout: &[u8], stderr: &[u8]) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            status: ExitStatus::from_raw(raw_status),
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_code_comes_from_the_status() {
+        // a wait status keeps the exit code in its second byte
+        let result = CommandResult::from_output(output(3 << 8, b"out", b"err"), Duration::ZERO);
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.signal, None);
+        assert!(!result.success());
+
+        let result = CommandResult::from_output(output(0, b"", b""), Duration::ZERO);
+        assert_eq!(result.exit_code, Some(0));
+        assert!(result.success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn killed_command_has_a_signal_and_no_exit_code() {
+        let result =
+            CommandResult::from_output(output(libc::SIGKILL, b"", b""), Duration::from_secs(2));
+        assert_eq!(result.exit_code, None);
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        assert!(!result.success());
+        assert_eq!(
+            result.render(),
+            "[killed by signal 9 after 2.00s]\n(no output)"
+        );
+    }
+
+    #[test]
+    fn duration_is_kept_in_milliseconds() {
+        let result =
+            CommandResult::from_parts(b"", b"", Some(0), None, Duration::from_micros(1_234_567));
+        assert_eq!(result.duration_ms, 1_234);
+        assert!(result.render().starts_with("[exit code 0 after 1.23s]"));
+    }
+
+    #[test]
+    fn render_shows_each_stream_that_said_something() {
+        let result = CommandResult::from_parts(
+            b"line 1\nline 2\n",
+            b"warning\n",
+            Some(1),
+            None,
+            Duration::from_millis(500),
+        );
+        assert_eq!(
+            result.render(),
+            "[exit code 1 after 0.50s]\n<stdout>\nline 1\nline 2\n</stdout>\n<stderr>\nwarning\n</stderr>"
+        );
+        let quiet = CommandResult::from_parts(b"", b"oops", None, None, Duration::ZERO);
+        assert_eq!(
+            quiet.render(),
+            "[ended without an exit code after 0.00s]\n<stderr>\noops\n</stderr>"
+        );
+    }
+
+    #[test]
+    fn kill_reason_replaces_the_status_line() {
+        let mut result = CommandResult::from_parts(b"partial", b"", None, Some(15), Duration::ZERO);
+        result.killed = Some(KillReason::TimedOut { after_secs: 30 });
+        assert!(result
+            .render()
+            .starts_with("[timed out after 30s and was killed;"));
+        result.killed = Some(KillReason::Cancelled);
+        assert!(result
+            .render()
+            .starts_with("[cancelled by the user after 0.00s;"));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced_and_flagged() {
+        let result = CommandResult::from_parts(b"caf\xe9", b"", Some(0), None, Duration::ZERO);
+        assert!(result.lossy);
+        assert!(!result.binary);
+        assert_eq!(result.stdout, "caf\u{fffd}");
+        assert!(result.render().contains("weren't valid UTF-8"));
+    }
+
+    #[test]
+    fn binary_output_is_left_out() {
+        let result = CommandResult::from_parts(
+            b"\x7fELF\x02\x01\x01\x00",
+            b"",
+            Some(0),
+            None,
+            Duration::ZERO,
+        );
+        assert!(result.binary);
+        assert_eq!(result.stdout, "(binary data, 8 bytes, not shown)");
+
+        // escapes, tabs and carriage returns are still text
+        let colored = CommandResult::from_parts(
+            b"\x1b[32mok\x1b[0m\tdone\r\n",
+            b"",
+            Some(0),
+            None,
+            Duration::ZERO,
+        );
+        assert!(!colored.binary);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn terminal_output_is_what_was_left_on_screen() {
+        let result = CommandResult::from_terminal(
+            output(0, b"\x1b[1mbuilding\x1b[0m\n 10%\r 50%\r100%\n", b""),
+            Duration::ZERO,
+        );
+        assert!(result.terminal);
+        assert_eq!(result.stdout, "building\n100%");
+        assert!(result.render().contains("<terminal>"));
+    }
+}
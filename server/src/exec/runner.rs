@@ -0,0 +1,428 @@
+// runs a shell command in its own process group, so a timeout or a cancel takes down everything it started.
+// its output comes through pipes, or a pseudo-terminal for programs that need one
+use serde::{Deserialize, Serialize};
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::config::app_config::API_KEY_VARS;
+use crate::error::IronError;
+#[cfg(unix)]
+use crate::exec::pty::{resize, take_controlling_terminal, write_all, Pty, PtyReader};
+use crate::exec::result::CommandResult;
+
+// a line longer than this is sent live in pieces
+const LIVE_CHUNK_BYTES: usize = 4_096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunLimits {
+    pub timeout: Duration,
+    // how long the group gets to exit after SIGTERM before it gets SIGKILL
+    pub kill_grace: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+    // both streams of a command run in a pseudo-terminal, escape sequences and all
+    Terminal,
+}
+
+// the FE's terminal view; a command in a pseudo-terminal sees it as its window
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for TerminalSize {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+// what a command in a pseudo-terminal gets from the FE while it runs
+#[derive(Debug)]
+pub struct TerminalIo {
+    pub size: watch::Receiver<TerminalSize>,
+    // keystrokes, passed on to the command as they are
+    pub input: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+struct Spawned {
+    child: Child,
+    stdout: (Arc<Mutex<Vec<u8>>>, JoinHandle<()>),
+    stderr: (Arc<Mutex<Vec<u8>>>, JoinHandle<()>),
+    // whatever else runs alongside the command, stopped once it's over
+    helpers: Vec<JoinHandle<()>>,
+}
+
+// a piece of output as it arrives; whole lines where possible
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum KillReason {
+    TimedOut { after_secs: u64 },
+    // the user cancelled the command from the FE
+    Cancelled,
+}
+
+// `cancel` fires whenever the session cancels its running commands. output is sent to `live` as it
+// arrives, and is in the result either way. with `terminal` the command runs in a pseudo-terminal
+// instead of with pipes
+pub async fn run_command(
+    command: &str,
+    limits: RunLimits,
+    mut cancel: watch::Receiver<u64>,
+    live: Option<UnboundedSender<OutputChunk>>,
+    terminal: Option<TerminalIo>,
+) -> Result<CommandResult, IronError> {
+    let started = Instant::now();
+    let mut shell = Command::new("sh");
+    without_api_keys(&mut shell)
+        .arg("-c")
+        .arg(command)
+        // if this future is dropped mid-command, at least the shell itself doesn't outlive it
+        .kill_on_drop(true);
+    let in_terminal = terminal.is_some();
+    let spawned = match terminal {
+        Some(io) => spawn_in_terminal(shell, io, live),
+        None => spawn_with_pipes(shell, live),
+    };
+    let Spawned {
+        mut child,
+        stdout,
+        stderr,
+        helpers,
+    } = spawned.map_err(|e| IronError::Execution(format!("Failed to run `{}`: {}", command, e)))?;
+
+    let (status, killed) = tokio::select! {
+        status = child.wait() => (status, None),
+        _ = tokio::time::sleep(limits.timeout) => {
+            let reason = KillReason::TimedOut { after_secs: limits.timeout.as_secs() };
+            (terminate(&mut child, limits.kill_grace).await, Some(reason))
+        }
+        _ = cancel.changed() => {
+            (terminate(&mut child, limits.kill_grace).await, Some(KillReason::Cancelled))
+        }
+    };
+    for helper in helpers {
+        helper.abort();
+    }
+    let status = status
+        .map_err(|e| IronError::Execution(format!("Failed to wait for `{}`: {}", command, e)))?;
+
+    // something that left the process group can keep the pipes open, so don't wait on them forever
+    let output = Output {
+        status,
+        stdout: collect(stdout, limits.kill_grace).await,
+        stderr: collect(stderr, limits.kill_grace).await,
+    };
+    let mut result = if in_terminal {
+        CommandResult::from_terminal(output, started.elapsed())
+    } else {
+        CommandResult::from_output(output, started.elapsed())
+    };
+    result.killed = killed;
+    if let Some(reason) = killed {
+        println!("Killed `{}`: {:?}", command, reason);
+    }
+    Ok(result)
+}
+
+// the server's provider keys are in its environment, often straight from .env; a command the model asked
+// for has no business reading them, or an `export -p` writing them anywhere
+pub fn without_api_keys(command: &mut Command) -> &mut Command {
+    for var in API_KEY_VARS {
+        command.env_remove(var);
+    }
+    command
+}
+
+fn spawn_with_pipes(
+    mut shell: Command,
+    live: Option<UnboundedSender<OutputChunk>>,
+) -> std::io::Result<Spawned> {
+    shell
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    shell.process_group(0);
+    let mut child = shell.spawn()?;
+    Ok(Spawned {
+        stdout: read_all(child.stdout.take(), OutputStream::Stdout, live.clone()),
+        stderr: read_all(child.stderr.take(), OutputStream::Stderr, live),
+        child,
+        helpers: Vec::new(),
+    })
+}
+
+#[cfg(unix)]
+fn spawn_in_terminal(
+    mut shell: Command,
+    io: TerminalIo,
+    live: Option<UnboundedSender<OutputChunk>>,
+) -> std::io::Result<Spawned> {
+    let TerminalIo {
+        mut size,
+        mut input,
+    } = io;
+    let pty = Pty::open(*size.borrow_and_update())?;
+    shell
+        .stdin(Stdio::from(pty.slave.try_clone()?))
+        .stdout(Stdio::from(pty.slave.try_clone()?))
+        .stderr(Stdio::from(pty.slave))
+        .env("TERM", "xterm-256color");
+    // the new session is also the process group that gets killed on a timeout or cancel
+    unsafe {
+        shell.pre_exec(take_controlling_terminal);
+    }
+    let child = shell.spawn()?;
+    // the command has its own copies of the slave now; ours would keep the read from ever ending
+    drop(shell);
+
+    let master = pty.master;
+    let keyboard = Arc::clone(&master);
+    let typing = tokio::spawn(async move {
+        while let Some(keys) = input.recv().await {
+            if let Err(err) = write_all(&keyboard, &keys).await {
+                eprintln!("Failed to pass input to the command: {}", err);
+                break;
+            }
+        }
+    });
+    let window = Arc::clone(&master);
+    let resizing = tokio::spawn(async move {
+        while size.changed().await.is_ok() {
+            if let Err(err) = resize(&window, *size.borrow_and_update()) {
+                eprintln!("Failed to resize the command's terminal: {}", err);
+            }
+        }
+    });
+    Ok(Spawned {
+        child,
+        stdout: read_all(Some(PtyReader(master)), OutputStream::Terminal, live),
+        stderr: read_all(None::<PtyReader>, OutputStream::Stderr, None),
+        helpers: vec![typing, resizing],
+    })
+}
+
+#[cfg(not(unix))]
+fn spawn_in_terminal(
+    _shell: Command,
+    _io: TerminalIo,
+    _live: Option<UnboundedSender<OutputChunk>>,
+) -> std::io::Result<Spawned> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "pseudo-terminals are only supported on unix",
+    ))
+}
+
+// SIGTERM to the whole group, then SIGKILL if it's still around after the grace period
+pub(crate) async fn terminate(child: &mut Child, grace: Duration) -> std::io::Result<ExitStatus> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        signal_group(pid, libc::SIGTERM);
+        if let Ok(status) = tokio::time::timeout(grace, child.wait()).await {
+            // the shell may be gone while its children ignore SIGTERM
+            signal_group(pid, libc::SIGKILL);
+            return status;
+        }
+        signal_group(pid, libc::SIGKILL);
+    }
+    child.kill().await?;
+    child.wait().await
+}
+
+#[cfg(unix)]
+pub(crate) fn signal_group(pgid: u32, signal: i32) {
+    // the group id is the shell's pid, since it was started with process_group(0)
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, signal);
+    }
+}
+
+// reads into a shared buffer, so whatever arrived is there even if the pipe never closes
+fn read_all<R>(
+    pipe: Option<R>,
+    stream: OutputStream,
+    live: Option<UnboundedSender<OutputChunk>>,
+) -> (Arc<Mutex<Vec<u8>>>, JoinHandle<()>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let shared = Arc::clone(&buffer);
+    let reader = tokio::spawn(async move {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut chunk = [0u8; 8_192];
+        // bytes read but not sent live yet, waiting for the end of their line
+        let mut pending = Vec::new();
+        // an error ends the read like eof does; what came before it is still worth showing
+        while let Ok(read @ 1..) = pipe.read(&mut chunk).await {
+            if let Ok(mut bytes) = shared.lock() {
+                bytes.extend_from_slice(&chunk[..read]);
+            }
+            if let Some(live) = &live {
+                pending.extend_from_slice(&chunk[..read]);
+                let ready = ready_to_send(stream, &pending);
+                send_live(live, stream, pending.drain(..ready).collect());
+            }
+        }
+        if let Some(live) = &live {
+            send_live(live, stream, pending);
+        }
+    });
+    (buffer, reader)
+}
+
+// up to the last newline; a long run without one goes out anyway, minus any utf-8 character cut in half.
+// a terminal's output goes out as it comes, since a prompt waiting for an answer doesn't end its line
+pub(crate) fn ready_to_send(stream: OutputStream, pending: &[u8]) -> usize {
+    if stream != OutputStream::Terminal {
+        if let Some(newline) = pending.iter().rposition(|&b| b == b'\n') {
+            return newline + 1;
+        }
+        if pending.len() < LIVE_CHUNK_BYTES {
+            return 0;
+        }
+    }
+    match std::str::from_utf8(pending) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => pending.len(),
+    }
+}
+
+pub(crate) fn send_live(live: &UnboundedSender<OutputChunk>, stream: OutputStream, bytes: Vec<u8>) {
+    if bytes.is_empty() {
+        return;
+    }
+    // nobody listening anymore shouldn't stop the command
+    let _ = live.send(OutputChunk {
+        stream,
+        content: String::from_utf8_lossy(&bytes).into_owned(),
+    });
+}
+
+async fn collect(
+    (buffer, reader): (Arc<Mutex<Vec<u8>>>, JoinHandle<()>),
+    wait: Duration,
+) -> Vec<u8> {
+    if tokio::time::timeout(wait, reader).await.is_err() {
+        eprintln!("Command output pipe still open after the command ended, keeping what was read");
+    }
+    buffer
+        .lock()
+        .map(|mut bytes| std::mem::take(&mut *bytes))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn lines_go_out_whole() {
+        assert_eq!(ready_to_send(OutputStream::Stdout, b"one\ntwo\nthr"), 8);
+        assert_eq!(ready_to_send(OutputStream::Stderr, b"no newline yet"), 0);
+        assert_eq!(ready_to_send(OutputStream::Stdout, b""), 0);
+    }
+
+    #[test]
+    fn a_long_line_goes_out_without_its_newline() {
+        let pending = vec![b'x'; LIVE_CHUNK_BYTES];
+        assert_eq!(
+            ready_to_send(OutputStream::Stdout, &pending),
+            LIVE_CHUNK_BYTES
+        );
+        assert_eq!(ready_to_send(OutputStream::Stdout, &pending[1..]), 0);
+    }
+
+    #[test]
+    fn a_character_cut_in_half_waits_for_the_rest() {
+        // "é" is two bytes; the first one ends the chunk
+        let mut pending = vec![b'x'; LIVE_CHUNK_BYTES - 1];
+        pending.push("é".as_bytes()[0]);
+        assert_eq!(
+            ready_to_send(OutputStream::Stdout, &pending),
+            LIVE_CHUNK_BYTES - 1
+        );
+        assert_eq!(ready_to_send(OutputStream::Terminal, "abé".as_bytes()), 4);
+        assert_eq!(
+            ready_to_send(OutputStream::Terminal, &"abé".as_bytes()[..3]),
+            2
+        );
+    }
+
+    #[test]
+    fn terminal_output_goes_out_as_it_comes() {
+        assert_eq!(ready_to_send(OutputStream::Terminal, b"Password: "), 10);
+    }
+
+    // what read_all sent live for the writes, each of which arrives as a read of its own, and what it kept
+    async fn sent_live(stream: OutputStream, writes: &[&[u8]]) -> (Vec<String>, Vec<u8>) {
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (buffer, handle) = read_all(Some(reader), stream, Some(tx));
+        for write in writes {
+            writer.write_all(write).await.unwrap();
+            // lets the reader pick this write up before the next one
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        drop(writer);
+        handle.await.unwrap();
+        let mut sent = Vec::new();
+        while let Ok(chunk) = rx.try_recv() {
+            assert_eq!(chunk.stream, stream);
+            sent.push(chunk.content);
+        }
+        let buffered = buffer.lock().unwrap().clone();
+        (sent, buffered)
+    }
+
+    #[tokio::test]
+    async fn live_output_follows_the_lines_and_the_tail_goes_out_at_eof() {
+        let (sent, buffered) = sent_live(
+            OutputStream::Stdout,
+            &[b"hel", b"lo\nwor", b"ld\nno newline"],
+        )
+        .await;
+        assert_eq!(sent, vec!["hello\n", "world\n", "no newline"]);
+        assert_eq!(buffered, b"hello\nworld\nno newline");
+    }
+
+    #[tokio::test]
+    async fn live_output_keeps_a_split_character_together() {
+        let mut first = vec![b'x'; LIVE_CHUNK_BYTES - 1];
+        first.push("é".as_bytes()[0]);
+        let second = &"é\n".as_bytes()[1..];
+        let (sent, _) = sent_live(OutputStream::Stdout, &[&first, second]).await;
+        assert_eq!(
+            sent,
+            vec!["x".repeat(LIVE_CHUNK_BYTES - 1), "é\n".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn terminal_output_is_sent_without_waiting_for_a_newline() {
+        let (sent, _) = sent_live(OutputStream::Terminal, &[b"Password: ", b"ok\n"]).await;
+        assert_eq!(sent, vec!["Password: ", "ok\n"]);
+    }
+}
@@ -0,0 +1,540 @@
+// one long-lived shell per chat, so a `cd` or `export` in one command still holds in the next. commands are
+// fed to it one at a time, and a sentinel printed after each one marks where its output ends and how it exited
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Instant;
+use tempfile::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Mutex};
+use uuid::Uuid;
+
+use crate::error::IronError;
+use crate::exec::result::{signal, CommandResult};
+#[cfg(unix)]
+use crate::exec::runner::signal_group;
+use crate::exec::runner::{
+    ready_to_send, send_live, terminate, without_api_keys, KillReason, OutputChunk, OutputStream,
+    RunLimits,
+};
+
+// inside the session's state directory
+const STATE_FILE: &str = "state.env";
+
+#[derive(Debug)]
+pub struct ShellSession {
+    chat_id: Uuid,
+    // None until the first command, and after the last shell was lost with its future
+    shell: Mutex<Option<Shell>>,
+    // holds the exported environment, working directory included, as of the last command that finished.
+    // a replacement shell starts from it, and so do commands that need a process of their own. whatever the
+    // user exported is in there, so it's a directory only we can read, made with the first command and
+    // removed with the session
+    state_dir: std::sync::Mutex<Option<TempDir>>,
+    // where the last command left the shell; None until one finished
+    working_dir: std::sync::Mutex<Option<PathBuf>>,
+}
+
+impl ShellSession {
+    pub fn new(chat_id: Uuid) -> Self {
+        Self {
+            chat_id,
+            shell: Mutex::new(None),
+            state_dir: std::sync::Mutex::new(None),
+            working_dir: std::sync::Mutex::new(None),
+        }
+    }
+
+    // the shell's directory, for what the prompts tell the model; before the first command that's the
+    // server's, which is where the shell starts
+    pub fn working_dir(&self) -> PathBuf {
+        self.working_dir
+            .lock()
+            .ok()
+            .and_then(|dir| dir.clone())
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_default())
+    }
+
+    fn state_file(&self) -> Result<PathBuf, IronError> {
+        let mut state_dir = self
+            .state_dir
+            .lock()
+            .map_err(|e| IronError::Storage(format!("shell state lock poisoned: {}", e)))?;
+        if let Some(dir) = state_dir.as_ref() {
+            return Ok(dir.path().join(STATE_FILE));
+        }
+        let failed = |e: io::Error| {
+            IronError::Execution(format!("Failed to set up the shell's state: {}", e))
+        };
+        let prefix = format!("iron-shell-{}-", self.chat_id);
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix);
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700));
+        let dir = builder.tempdir().map_err(failed)?;
+        let state_file = dir.path().join(STATE_FILE);
+        // `export -p >` keeps the mode of a file that's already there
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&state_file).map_err(failed)?;
+        *state_dir = Some(dir);
+        Ok(state_file)
+    }
+
+    // commands queue up behind the one that's running. a shell that was killed or exited is replaced first
+    pub async fn run(
+        &self,
+        command: &str,
+        limits: RunLimits,
+        cancel: watch::Receiver<u64>,
+        live: Option<UnboundedSender<OutputChunk>>,
+    ) -> Result<CommandResult, IronError> {
+        let state_file = self.state_file()?;
+        let mut slot = self.shell.lock().await;
+        let alive = slot.as_mut().map(Shell::is_alive);
+        let mut shell = match (slot.take(), alive) {
+            (Some(shell), Some(true)) => shell,
+            (previous, _) => {
+                if previous.is_some() {
+                    println!("Shell exited, starting a new one from where it left off");
+                }
+                // takes down whatever the old one left running
+                drop(previous);
+                Shell::start(&state_file)
+                    .await
+                    .map_err(|e| IronError::Execution(format!("Failed to start a shell: {}", e)))?
+            }
+        };
+        let result = shell.run(command, &state_file, limits, cancel, live).await;
+        if let Some(dir) = shell.working_dir.take() {
+            if let Ok(mut working_dir) = self.working_dir.lock() {
+                *working_dir = Some(dir);
+            }
+        }
+        *slot = Some(shell);
+        result
+    }
+
+    // `command` as a script of its own, starting in the shell's directory and environment
+    pub fn from_shell_state(&self, command: &str) -> Result<String, IronError> {
+        Ok(format!(
+            "{}\n{}",
+            restore_state(&self.state_file()?),
+            command
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct Shell {
+    child: Child,
+    // its process group, which is also what it leaves running in the background
+    pgid: Option<u32>,
+    stdin: ChildStdin,
+    stdout: UnboundedReceiver<Vec<u8>>,
+    stderr: UnboundedReceiver<Vec<u8>>,
+    // it was killed or stopped taking commands; it's replaced before the next one
+    broken: bool,
+    // where the last command that finished left it
+    working_dir: Option<PathBuf>,
+}
+
+enum Ended {
+    // both sentinels showed up
+    Finished,
+    // the shell itself went away, e.g. the command ran `exit`
+    Exited,
+    Killed(KillReason),
+}
+
+impl Shell {
+    async fn start(state_file: &Path) -> io::Result<Self> {
+        let mut shell = Command::new("sh");
+        without_api_keys(&mut shell)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        shell.process_group(0);
+        let mut child = shell.spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::other("the shell has no stdin"))?;
+        let mut shell = Self {
+            pgid: child.id(),
+            stdout: forward(child.stdout.take()),
+            stderr: forward(child.stderr.take()),
+            stdin,
+            child,
+            broken: false,
+            working_dir: None,
+        };
+        let restore = format!("{}\n", restore_state(state_file));
+        shell.stdin.write_all(restore.as_bytes()).await?;
+        Ok(shell)
+    }
+
+    fn is_alive(&mut self) -> bool {
+        !self.broken && matches!(self.child.try_wait(), Ok(None))
+    }
+
+    async fn run(
+        &mut self,
+        command: &str,
+        state_file: &Path,
+        limits: RunLimits,
+        mut cancel: watch::Receiver<u64>,
+        live: Option<UnboundedSender<OutputChunk>>,
+    ) -> Result<CommandResult, IronError> {
+        let started = Instant::now();
+        // what's waiting was printed in the background since the last command ended, so it isn't this one's.
+        // a background job that prints while this command runs still ends up in its output; there's no
+        // telling the two apart
+        for pipe in [&mut self.stdout, &mut self.stderr] {
+            while pipe.try_recv().is_ok() {}
+        }
+        let sentinel = format!("__iron_done_{}", Uuid::new_v4().simple());
+        // eval keeps a syntax error from swallowing the lines after it, and `command` keeps one from taking
+        // the shell down. the command's stdin is /dev/null, so it can't read those lines either
+        let script = format!(
+            "command eval {} </dev/null\n__iron_status=$?\nexport -p > {}\nprintf '%s:%s:%s\\n' {} \"$__iron_status\" \"$PWD\"\nprintf '%s\\n' {} >&2\n",
+            quote(command),
+            quote(&state_file.to_string_lossy()),
+            sentinel,
+            sentinel
+        );
+        if let Err(err) = self.stdin.write_all(script.as_bytes()).await {
+            self.broken = true;
+            return Err(IronError::Execution(format!(
+                "The shell stopped taking commands: {}",
+                err
+            )));
+        }
+
+        let mut stdout = Capture::new(&sentinel, OutputStream::Stdout, live.clone());
+        let mut stderr = Capture::new(&sentinel, OutputStream::Stderr, live);
+        let deadline = tokio::time::Instant::now() + limits.timeout;
+        let ended = loop {
+            if stdout.done && stderr.done {
+                break Ended::Finished;
+            }
+            tokio::select! {
+                bytes = self.stdout.recv(), if !stdout.done => match bytes {
+                    Some(bytes) => stdout.push(&bytes),
+                    None => break Ended::Exited,
+                },
+                bytes = self.stderr.recv(), if !stderr.done => match bytes {
+                    Some(bytes) => stderr.push(&bytes),
+                    None => break Ended::Exited,
+                },
+                _ = tokio::time::sleep_until(deadline) => {
+                    break Ended::Killed(KillReason::TimedOut { after_secs: limits.timeout.as_secs() });
+                }
+                _ = cancel.changed() => break Ended::Killed(KillReason::Cancelled),
+            }
+        };
+
+        let (exit_code, signal, killed) = match ended {
+            Ended::Finished => {
+                self.working_dir = stdout.working_dir.take();
+                // the shell reports a command killed by a signal as 128 + the signal, the way it'd show up
+                // run on its own. an `exit 137` looks the same and is read as SIGKILL too
+                match stdout.exit_code {
+                    Some(code @ 129..=192) => (None, Some(code - 128), None),
+                    code => (code, None, None),
+                }
+            }
+            ended => {
+                self.broken = true;
+                let killed = match ended {
+                    Ended::Killed(reason) => Some(reason),
+                    _ => None,
+                };
+                let status = match killed {
+                    Some(_) => terminate(&mut self.child, limits.kill_grace).await,
+                    None => self.child.wait().await,
+                }
+                .map_err(|e| {
+                    IronError::Execution(format!("Failed to wait for `{}`: {}", command, e))
+                })?;
+                // whatever it printed on the way out
+                drain(&mut self.stdout, &mut stdout, limits).await;
+                drain(&mut self.stderr, &mut stderr, limits).await;
+                (status.code(), signal(&status), killed)
+            }
+        };
+        let mut result = CommandResult::from_parts(
+            &stdout.output,
+            &stderr.output,
+            exit_code,
+            signal,
+            started.elapsed(),
+        );
+        result.killed = killed;
+        if let Some(reason) = killed {
+            println!("Killed `{}`: {:?}", command, reason);
+        }
+        Ok(result)
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        // kill_on_drop only takes the shell itself, not what it started in the background
+        #[cfg(unix)]
+        if let Some(pgid) = self.pgid {
+            signal_group(pgid, libc::SIGKILL);
+        }
+    }
+}
+
+// one stream's output for the running command, up to the sentinel
+struct Capture<'a> {
+    sentinel: &'a [u8],
+    stream: OutputStream,
+    live: Option<UnboundedSender<OutputChunk>>,
+    output: Vec<u8>,
+    // where the sentinel starts, once it's been seen
+    sentinel_at: Option<usize>,
+    // how much of the output has gone out live
+    sent: usize,
+    done: bool,
+    // the command's $? and the shell's directory after it, which only stdout's sentinel carries
+    exit_code: Option<i32>,
+    working_dir: Option<PathBuf>,
+}
+
+impl<'a> Capture<'a> {
+    fn new(
+        sentinel: &'a str,
+        stream: OutputStream,
+        live: Option<UnboundedSender<OutputChunk>>,
+    ) -> Self {
+        Self {
+            sentinel: sentinel.as_bytes(),
+            stream,
+            live,
+            output: Vec::new(),
+            sentinel_at: None,
+            sent: 0,
+            done: false,
+            exit_code: None,
+            working_dir: None,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        // anything after the sentinel was printed in the background, after the command was over
+        if self.done {
+            return;
+        }
+        // the sentinel may have been cut in two by the previous read
+        let from = self.output.len().saturating_sub(self.sentinel.len());
+        self.output.extend_from_slice(bytes);
+        if self.sentinel_at.is_none() {
+            self.sentinel_at = self.output[from..]
+                .windows(self.sentinel.len())
+                .position(|window| window == self.sentinel)
+                .map(|at| from + at);
+        }
+        if let Some(at) = self.sentinel_at {
+            let rest = &self.output[at + self.sentinel.len()..];
+            // otherwise the rest of its line is still on its way
+            if let Some(newline) = rest.iter().position(|&b| b == b'\n') {
+                // :<status>:<directory>, the directory being whatever's left since it may hold colons
+                let fields = std::str::from_utf8(&rest[..newline])
+                    .ok()
+                    .and_then(|rest| rest.strip_prefix(':'))
+                    .and_then(|rest| rest.split_once(':'));
+                if let Some((code, dir)) = fields {
+                    self.exit_code = code.parse().ok();
+                    self.working_dir = Some(PathBuf::from(dir)).filter(|dir| dir.is_absolute());
+                }
+                self.output.truncate(at);
+                self.done = true;
+            }
+        }
+        self.send_ready();
+    }
+
+    // for when the shell went away before printing the sentinel
+    fn finish(&mut self) {
+        if let Some(at) = self.sentinel_at {
+            self.output.truncate(at);
+        }
+        self.done = true;
+        self.send_ready();
+    }
+
+    fn send_ready(&mut self) {
+        let Some(live) = &self.live else {
+            return;
+        };
+        // the end of what's arrived could be the start of the sentinel
+        let end = match (self.done, self.sentinel_at) {
+            (true, _) => self.output.len(),
+            (false, Some(at)) => at,
+            (false, None) => self.output.len().saturating_sub(self.sentinel.len()),
+        };
+        let pending = &self.output[self.sent.min(end)..end];
+        let ready = if self.done {
+            pending.len()
+        } else {
+            ready_to_send(self.stream, pending)
+        };
+        send_live(live, self.stream, pending[..ready].to_vec());
+        self.sent += ready;
+    }
+}
+
+// the pipes outlive any one command, so they're read for as long as the shell has them open
+fn forward<R>(pipe: Option<R>) -> UnboundedReceiver<Vec<u8>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut chunk = [0u8; 8_192];
+        while let Ok(read @ 1..) = pipe.read(&mut chunk).await {
+            if tx.send(chunk[..read].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// something that left the process group can keep the pipe open, so don't wait on it forever
+async fn drain(
+    pipe: &mut UnboundedReceiver<Vec<u8>>,
+    capture: &mut Capture<'_>,
+    limits: RunLimits,
+) {
+    while let Ok(Some(bytes)) = tokio::time::timeout(limits.kill_grace, pipe.recv()).await {
+        capture.push(&bytes);
+    }
+    capture.finish();
+}
+
+// sources the last snapshot, if there is one; only exported variables and the directory make it across
+fn restore_state(state_file: &Path) -> String {
+    let state_file = quote(&state_file.to_string_lossy());
+    format!(
+        "[ -f {0} ] && . {0} 2>/dev/null; cd \"${{PWD:-.}}\" 2>/dev/null",
+        state_file
+    )
+}
+
+// single quotes keep everything literal; a quote inside becomes '\''
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn limits() -> RunLimits {
+        RunLimits {
+            timeout: Duration::from_secs(10),
+            kill_grace: Duration::from_millis(200),
+        }
+    }
+
+    // a dropped sender counts as cancelling, so it has to outlive the command
+    async fn run(session: &ShellSession, command: &str) -> CommandResult {
+        let (_cancel_tx, cancel) = watch::channel(0);
+        session.run(command, limits(), cancel, None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn directory_and_exports_carry_over() {
+        let session = ShellSession::new(Uuid::new_v4());
+        run(&session, "cd /tmp && export GREETING=hello").await;
+        let result = run(&session, "pwd; echo $GREETING").await;
+        assert_eq!(result.stdout, "/tmp\nhello\n");
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(run(&session, "exit 3").await.exit_code, Some(3));
+    }
+
+    #[tokio::test]
+    async fn working_dir_follows_the_shell() {
+        let session = ShellSession::new(Uuid::new_v4());
+        assert_eq!(session.working_dir(), std::env::current_dir().unwrap());
+        run(&session, "cd /tmp").await;
+        assert_eq!(session.working_dir(), PathBuf::from("/tmp"));
+        // a command that fails leaves the shell where it was
+        run(&session, "cd /no/such/dir").await;
+        assert_eq!(session.working_dir(), PathBuf::from("/tmp"));
+    }
+
+    #[tokio::test]
+    async fn a_command_killed_by_a_signal_reports_it() {
+        let session = ShellSession::new(Uuid::new_v4());
+        // $$ is the session's shell in a ( ) subshell, so it takes a shell of its own
+        let result = run(&session, "sh -c 'kill -9 $$'").await;
+        assert_eq!(result.exit_code, None);
+        assert_eq!(result.signal, Some(9));
+        assert!(result.render().contains("killed by signal 9"));
+        // and the session's shell is still the one running
+        assert_eq!(run(&session, "exit 3").await.exit_code, Some(3));
+    }
+
+    #[tokio::test]
+    async fn background_output_from_before_a_command_isnt_its_output() {
+        let session = ShellSession::new(Uuid::new_v4());
+        run(&session, "sh -c 'sleep 0.2; echo late' &").await;
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(run(&session, "echo next").await.stdout, "next\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn state_file_is_private_and_goes_away_with_the_session() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let session = ShellSession::new(Uuid::new_v4());
+        run(&session, "export TOKEN=abc").await;
+        let state_file = session.state_file().unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&state_file), 0o600);
+        assert_eq!(mode(state_file.parent().unwrap()), 0o700);
+        assert!(std::fs::read_to_string(&state_file)
+            .unwrap()
+            .contains("TOKEN"));
+
+        drop(session);
+        assert!(!state_file.parent().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn commands_dont_see_the_servers_api_keys() {
+        // only this test sets it, and nothing reads it back from the server's environment
+        std::env::set_var("IRON_LOCAL_API_KEY", "sk-not-for-the-shell");
+        let session = ShellSession::new(Uuid::new_v4());
+        let result = run(&session, "echo \"${IRON_LOCAL_API_KEY:-unset}\"").await;
+        assert_eq!(result.stdout, "unset\n");
+        let state = std::fs::read_to_string(session.state_file().unwrap()).unwrap();
+        assert!(!state.contains("sk-not-for-the-shell"));
+
+        let script = session
+            .from_shell_state("echo \"${IRON_LOCAL_API_KEY:-unset}\"")
+            .unwrap();
+        let (_cancel_tx, cancel) = watch::channel(0);
+        let result = crate::exec::runner::run_command(&script, limits(), cancel, None, None)
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "unset\n");
+    }
+}
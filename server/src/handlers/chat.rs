@@ -1,136 +1,549 @@
 // llm chat handlers
-use dotenv::dotenv;
-use reqwest::Client;
-use serde_json::json;
-use std::env;
-use std::fs;
+use chrono::Utc;
+use futures_util::StreamExt;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
 
+use crate::error::IronError;
+use crate::llm::provider::{
+    ChatMessage, ChatRequest, Completion, ProviderError, StreamEvent, ToolSpec,
+};
+use crate::llm::structured::{
+    flatten_tool_messages, parse_structured_turn, structured_turn_schema,
+    STRUCTURED_OUTPUT_INSTRUCTIONS,
+};
+use crate::llm::tokens::tail_within_budget;
+use crate::llm::tools::run_command_tool;
+use crate::prompts::library::PromptName;
 use crate::state::app_state::ChatState;
 use crate::state::app_state::ContextMessage;
 use crate::state::app_state::MessageType;
+use crate::state::compaction::{summarization_request, Compactor, ConversationSummary};
+use crate::state::context_window::{ContextBuilder, ContextReport};
+use crate::state::usage::UsageSource;
 
+// one assistant reply, along with the request that produced it, so tool results can be sent as a follow-up
+#[derive(Debug, Clone)]
+pub struct AssistantTurn {
+    // the chat action this turn is part of; follow-ups are billed to the same action
+    pub action_id: Uuid,
+    pub request: ChatRequest,
+    pub completion: Completion,
+    // what had to be left out of `request` to fit the model's context window
+    pub context_report: ContextReport,
+    // the model said the user's request is fully handled; only structured replies can say so
+    pub done: bool,
+}
+
+// how many times a model gets told what was wrong with its json before the turn fails
+const MAX_STRUCTURED_REPAIRS: usize = 2;
+
+// streams deltas to `delta_tx` as they arrive, but only commits the complete response to state
 pub(crate) async fn handle_openai_call(
+    action_id: Uuid,
+    new_message: &ContextMessage,
+    state: &ChatState,
+    delta_tx: UnboundedSender<String>,
+) -> Result<AssistantTurn, IronError> {
+    let mut system_prompt = state.render_prompt(PromptName::System).await?;
+    if let Some(plan) = state.active_plan()? {
+        system_prompt = format!("{}\n\n{}", system_prompt, plan.prompt_section());
+    }
+    // a profile that can't run anything isn't offered the tool at all
+    let tools = if state.get_profile()?.allowed_command_kinds.is_empty() {
+        Vec::new()
+    } else {
+        vec![run_command_tool()]
+    };
+    start_turn(
+        action_id,
+        new_message,
+        state,
+        system_prompt,
+        tools,
+        delta_tx,
+    )
+    .await
+}
+
+// the same conversation, but the model is asked for numbered steps instead, and can't run anything yet
+pub(crate) async fn handle_plan_request(
+    action_id: Uuid,
     new_message: &ContextMessage,
     state: &ChatState,
-) -> Result<String, Box<dyn std::error::Error + 'static>> {
-    // TODO: make this function return a stream, so the frontend can consume the stream via the websocket
-    // TODO: however, also need to make sure that the function calling this receives all the output at once,
-    // since it has to update context in discrete message actions
-    dotenv().ok();
-    let api_key = env::var("OPENAI_API_KEY").expect("Missing OPENAI_API_KEY env variable");
-    let request_url = "https://api.openai.com/v1/chat/completions";
-    // TODO: is there a better way to do this?
-    let system_prompt = fs::read_to_string("default_system_prompt.txt")
-        .expect("Failed to read default_system_prompt.txt");
+    delta_tx: UnboundedSender<String>,
+) -> Result<AssistantTurn, IronError> {
+    let system_prompt = state.render_prompt(PromptName::Planning).await?;
+    start_turn(
+        action_id,
+        new_message,
+        state,
+        system_prompt,
+        Vec::new(),
+        delta_tx,
+    )
+    .await
+}
+
+// adds the new message to the chat, makes room for it, and asks for the first reply of the action
+async fn start_turn(
+    action_id: Uuid,
+    new_message: &ContextMessage,
+    state: &ChatState,
+    system_prompt: String,
+    tools: Vec<ToolSpec>,
+    delta_tx: UnboundedSender<String>,
+) -> Result<AssistantTurn, IronError> {
     state.add_message_to_state(
         new_message.message_type.clone(),
         new_message.content.clone(),
     )?;
 
-    let full_context = match state.get_full_context() {
-        Ok(context) => context,
+    let builder = ContextBuilder::for_model(
+        &state.model()?,
+        &state.context_config()?,
+        &system_prompt,
+        &tools,
+    );
+    // a failed summary isn't fatal, the context builder still trims whatever doesn't fit
+    let summarized_messages = match compact_if_needed(state, &builder, Some(action_id)).await {
+        Ok(summarized) => summarized,
+        Err(err) => {
+            eprintln!("Error compacting chat context: {}", err);
+            0
+        }
+    };
+
+    let messages = match state.get_messages() {
+        Ok(messages) => messages,
         Err(err) => {
             eprintln!("Error retrieving chat context: {}", err);
-            return Err(err.into());
+            return Err(err);
         }
     };
 
-    let client = Client::new();
-    let response = client
-        .post(request_url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&json!({
-                "model": "gpt-4o-mini",
-                "store": true,
-                "messages": [
-                    {
-                        "role": "developer",
-                        "content": system_prompt
-                    },
-                    {
-                        "role": "user",
-                        "content": full_context
-                    },
-                ]
-        }))
-        .send()
+    let request = ChatRequest::new(system_prompt, messages).with_tools(tools);
+    let mut turn = stream_assistant_turn(action_id, request, state, delta_tx).await?;
+    if summarized_messages > 0 {
+        turn.context_report.summarized_messages = summarized_messages;
+        turn.context_report
+            .strategies
+            .insert(0, "summarize_history".to_string());
+    }
+    Ok(turn)
+}
+
+// once the context passes the compaction threshold, folds everything but the recent turns into the
+// running summary. returns how many messages were newly summarized
+async fn compact_if_needed(
+    state: &ChatState,
+    builder: &ContextBuilder,
+    action_id: Option<Uuid>,
+) -> Result<usize, IronError> {
+    let compactor = Compactor {
+        budget: builder.budget(),
+        compact_at_percent: state.config.context.compact_at_percent,
+    };
+    if !compactor.should_compact(builder.counter().count_messages(&state.get_messages()?)) {
+        return Ok(0);
+    }
+
+    let context = state.get_context()?;
+    let previous = state.get_summary()?;
+    let summarized = previous.as_ref().map_or(0, |summary| summary.covers);
+    let Some(cut) = compactor.cut(&context, summarized, builder.counter()) else {
+        return Ok(0);
+    };
+
+    let system_prompt = state.render_prompt(PromptName::Compaction).await?;
+    // the summarizer may be a smaller model than the assistant, so size the transcript for it
+    let summarizer = ContextBuilder::for_model(
+        &state.model_for(UsageSource::Compaction)?,
+        &state.context_config()?,
+        &system_prompt,
+        &[],
+    );
+    let request = state.prepare_request(
+        UsageSource::Compaction,
+        summarization_request(
+            system_prompt,
+            previous.as_ref(),
+            &context[summarized..cut],
+            summarizer.budget(),
+            summarizer.counter(),
+        ),
+    )?;
+    let completion = state
+        .llm_for(UsageSource::Compaction)?
+        .complete(&request)
         .await?;
+    state.record_usage(action_id, UsageSource::Compaction, &completion)?;
+    let content = completion.content;
+    if content.trim().is_empty() {
+        return Err(ProviderError::InvalidResponse(
+            "summarization returned an empty summary".into(),
+        )
+        .into());
+    }
 
-    let response_json: serde_json::Value = response.json().await?;
-    // TODO: probably good to define an openai response struct so it can get typed instantly and we can refer
-    // to local code typing for documentation instead of reading oai documentation to know how to extract what
-    let completion = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("No response from OpenAI")
-        .to_string();
-
-    state.add_message_to_state(MessageType::AssistantResponse, completion.clone())?;
-
-    // TODO: handle command extraction for autorun
-    //if completion.contains("COMMAND (READ-ONLY):") {
-    //    // Extract the command after "COMMAND (READ-ONLY):"
-    //    if let Some(command) = completion.split("COMMAND (READ-ONLY):").nth(1) {
-    //        let extracted_command = command.trim().lines().next().unwrap_or("").to_string();
-    //        let cli_command = CliCommand {
-    //            command: String::from(extracted_command),
-    //        };
-    //        cli_command(cli_command, prev_context);
-    //    }
-    //}
-    return Ok(completion);
+    println!(
+        "Compacted chat {}: summarized messages {}..{}",
+        state.chat_id, summarized, cut
+    );
+    state.set_summary(ConversationSummary {
+        content,
+        covers: cut,
+        created_at: Utc::now(),
+    })?;
+    Ok(cut - summarized)
+}
+
+// answers every tool call of `turn` with the matching entry of `tool_results` and asks for the next reply
+pub(crate) async fn handle_tool_results(
+    turn: AssistantTurn,
+    tool_results: Vec<ChatMessage>,
+    state: &ChatState,
+    delta_tx: UnboundedSender<String>,
+) -> Result<AssistantTurn, IronError> {
+    let mut request = turn.request;
+    request
+        .messages
+        .push(ChatMessage::assistant_with_tool_calls(
+            turn.completion.content,
+            turn.completion.tool_calls,
+        ));
+    request.messages.extend(tool_results);
+    stream_assistant_turn(turn.action_id, request, state, delta_tx).await
+}
+
+async fn stream_assistant_turn(
+    action_id: Uuid,
+    request: ChatRequest,
+    state: &ChatState,
+    delta_tx: UnboundedSender<String>,
+) -> Result<AssistantTurn, IronError> {
+    let llm = state.llm()?;
+    // re-applied on every turn, so a change made mid-action is picked up by its tool result follow-ups
+    let mut request = state.prepare_request(UsageSource::Assistant, request)?;
+    let builder = ContextBuilder::for_model(
+        &state.model()?,
+        &state.context_config()?,
+        &request.system_prompt,
+        &request.tools,
+    );
+    let (messages, context_report) = builder.build(request.messages);
+    request.messages = messages;
+
+    // models that can't call tools get to answer in json instead
+    let structured = !request.tools.is_empty() && !llm.model_info().supports_tools;
+    let (completion, done) = if structured {
+        let (completion, done) = complete_structured(action_id, &request, state).await?;
+        // nothing to stream, so the explanation arrives all at once
+        if !completion.content.is_empty() {
+            let _ = delta_tx.send(completion.content.clone());
+        }
+        (completion, done)
+    } else {
+        let completion = stream_completion(&request, state, delta_tx).await?;
+        state.record_usage(Some(action_id), UsageSource::Assistant, &completion)?;
+        (completion, false)
+    };
+
+    // a reply that only calls tools has no text; the command itself gets recorded when it runs
+    if !completion.content.is_empty() {
+        state.add_message_to_state(MessageType::AssistantResponse, completion.content.clone())?;
+    }
+
+    Ok(AssistantTurn {
+        action_id,
+        request,
+        completion,
+        context_report,
+        done,
+    })
+}
+
+async fn stream_completion(
+    request: &ChatRequest,
+    state: &ChatState,
+    delta_tx: UnboundedSender<String>,
+) -> Result<Completion, IronError> {
+    let mut stream = state.llm()?.stream(request).await?;
+    let mut completion = None;
+    while let Some(event) = stream.next().await {
+        match event? {
+            StreamEvent::Delta(delta) => {
+                // the receiving end going away shouldn't abort the call
+                let _ = delta_tx.send(delta);
+            }
+            StreamEvent::Done(done) => completion = Some(done),
+        }
+    }
+    Ok(completion.ok_or_else(|| {
+        ProviderError::InvalidResponse("stream ended without a completion".into())
+    })?)
+}
+
+// asks for a json reply and feeds validation errors back until it gets a usable one. the completion
+// that comes out looks like a tool calling one: the explanation as content, the command as a tool call
+async fn complete_structured(
+    action_id: Uuid,
+    request: &ChatRequest,
+    state: &ChatState,
+) -> Result<(Completion, bool), IronError> {
+    let mut attempt = ChatRequest {
+        system_prompt: format!(
+            "{}\n\n{}",
+            request.system_prompt, STRUCTURED_OUTPUT_INSTRUCTIONS
+        ),
+        messages: flatten_tool_messages(request.messages.clone()),
+        ..request.clone()
+    }
+    .with_response_schema(structured_turn_schema());
+
+    let llm = state.llm()?;
+    let mut repairs = 0;
+    loop {
+        let completion = llm.complete(&attempt).await?;
+        state.record_usage(Some(action_id), UsageSource::Assistant, &completion)?;
+        match parse_structured_turn(&completion.content) {
+            Ok(turn) => {
+                let tool_calls = turn.tool_calls();
+                return Ok((
+                    Completion {
+                        content: turn.explanation,
+                        tool_calls,
+                        ..completion
+                    },
+                    turn.done,
+                ));
+            }
+            Err(err) if repairs < MAX_STRUCTURED_REPAIRS => {
+                repairs += 1;
+                eprintln!(
+                    "Model replied with invalid structured output (repair {}/{}): {}",
+                    repairs, MAX_STRUCTURED_REPAIRS, err
+                );
+                attempt
+                    .messages
+                    .push(ChatMessage::assistant(completion.content));
+                attempt.messages.push(ChatMessage::user(format!(
+                    "{}\nReply again with only the JSON object.",
+                    err
+                )));
+            }
+            Err(err) => {
+                return Err(ProviderError::InvalidResponse(format!(
+                    "no valid structured reply after {} repairs: {}",
+                    MAX_STRUCTURED_REPAIRS, err
+                ))
+                .into())
+            }
+        }
+    }
 }
 
 pub(crate) async fn handle_openai_call_as_mock_user(
     state: &ChatState,
-) -> Result<String, Box<dyn std::error::Error + 'static>> {
-    // TODO: make this function return a stream, so the frontend can consume the stream via the websocket
-    // TODO: however, also need to make sure that the function calling this receives all the output at once,
-    // since it has to update context in discrete message actions
-    dotenv().ok();
-    let api_key = env::var("OPENAI_API_KEY").expect("Missing OPENAI_API_KEY env variable");
-    let request_url = "https://api.openai.com/v1/chat/completions";
-    // TODO: is there a better way to do this?
-    let system_prompt = fs::read_to_string("continuation_system_prompt.txt")
-        .expect("Failed to read continuation_system_prompt.txt");
+) -> Result<String, IronError> {
+    // the continuation "user" is never shown as it types, so there's no need to stream here
+    let system_prompt = state.render_prompt(PromptName::Continuation).await?;
 
     let full_context = match state.get_full_context() {
         Ok(context) => context,
         Err(err) => {
             eprintln!("Error retrieving chat context: {}", err);
-            return Err(err.into());
+            return Err(err);
         }
     };
 
-    let client = Client::new();
-    let response = client
-        .post(request_url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&json!({
-                "model": "gpt-4o-mini",
-                "store": true,
-                "messages": [
-                    {
-                        "role": "developer",
-                        "content": system_prompt
-                    },
-                    {
-                        "role": "user",
-                        "content": full_context
-                    },
-                ]
-        }))
-        .send()
+    let builder = ContextBuilder::for_model(
+        &state.model_for(UsageSource::MockUser)?,
+        &state.context_config()?,
+        &system_prompt,
+        &[],
+    );
+    // the mock user only needs to know where the conversation is at, so keep the most recent part
+    let transcript = tail_within_budget(&full_context, builder.budget(), builder.counter());
+
+    let request = state.prepare_request(
+        UsageSource::MockUser,
+        ChatRequest::new(system_prompt, vec![ChatMessage::user(transcript)]),
+    )?;
+    let completion = state
+        .llm_for(UsageSource::MockUser)?
+        .complete(&request)
         .await?;
+    state.record_usage(None, UsageSource::MockUser, &completion)?;
+
+    Ok(completion.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    use crate::llm::mock::MockProvider;
+    use crate::llm::provider::TokenUsage;
+    use crate::llm::tools::RUN_COMMAND_TOOL;
+    use crate::testing::chat_state;
+
+    fn prompt(content: &str) -> ContextMessage {
+        ContextMessage {
+            message_type: MessageType::UserPrompt,
+            content: content.to_string(),
+            timestamp: None,
+            tool_call_id: None,
+            command_result: None,
+        }
+    }
+
+    fn scripted(responses: &[&str]) -> Arc<ChatState> {
+        let responses = responses.iter().map(|r| r.to_string()).collect();
+        chat_state(Arc::new(MockProvider::with_responses(
+            "mock-model".to_string(),
+            responses,
+        )))
+    }
+
+    fn received(mut rx: mpsc::UnboundedReceiver<String>) -> String {
+        let mut text = String::new();
+        while let Ok(delta) = rx.try_recv() {
+            text.push_str(&delta);
+        }
+        text
+    }
+
+    #[tokio::test]
+    async fn reply_is_streamed_and_stored() {
+        let state = scripted(&["hello there"]);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let turn = handle_openai_call(Uuid::new_v4(), &prompt("hi"), &state, tx)
+            .await
+            .unwrap();
+
+        assert_eq!(turn.completion.content, "hello there");
+        assert!(turn.completion.tool_calls.is_empty());
+        assert_eq!(received(rx), "hello there");
+        let context = state.get_context().unwrap();
+        let stored: Vec<_> = context
+            .iter()
+            .map(|m| (m.message_type.clone(), m.content.as_str()))
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                (MessageType::UserPrompt, "hi"),
+                (MessageType::AssistantResponse, "hello there"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn request_carries_the_system_prompt_and_tool() {
+        let state = scripted(&["ok"]);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let turn = handle_openai_call(Uuid::new_v4(), &prompt("hi"), &state, tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            turn.request.system_prompt,
+            state.render_prompt(PromptName::System).await.unwrap()
+        );
+        assert_eq!(turn.request.tools.len(), 1);
+        assert_eq!(turn.request.tools[0].name, RUN_COMMAND_TOOL);
+        assert_eq!(turn.request.messages.last().unwrap().content, "hi");
+    }
+
+    #[tokio::test]
+    async fn command_line_is_proposed_as_a_tool_call() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let turn = handle_openai_call(Uuid::new_v4(), &prompt("list files\n$ ls -la"), &state, tx)
+            .await
+            .unwrap();
+
+        assert!(turn.completion.content.is_empty());
+        assert_eq!(received(rx), "");
+        let call = &turn.completion.tool_calls[0];
+        assert_eq!(call.name, RUN_COMMAND_TOOL);
+        let args: serde_json::Value = serde_json::from_str(&call.arguments).unwrap();
+        assert_eq!(args["command"], "ls -la");
+        // a reply without text leaves nothing but the prompt in the chat
+        assert_eq!(state.get_context().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tool_results_go_back_to_the_model() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let action_id = Uuid::new_v4();
+        let turn = handle_openai_call(action_id, &prompt("$ pwd"), &state, tx)
+            .await
+            .unwrap();
+        let call_id = turn.completion.tool_calls[0].id.clone();
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let results = vec![ChatMessage::tool_result(call_id.clone(), "/home/user")];
+        let follow_up = handle_tool_results(turn, results, &state, tx)
+            .await
+            .unwrap();
+
+        assert_eq!(follow_up.action_id, action_id);
+        assert_eq!(follow_up.completion.content, "mock response to: /home/user");
+        assert_eq!(received(rx), "mock response to: /home/user");
+        // the follow-up request holds the call and its answer, in that order
+        let sent = &follow_up.request.messages;
+        let call = &sent[sent.len() - 2];
+        assert_eq!(call.tool_calls[0].id, call_id);
+        assert_eq!(sent[sent.len() - 1].content, "/home/user");
+    }
+
+    #[tokio::test]
+    async fn usage_is_billed_to_the_action() {
+        let completion = |prompt_tokens| Completion {
+            model: "mock-model".to_string(),
+            content: "ok".to_string(),
+            tool_calls: Vec::new(),
+            usage: Some(TokenUsage {
+                prompt_tokens,
+                completion_tokens: 1,
+                cached_prompt_tokens: 0,
+                cache_write_prompt_tokens: 0,
+            }),
+        };
+        let state = chat_state(Arc::new(MockProvider::with_completions(
+            "mock-model".to_string(),
+            vec![completion(10), completion(20)],
+        )));
+        let action_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        handle_openai_call(action_id, &prompt("first"), &state, tx)
+            .await
+            .unwrap();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        handle_openai_call(Uuid::new_v4(), &prompt("second"), &state, tx)
+            .await
+            .unwrap();
+
+        let usage = state.action_usage(action_id).unwrap();
+        assert_eq!(usage.calls, 1);
+        assert_eq!(usage.usage.prompt_tokens, 10);
+        assert_eq!(state.usage_summary().unwrap().total.calls, 2);
+    }
 
-    let response_json: serde_json::Value = response.json().await?;
-    // TODO: probably good to define an openai response struct so it can get typed instantly and we can refer
-    // to local code typing for documentation instead of reading oai documentation to know how to extract what
-    let completion = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("No response from OpenAI")
-        .to_string();
+    #[tokio::test]
+    async fn mock_user_sees_the_conversation() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        state
+            .add_message_to_state(MessageType::UserPrompt, "find the config".to_string())
+            .unwrap();
 
-    return Ok(completion);
+        let reply = handle_openai_call_as_mock_user(&state).await.unwrap();
+        assert!(reply.starts_with("mock response to: "));
+        assert!(reply.contains("find the config"));
+    }
 }
@@ -1,22 +1,57 @@
 // cli command handlers
-use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedSender;
 
-use crate::state::app_state::{ChatState, CliCommandType, MessageType};
+use crate::error::IronError;
+use crate::exec::result::CommandResult;
+use crate::exec::runner::{run_command, OutputChunk};
+use crate::handlers::handler::CliCommand;
+use crate::state::app_state::{ChatState, MessageType};
 
+// a command that runs but fails, times out or is cancelled is still a result; only not being able to
+// run it at all is an error. `command_id` is what the FE sends keystrokes to, when it runs in a terminal
 pub async fn handle_cli_command(
-    command: String,
-    command_type: CliCommandType,
+    command: CliCommand,
+    command_id: &str,
     state: &ChatState,
-) -> Result<String, Box<dyn std::error::Error + 'static>> {
-    // TODO: have this stream instead of running an await on the caller
-    let msg_type: MessageType = command_type.into();
-    state.add_message_to_state(msg_type, command.clone())?;
+    live: Option<UnboundedSender<OutputChunk>>,
+) -> Result<CommandResult, IronError> {
+    let msg_type: MessageType = command.command_type.into();
+    record_message(
+        state,
+        msg_type,
+        command.command.clone(),
+        &command.tool_call_id,
+    )?;
 
-    let output = Command::new("sh").arg("-c").arg(&command).output().await?;
+    let limits = state.command_limits(command.timeout_secs)?;
+    let cancel = state.command_cancel.subscribe();
+    let result = if state.use_tty(command.tty)? {
+        // a terminal needs a process of its own; it starts where the shell is, but can't move it
+        let script = state.shell.from_shell_state(&command.command)?;
+        let terminal = state.open_terminal(command_id)?;
+        let result = run_command(&script, limits, cancel, live, Some(terminal)).await;
+        state.close_terminal(command_id)?;
+        result
+    } else {
+        state
+            .shell
+            .run(&command.command, limits, cancel, live)
+            .await
+    };
 
-    let output_str = String::from_utf8(output.stdout)?;
-
-    state.add_message_to_state(MessageType::CliOutput, output_str.clone())?;
+    let result = result?;
+    state.add_command_result(result.clone(), command.tool_call_id)?;
+    Ok(result)
+}
 
-    Ok(output_str)
+fn record_message(
+    state: &ChatState,
+    message_type: MessageType,
+    content: String,
+    tool_call_id: &Option<String>,
+) -> Result<(), IronError> {
+    match tool_call_id {
+        Some(id) => state.add_tool_message_to_state(message_type, content, id.clone()),
+        None => state.add_message_to_state(message_type, content),
+    }
 }
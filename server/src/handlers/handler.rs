@@ -1,26 +1,58 @@
-use futures_util::stream::SplitSink;
-use futures_util::{SinkExt, StreamExt};
+use std::future::Future;
 use std::result::Result;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::Message;
-
-use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
 use serde::Deserialize;
 use serde::Serialize;
-use tokio_tungstenite::WebSocketStream;
 
 use crate::db::db::dummy_db_function;
-use crate::handlers::chat::handle_openai_call;
+use crate::error::IronError;
+use crate::exec::classify::classify_command;
+use crate::exec::result::CommandResult;
+use crate::exec::runner::OutputChunk;
+use crate::handlers::chat::{
+    handle_openai_call, handle_plan_request, handle_tool_results, AssistantTurn,
+};
 use crate::handlers::cli::handle_cli_command;
+use crate::llm::provider::{ChatMessage, Completion, ToolCall};
+use crate::llm::tools::{parse_run_command, CommandKind};
+use crate::prompts::profile::PromptProfile;
+use crate::protocol::{send_frame, CommandFinishedEvent, FrontendFrame, UsageEvent, WsWriteStream};
 use crate::state::app_state::{ChatState, CliCommandType, ContextMessage, MessageType};
+use crate::state::plan::{Plan, PlanStatus};
 
-type BoxError = Box<dyn std::error::Error + std::marker::Send + Sync + 'static>;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AssistantResponse {
     output: String,
     status: ResponseStatus,
+    // set when the provider said how long to wait before trying again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    // the model that actually answered, which isn't the session's own one after a fallback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+}
+
+impl AssistantResponse {
+    fn success(completion: &Completion) -> Self {
+        Self {
+            output: completion.content.clone(),
+            status: ResponseStatus::Success,
+            retry_after_secs: None,
+            model: Some(completion.model.clone()),
+        }
+    }
+
+    fn from_error(err: &IronError) -> Self {
+        Self {
+            output: err.to_string(),
+            status: err.status(),
+            retry_after_secs: err.retry_after().map(|wait| wait.as_secs().max(1)),
+            model: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,16 +61,30 @@ pub struct CliResponse {
     status: ResponseStatus,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum ResponseStatus {
     Success,
     Failure,
+    // the provider is throttling us even after retries; waiting will fix it
+    RateLimited,
+    // the provider is down or overloaded even after retries; waiting will probably fix it
+    ProviderUnavailable,
+    // the api key is missing or was rejected; waiting won't fix it
+    AuthFailed,
+    // the account ran out of credits on every provider it could fall back to
+    QuotaExhausted,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CliCommand {
-    command_type: CliCommandType,
-    command: String,
+    pub command_type: CliCommandType,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tty: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -50,9 +96,8 @@ pub enum ChatActionOutcome {
 pub async fn handle_chat_action(
     typed_msg: ContextMessage,
     chat_state: Arc<ChatState>,
-    fe_write_stream: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-    cli_write_stream: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-) -> Result<ChatActionOutcome, BoxError> {
+    fe_write_stream: WsWriteStream,
+) -> Result<ChatActionOutcome, IronError> {
     // does it make sense to create a chat_action struct?
     // if so:
     // chat actions consist of the below (the field names can be improved):
@@ -63,81 +108,285 @@ pub async fn handle_chat_action(
     // llm_summary: llm's summary of output in the context of the global context (e.g. "it looks like there are files xyz here")
     //
     // this function can either be triggered by a user's request for an action, or an LLM's continuation
-    // TODO: i think the cloning here is unnecessary, right? the function call can just access the memory instead of owning it
-    let llm_response = openai_message(typed_msg.clone(), chat_state.clone()).await;
-    if llm_response.status != ResponseStatus::Success {
-        // panik
-        return Err("err".into());
+    let action_id = Uuid::new_v4();
+    let outcome = run_chat_action(action_id, typed_msg, chat_state.clone(), &fe_write_stream).await;
+    // tokens are spent even when the action fails, so the FE hears about them either way
+    if let Err(err) = send_usage(action_id, &chat_state, &fe_write_stream).await {
+        eprintln!("Error sending usage for chat action {}: {}", action_id, err);
     }
-    // send response to fe stream
-    let mut fe_ws = fe_write_stream.lock().await; // Lock the write stream before using it
-    fe_ws.send(llm_response.output.into()).await?;
-    drop(fe_ws);
-    // TODO: send the llm response back to the websocket server's write stream to the FE.
+    if let Err(err) = send_redaction_report(&chat_state, &fe_write_stream).await {
+        eprintln!(
+            "Error sending redaction report for chat action {}: {}",
+            action_id, err
+        );
+    }
+    outcome
+}
+
+async fn run_chat_action(
+    action_id: Uuid,
+    typed_msg: ContextMessage,
+    chat_state: Arc<ChatState>,
+    fe_write_stream: &WsWriteStream,
+) -> Result<ChatActionOutcome, IronError> {
+    if chat_state.needs_plan()? {
+        return propose_plan(action_id, typed_msg, &chat_state, fe_write_stream).await;
+    }
+
+    // TODO: i think the cloning here is unnecessary, right? the function call can just access the memory instead of owning it
+    let turn = match openai_message(action_id, typed_msg, chat_state.clone(), fe_write_stream).await
+    {
+        Ok(turn) => turn,
+        Err(err) => {
+            // retries already happened in the provider; the caller tells the FE what went wrong
+            eprintln!("Error getting assistant response: {}", err);
+            return Err(err);
+        }
+    };
+    send_context_report(&turn, fe_write_stream).await?;
+    // deltas were already streamed, this frame marks the end of the assistant's turn
+    send_frame(
+        fe_write_stream,
+        &FrontendFrame::AssistantResponse(AssistantResponse::success(&turn.completion)),
+    )
+    .await?;
+    let mut plan_completed =
+        send_plan_progress(&turn.completion.content, &chat_state, fe_write_stream).await?;
 
     // update the db by calling dummy_db_function for now; or should the functions the handler hands off to take care of the db updates?
-    dummy_db_function();
+    dummy_db_function().await;
 
     // decide if there needs to be any CLI action here
-    let command = extract_command_from_frontend_message(typed_msg.content.clone());
-    if let Some(command) = command {
-        let cli_response = cli_command(command, chat_state.clone()).await;
-        match cli_response.status {
-            ResponseStatus::Success => {
-                let cli_message = ContextMessage {
-                    message_type: MessageType::CliOutput,
-                    content: cli_response.output,
-                    timestamp: Some(chrono::Utc::now()),
-                };
-                // cli response should automatically be streamed, since the CLI has a websocket connection open with the websocket server.
-                let llm_response = openai_message(cli_message, chat_state).await;
-
-                // send response to fe stream
-                let mut fe_ws = fe_write_stream.lock().await; // Lock the write stream before using it
-                fe_ws.send(llm_response.output.into()).await?;
-                drop(fe_ws);
+    if turn.completion.tool_calls.is_empty() {
+        // return, the chat action has finished
+        return Ok(ChatActionOutcome::Stop);
+    }
+
+    // every tool call has to be answered, even the ones we couldn't make sense of or aren't allowed to run
+    let profile = chat_state.get_profile()?;
+    let mut tool_results = Vec::new();
+    for tool_call in &turn.completion.tool_calls {
+        let output = match extract_command_from_tool_call(tool_call, &profile) {
+            // cli response should automatically be streamed, since the CLI has a websocket connection open with the websocket server.
+            Ok(command) if approved(&command, &chat_state, fe_write_stream).await? => {
+                cli_command(command, chat_state.clone(), fe_write_stream)
+                    .await
+                    .output
             }
-            ResponseStatus::Failure => {
-                // do nothing for now
+            Ok(command) => {
+                let output = format!(
+                    "The user didn't approve this command, so it was not run: {}",
+                    command.command
+                );
+                record_unrun_call(&chat_state, tool_call, &output)?;
+                output
             }
-        }
-        // TODO: need a way to determine whether a chat outcome should be continue or stop.
-        return Ok(ChatActionOutcome::Continue);
-    } else {
-        // return, the chat action has finished
-        // this logic is wrong for now
+            Err(err) => {
+                record_unrun_call(&chat_state, tool_call, &err)?;
+                err
+            }
+        };
+        tool_results.push(ChatMessage::tool_result(tool_call.id.clone(), output));
+    }
+
+    let mut done = false;
+    let llm_response =
+        match tool_results_message(turn, tool_results, chat_state.clone(), fe_write_stream).await {
+            Ok(turn) => {
+                send_context_report(&turn, fe_write_stream).await?;
+                done = turn.done;
+                plan_completed |=
+                    send_plan_progress(&turn.completion.content, &chat_state, fe_write_stream)
+                        .await?;
+                AssistantResponse::success(&turn.completion)
+            }
+            // TODO: make verbose?
+            Err(e) => AssistantResponse::from_error(&e),
+        };
+    // send response to fe stream
+    send_frame(
+        fe_write_stream,
+        &FrontendFrame::AssistantResponse(llm_response),
+    )
+    .await?;
+
+    // TODO: need a way to determine whether a chat outcome should be continue or stop.
+    // structured replies say so themselves, as does finishing the last step of a plan; tool calling ones
+    // keep going until the mock user stops
+    if done || plan_completed {
         return Ok(ChatActionOutcome::Stop);
     }
+    Ok(ChatActionOutcome::Continue)
+}
+
+// asks for a plan instead of a first step; nothing runs until the user approves it
+async fn propose_plan(
+    action_id: Uuid,
+    typed_msg: ContextMessage,
+    chat_state: &Arc<ChatState>,
+    fe_write_stream: &WsWriteStream,
+) -> Result<ChatActionOutcome, IronError> {
+    let state = Arc::clone(chat_state);
+    let turn = with_streamed_deltas(fe_write_stream, |delta_tx| async move {
+        handle_plan_request(action_id, &typed_msg, &state, delta_tx).await
+    })
+    .await?;
+    send_context_report(&turn, fe_write_stream).await?;
+    send_frame(
+        fe_write_stream,
+        &FrontendFrame::AssistantResponse(AssistantResponse::success(&turn.completion)),
+    )
+    .await?;
+
+    // a reply without numbered steps is just an answer, e.g. to a question that didn't need a plan
+    match Plan::parse(&turn.completion.content) {
+        Ok(plan) => {
+            println!(
+                "Chat {} proposed plan {} with {} steps",
+                chat_state.chat_id,
+                plan.id,
+                plan.steps.len()
+            );
+            chat_state.propose_plan(plan.clone())?;
+            send_frame(fe_write_stream, &FrontendFrame::Plan(plan)).await?;
+        }
+        Err(err) => eprintln!("No plan proposed in chat {}: {}", chat_state.chat_id, err),
+    }
+    Ok(ChatActionOutcome::Stop)
+}
+
+// sends the active plan again whenever a reply moved one of its steps; true once it's finished
+async fn send_plan_progress(
+    reply: &str,
+    chat_state: &ChatState,
+    fe_write_stream: &WsWriteStream,
+) -> Result<bool, IronError> {
+    let Some(plan) = chat_state.track_plan(reply)? else {
+        return Ok(false);
+    };
+    let completed = plan.status == PlanStatus::Completed;
+    send_frame(fe_write_stream, &FrontendFrame::Plan(plan)).await?;
+    Ok(completed)
 }
 
 pub async fn openai_message(
+    action_id: Uuid,
     new_message: ContextMessage,
     state: Arc<ChatState>,
-) -> AssistantResponse {
-    match handle_openai_call(&new_message, &state).await {
-        Ok(output) => AssistantResponse {
-            output,
-            status: ResponseStatus::Success,
-        },
-        Err(e) => AssistantResponse {
-            output: e.to_string(),
-            // TODO: make verbose?
-            status: ResponseStatus::Failure,
-        },
+    fe_write_stream: &WsWriteStream,
+) -> Result<AssistantTurn, IronError> {
+    with_streamed_deltas(fe_write_stream, |delta_tx| async move {
+        handle_openai_call(action_id, &new_message, &state, delta_tx).await
+    })
+    .await
+}
+
+pub async fn tool_results_message(
+    turn: AssistantTurn,
+    tool_results: Vec<ChatMessage>,
+    state: Arc<ChatState>,
+    fe_write_stream: &WsWriteStream,
+) -> Result<AssistantTurn, IronError> {
+    with_streamed_deltas(fe_write_stream, |delta_tx| async move {
+        handle_tool_results(turn, tool_results, &state, delta_tx).await
+    })
+    .await
+}
+
+// lets the FE show that the model isn't seeing the whole conversation anymore
+async fn send_context_report(
+    turn: &AssistantTurn,
+    fe_write_stream: &WsWriteStream,
+) -> Result<(), IronError> {
+    if !turn.context_report.elided_anything() {
+        return Ok(());
     }
+    send_frame(
+        fe_write_stream,
+        &FrontendFrame::ContextTrimmed(turn.context_report.clone()),
+    )
+    .await
 }
 
-async fn cli_command(command: CliCommand, state: Arc<ChatState>) -> CliResponse {
-    match handle_cli_command(
-        command.command.clone(),
-        command.command_type.clone(),
-        &state,
+async fn send_usage(
+    action_id: Uuid,
+    chat_state: &ChatState,
+    fe_write_stream: &WsWriteStream,
+) -> Result<(), IronError> {
+    let frame = FrontendFrame::Usage(UsageEvent {
+        action_id,
+        action: chat_state.action_usage(action_id)?,
+        session: chat_state.usage_summary()?,
+    });
+    send_frame(fe_write_stream, &frame).await
+}
+
+// lets the FE show that the model saw placeholders instead of some of the chat's content
+async fn send_redaction_report(
+    chat_state: &ChatState,
+    fe_write_stream: &WsWriteStream,
+) -> Result<(), IronError> {
+    let Some(report) = chat_state.take_redaction_report()? else {
+        return Ok(());
+    };
+    println!(
+        "Masked {} new secret(s) in chat {}",
+        report.masked.len(),
+        chat_state.chat_id
+    );
+    send_frame(fe_write_stream, &FrontendFrame::SecretsRedacted(report)).await
+}
+
+// runs `call` while forwarding every delta it produces to the FE as an assistant_delta frame
+async fn with_streamed_deltas<F, Fut>(fe_write_stream: &WsWriteStream, call: F) -> Fut::Output
+where
+    F: FnOnce(mpsc::UnboundedSender<String>) -> Fut,
+    Fut: Future,
+{
+    with_streamed_frames(
+        fe_write_stream,
+        |content| FrontendFrame::AssistantDelta { content },
+        call,
     )
     .await
-    {
-        Ok(output) => CliResponse {
-            output,
-            status: ResponseStatus::Success,
+}
+
+// runs `call` while forwarding everything it sends to the FE, as the frame `to_frame` makes of it
+async fn with_streamed_frames<T, F, Fut>(
+    fe_write_stream: &WsWriteStream,
+    to_frame: impl Fn(T) -> FrontendFrame,
+    call: F,
+) -> Fut::Output
+where
+    F: FnOnce(mpsc::UnboundedSender<T>) -> Fut,
+    Fut: Future,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel::<T>();
+    let forward = async {
+        while let Some(item) = rx.recv().await {
+            if let Err(err) = send_frame(fe_write_stream, &to_frame(item)).await {
+                eprintln!("Error forwarding frame to the FE: {}", err);
+            }
+        }
+    };
+    // the sender is moved into the call, so the forwarder finishes as soon as the call does
+    let (result, _) = tokio::join!(call(tx), forward);
+    result
+}
+
+async fn cli_command(
+    command: CliCommand,
+    state: Arc<ChatState>,
+    fe_write_stream: &WsWriteStream,
+) -> CliResponse {
+    match streamed_cli_command(command, state, fe_write_stream).await {
+        Ok(result) => CliResponse {
+            output: result.render(),
+            status: if result.success() {
+                ResponseStatus::Success
+            } else {
+                ResponseStatus::Failure
+            },
         },
         Err(e) => CliResponse {
             output: e.to_string(),
@@ -146,11 +395,382 @@ async fn cli_command(command: CliCommand, state: Arc<ChatState>) -> CliResponse
     }
 }
 
-fn extract_command_from_frontend_message(command: String) -> Option<CliCommand> {
-    // this should be simple, the LLM should structure its output such that it specifies whether the cmd is READONLY or WRITE/EXECUTE
-    let cmd = CliCommand {
-        command_type: CliCommandType::WriteExecuteCliCommand,
-        command,
+// the command's output goes to the FE as it's printed; the model gets all of it once it's done
+async fn streamed_cli_command(
+    mut command: CliCommand,
+    state: Arc<ChatState>,
+    fe_write_stream: &WsWriteStream,
+) -> Result<CommandResult, IronError> {
+    // the tool call id ties the output to the call the FE already saw in the assistant's reply
+    let command_id = command
+        .tool_call_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    // settled up front, so the FE knows whether to show a terminal before any output arrives
+    let tty = state.use_tty(command.tty)?;
+    command.tty = Some(tty);
+    let started = FrontendFrame::CommandStarted {
+        command_id: command_id.clone(),
+        command: command.command.clone(),
+        tty,
+    };
+    if let Err(err) = send_frame(fe_write_stream, &started).await {
+        eprintln!("Error sending command start to the FE: {}", err);
+    }
+    let output_id = command_id.clone();
+    let input_id = command_id.clone();
+    let result =
+        with_streamed_frames(
+            fe_write_stream,
+            |chunk: OutputChunk| FrontendFrame::CommandOutput {
+                command_id: output_id.clone(),
+                stream: chunk.stream,
+                content: chunk.content,
+            },
+            |output_tx| async move {
+                handle_cli_command(command, &input_id, &state, Some(output_tx)).await
+            },
+        )
+        .await?;
+    let finished = FrontendFrame::CommandFinished(CommandFinishedEvent::new(command_id, &result));
+    if let Err(err) = send_frame(fe_write_stream, &finished).await {
+        eprintln!("Error sending command end to the FE: {}", err);
+    }
+    Ok(result)
+}
+
+// commands the session's preferences don't let run on their own wait for the user to ack them
+async fn approved(
+    command: &CliCommand,
+    chat_state: &ChatState,
+    fe_write_stream: &WsWriteStream,
+) -> Result<bool, IronError> {
+    if chat_state.get_preferences()?.autoruns(command.command_type) {
+        return Ok(true);
+    }
+    let command_id = command.tool_call_id.clone().unwrap_or_default();
+    let approval = chat_state.await_approval(&command_id)?;
+    let proposed = FrontendFrame::CommandProposed {
+        command_id,
+        command: command.command.clone(),
+        command_type: command.command_type,
     };
-    return Some(cmd);
+    send_frame(fe_write_stream, &proposed).await?;
+    // the session going away drops the sender, which counts as not approving
+    Ok(approval.await.unwrap_or(false))
+}
+
+fn extract_command_from_tool_call(
+    tool_call: &ToolCall,
+    profile: &PromptProfile,
+) -> Result<CliCommand, String> {
+    // the model says in the tool arguments whether the cmd is READONLY or WRITE/EXECUTE, but that can only
+    // make it more careful: a read_only command that looks like it could change something counts as modify
+    let args = parse_run_command(tool_call)?;
+    let kind = checked_kind(args.kind, &args.command);
+    if !profile.allows(kind) {
+        return Err(format!(
+            "The {} profile doesn't allow {:?} commands; this one was not run: {}",
+            profile.name, kind, args.command
+        ));
+    }
+    Ok(CliCommand {
+        command_type: kind.into(),
+        command: args.command,
+        tool_call_id: Some(tool_call.id.clone()),
+        timeout_secs: args.timeout_secs,
+        tty: args.tty,
+    })
+}
+
+fn checked_kind(declared: CommandKind, command: &str) -> CommandKind {
+    match declared {
+        CommandKind::Modify => CommandKind::Modify,
+        CommandKind::ReadOnly => classify_command(command),
+    }
+}
+
+// a call that wasn't run still gets its answer into the chat's history, the way one that ran does, so
+// later requests know it was turned down rather than asking for it again
+fn record_unrun_call(
+    state: &ChatState,
+    tool_call: &ToolCall,
+    output: &str,
+) -> Result<(), IronError> {
+    let (kind, command) = match parse_run_command(tool_call) {
+        Ok(args) => (checked_kind(args.kind, &args.command), args.command),
+        // arguments we couldn't make sense of are kept as they came
+        Err(_) => (CommandKind::Modify, tool_call.arguments.clone()),
+    };
+    let command_type: CliCommandType = kind.into();
+    state.add_tool_message_to_state(command_type.into(), command, tool_call.id.clone())?;
+    state.add_tool_message_to_state(
+        MessageType::CliOutput,
+        output.to_string(),
+        tool_call.id.clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use std::time::Duration;
+
+    use crate::llm::mock::MockProvider;
+    use crate::llm::tools::RUN_COMMAND_TOOL;
+    use crate::testing::{chat_state, frame_types, Frontend};
+
+    fn prompt(content: &str) -> ContextMessage {
+        ContextMessage {
+            message_type: MessageType::UserPrompt,
+            content: content.to_string(),
+            timestamp: None,
+            tool_call_id: None,
+            command_result: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_without_a_command_stops_the_action() {
+        let state = chat_state(Arc::new(MockProvider::with_responses(
+            "mock-model".to_string(),
+            vec!["nothing to run".to_string()],
+        )));
+        let fe = Frontend::connect().await;
+
+        let outcome = handle_chat_action(prompt("hi"), state, fe.write.clone())
+            .await
+            .unwrap();
+
+        assert!(matches!(outcome, ChatActionOutcome::Stop));
+        let frames = fe.frames().await;
+        assert_eq!(
+            frame_types(&frames),
+            vec!["assistant_delta", "assistant_response", "usage"]
+        );
+        assert_eq!(frames[1]["output"], "nothing to run");
+        assert_eq!(frames[1]["status"], "Success");
+    }
+
+    #[tokio::test]
+    async fn proposed_command_runs_and_its_output_goes_back_to_the_model() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let fe = Frontend::connect().await;
+
+        let outcome = handle_chat_action(prompt("$ echo hi"), state.clone(), fe.write.clone())
+            .await
+            .unwrap();
+
+        assert!(matches!(outcome, ChatActionOutcome::Continue));
+        let frames = fe.frames().await;
+        let types = frame_types(&frames);
+        assert_eq!(types.first(), Some(&"assistant_response"));
+        assert_eq!(types.last(), Some(&"usage"));
+        let started = frames
+            .iter()
+            .find(|frame| frame["type"] == "command_started")
+            .unwrap();
+        assert_eq!(started["command"], "echo hi");
+        let finished = frames
+            .iter()
+            .find(|frame| frame["type"] == "command_finished")
+            .unwrap();
+        assert_eq!(finished["command_id"], started["command_id"]);
+        assert_eq!(finished["exit_code"], 0);
+        let streamed: String = frames
+            .iter()
+            .filter(|frame| frame["type"] == "command_output")
+            .filter_map(|frame| frame["content"].as_str())
+            .collect();
+        assert_eq!(streamed, "hi\n");
+
+        // the follow-up reply echoes the tool result, which holds what the command printed
+        let reply = frames
+            .iter()
+            .rfind(|frame| frame["type"] == "assistant_response")
+            .unwrap();
+        let output = reply["output"].as_str().unwrap();
+        assert!(output.starts_with("mock response to: "), "{}", output);
+        assert!(output.contains("hi"), "{}", output);
+
+        let kinds: Vec<_> = state
+            .get_context()
+            .unwrap()
+            .into_iter()
+            .map(|m| m.message_type)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                MessageType::UserPrompt,
+                MessageType::ReadOnlyCliCommand,
+                MessageType::CliOutput,
+                MessageType::AssistantResponse,
+            ]
+        );
+    }
+
+    // the model asks for one command that changes something, then answers whatever comes back
+    fn modifying_command(command: &str) -> Arc<ChatState> {
+        proposing(command, "modify")
+    }
+
+    fn proposing(command: &str, kind: &str) -> Arc<ChatState> {
+        chat_state(Arc::new(MockProvider::with_completions(
+            "mock-model".to_string(),
+            vec![Completion {
+                model: "mock-model".to_string(),
+                content: String::new(),
+                tool_calls: vec![ToolCall {
+                    id: "call_1".to_string(),
+                    name: RUN_COMMAND_TOOL.to_string(),
+                    arguments: json!({"command": command, "kind": kind, "rationale": "test"})
+                        .to_string(),
+                }],
+                usage: None,
+            }],
+        )))
+    }
+
+    async fn waiting_for_approval(state: &ChatState, command_id: &str) {
+        for _ in 0..200 {
+            if state
+                .command_approvals
+                .lock()
+                .unwrap()
+                .contains_key(command_id)
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never waited for approval", command_id);
+    }
+
+    fn last_reply(frames: &[serde_json::Value]) -> String {
+        frames
+            .iter()
+            .rfind(|frame| frame["type"] == "assistant_response")
+            .and_then(|frame| frame["output"].as_str())
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn modifying_command_waits_for_the_users_ack() {
+        let state = modifying_command("echo changed");
+        let fe = Frontend::connect().await;
+
+        let action = tokio::spawn(handle_chat_action(
+            prompt("change something"),
+            state.clone(),
+            fe.write.clone(),
+        ));
+        waiting_for_approval(&state, "call_1").await;
+        state.approve_command("call_1").unwrap();
+        action.await.unwrap().unwrap();
+
+        let frames = fe.frames().await;
+        let types = frame_types(&frames);
+        let proposed = types.iter().position(|t| *t == "command_proposed").unwrap();
+        let started = types.iter().position(|t| *t == "command_started").unwrap();
+        assert!(proposed < started, "{:?}", types);
+        assert_eq!(frames[proposed]["command_id"], "call_1");
+        assert_eq!(frames[proposed]["command"], "echo changed");
+        assert_eq!(frames[proposed]["command_type"], "WriteExecuteCliCommand");
+        assert!(last_reply(&frames).contains("changed"));
+    }
+
+    #[tokio::test]
+    async fn cancelled_command_is_not_run() {
+        let state = modifying_command("echo changed");
+        let fe = Frontend::connect().await;
+
+        let action = tokio::spawn(handle_chat_action(
+            prompt("change something"),
+            state.clone(),
+            fe.write.clone(),
+        ));
+        waiting_for_approval(&state, "call_1").await;
+        state.cancel_commands();
+        action.await.unwrap().unwrap();
+
+        let frames = fe.frames().await;
+        assert!(!frame_types(&frames).contains(&"command_started"));
+        assert!(last_reply(&frames).contains("didn't approve"));
+        assert!(state.approve_command("call_1").is_err());
+
+        // the next request still sees the call, and that it was turned down
+        let messages = state.get_messages().unwrap();
+        let result = messages
+            .iter()
+            .find(|msg| msg.tool_call_id.as_deref() == Some("call_1"))
+            .unwrap();
+        assert!(result.content.contains("didn't approve"), "{:?}", messages);
+        assert!(messages
+            .iter()
+            .any(|msg| msg.tool_calls.iter().any(|call| call.id == "call_1")));
+    }
+
+    #[tokio::test]
+    async fn autorun_all_runs_modifying_commands_right_away() {
+        let state = modifying_command("echo changed");
+        let mut preferences = state.get_preferences().unwrap();
+        preferences.autorun_all = true;
+        state.set_preferences(preferences).unwrap();
+        let fe = Frontend::connect().await;
+
+        handle_chat_action(prompt("change something"), state, fe.write.clone())
+            .await
+            .unwrap();
+
+        let types = frame_types(&fe.frames().await).join(",");
+        assert!(!types.contains("command_proposed"), "{}", types);
+        assert!(types.contains("command_finished"), "{}", types);
+    }
+
+    #[tokio::test]
+    async fn command_the_model_calls_read_only_still_needs_an_ack_if_it_writes() {
+        let state = proposing("rm -rf target", "read_only");
+        let fe = Frontend::connect().await;
+
+        let action = tokio::spawn(handle_chat_action(
+            prompt("clean up"),
+            state.clone(),
+            fe.write.clone(),
+        ));
+        waiting_for_approval(&state, "call_1").await;
+        state.cancel_commands();
+        action.await.unwrap().unwrap();
+
+        let frames = fe.frames().await;
+        let proposed = frames
+            .iter()
+            .find(|frame| frame["type"] == "command_proposed")
+            .unwrap();
+        assert_eq!(proposed["command_type"], "WriteExecuteCliCommand");
+    }
+
+    #[test]
+    fn read_only_profile_refuses_commands_that_write_whatever_the_model_says() {
+        let mut profile = PromptProfile::default_profile();
+        profile.name = "ops".to_string();
+        profile.allowed_command_kinds = vec![CommandKind::ReadOnly];
+        let call = |command: &str| ToolCall {
+            id: "call_1".to_string(),
+            name: RUN_COMMAND_TOOL.to_string(),
+            arguments: json!({"command": command, "kind": "read_only"}).to_string(),
+        };
+
+        let allowed = extract_command_from_tool_call(&call("ls -la"), &profile).unwrap();
+        assert_eq!(allowed.command_type, CliCommandType::ReadOnlyCliCommand);
+        let err = extract_command_from_tool_call(&call("echo x > notes"), &profile).unwrap_err();
+        assert!(
+            err.starts_with("The ops profile doesn't allow Modify commands"),
+            "{}",
+            err
+        );
+    }
 }
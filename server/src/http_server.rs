@@ -1,22 +1,35 @@
 // http server entry point
-use actix_web::{get, web, App, HttpServer, Responder};
-use std::sync::Mutex;
-use uuid::Uuid;
+use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
 
-use crate::state::app_state::{ChatState, SharedChatState};
+use crate::state::app_state::SharedChatState;
 
 #[get("/")]
 async fn index() -> impl Responder {
-    format!("Hello world from iron!")
+    "Hello world from iron!".to_string()
+}
+
+// what the session has spent on llm calls so far, broken down by source and model
+#[get("/usage")]
+async fn usage(chat_state: web::Data<SharedChatState>) -> impl Responder {
+    match chat_state.usage_summary() {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
 }
 
 // probably need some other functions here for stripe plans ad stuff, but the core logic shouldnt be on this server
 
 // http server setup and routing
 // #[actix_web::main]
-pub async fn start_http_server() -> std::io::Result<()> {
-    HttpServer::new(move || App::new().service(index))
-        .bind(("127.0.0.1", 6001))?
-        .run()
-        .await
+pub async fn start_http_server(chat_state: SharedChatState) -> std::io::Result<()> {
+    let chat_state = web::Data::new(chat_state);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(chat_state.clone())
+            .service(index)
+            .service(usage)
+    })
+    .bind(("127.0.0.1", 6001))?
+    .run()
+    .await
 }
@@ -1,6 +1,14 @@
 // shared library code -- not sure if this needs to contain anything yet?
+pub mod config;
 pub mod db;
+pub mod error;
+pub mod exec;
 pub mod handlers;
 pub mod http_server;
+pub mod llm;
+pub mod prompts;
+pub mod protocol;
 pub mod state;
+#[cfg(test)]
+mod testing;
 pub mod websocket_server;
@@ -0,0 +1,642 @@
+// anthropic messages api provider
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::Client;
+use tokio::sync::mpsc;
+
+use crate::config::app_config::ProviderSettings;
+use crate::llm::anthropic_api::{
+    ApiError, BlockDelta, ContentBlock, Message, MessagesRequest, MessagesResponse,
+    MessagesStreamEvent, ThinkingConfig, ToolDefinition,
+};
+use crate::llm::provider::{
+    channel_stream, ChatMessage, ChatRequest, Completion, CompletionStream, LlmProvider, ModelInfo,
+    ProviderError, ReasoningEffort, Role, StreamEvent, TokenUsage, ToolCall,
+};
+use crate::llm::sse::sse_events;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// the messages api requires an explicit output budget
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Debug)]
+pub struct AnthropicProvider {
+    client: Client,
+    api_key: Option<String>,
+    model: String,
+    base_url: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(settings: &ProviderSettings) -> Self {
+        Self {
+            client: Client::new(),
+            api_key: settings.api_key.clone(),
+            model: settings.model.clone(),
+            base_url: settings.base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn build_body(&self, request: &ChatRequest, stream: bool) -> MessagesRequest {
+        // history is append-only, so marking the system prompt and the newest block as cache
+        // breakpoints lets every call reuse the prefix written by the previous one
+        let mut messages = messages_json(&request.messages);
+        if let Some(last_block) = messages.last_mut().and_then(|msg| msg.content.last_mut()) {
+            last_block.set_cache_breakpoint();
+        }
+        let mut system = Vec::new();
+        if !request.system_prompt.is_empty() {
+            let mut block = ContentBlock::text(request.system_prompt.clone());
+            block.set_cache_breakpoint();
+            system.push(block);
+        }
+        let tools = request
+            .tools
+            .iter()
+            .map(|tool| ToolDefinition {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                input_schema: tool.parameters.clone(),
+            })
+            .collect();
+
+        let max_tokens = request.sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        // we don't keep thinking blocks around, and the api wants them echoed back when answering
+        // a tool call made while thinking, so tool result follow-ups go without
+        let answering_tools = request
+            .messages
+            .last()
+            .is_some_and(|msg| msg.role == Role::Tool);
+        let thinking = request
+            .sampling
+            .reasoning_effort
+            .and_then(thinking_budget)
+            .filter(|_| !answering_tools)
+            .map(|budget_tokens| ThinkingConfig {
+                kind: "enabled",
+                budget_tokens,
+            });
+
+        MessagesRequest {
+            model: request.model.clone().unwrap_or_else(|| self.model.clone()),
+            // thinking comes out of max_tokens, so the reply keeps the room it was given
+            max_tokens: max_tokens + thinking.as_ref().map_or(0, |t| t.budget_tokens),
+            messages,
+            stream,
+            system,
+            tools,
+            // thinking only works at the default temperature
+            temperature: request.sampling.temperature.filter(|_| thinking.is_none()),
+            thinking,
+        }
+    }
+
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let api_key = self.api_key.as_ref().ok_or_else(|| {
+            ProviderError::Config("Missing ANTHROPIC_API_KEY env variable".into())
+        })?;
+
+        let response = self
+            .client
+            .post(format!("{}/v1/messages", self.base_url))
+            .header("Content-Type", "application/json")
+            .header("x-api-key", api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&self.build_body(request, stream))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(ProviderError::from_response(response).await);
+        }
+        Ok(response)
+    }
+}
+
+// 1024 is the smallest budget the api accepts
+fn thinking_budget(effort: ReasoningEffort) -> Option<u32> {
+    match effort {
+        ReasoningEffort::Minimal => None,
+        ReasoningEffort::Low => Some(1_024),
+        ReasoningEffort::Medium => Some(4_096),
+        ReasoningEffort::High => Some(16_384),
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo {
+            provider: "anthropic".to_string(),
+            model: self.model.clone(),
+            supports_tools: true,
+            supports_streaming: true,
+        }
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+        let response: MessagesResponse = self.send(request, false).await?.json().await?;
+
+        let mut content = String::new();
+        let mut tool_calls = Vec::new();
+        for block in response.content {
+            match block {
+                ContentBlock::Text { text, .. } => content.push_str(&text),
+                ContentBlock::ToolUse {
+                    id, name, input, ..
+                } => tool_calls.push(ToolCall {
+                    id,
+                    name,
+                    arguments: input.to_string(),
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(Completion {
+            model: response.model.unwrap_or_else(|| self.model.clone()),
+            content,
+            tool_calls,
+            usage: response.usage.map(TokenUsage::from),
+        })
+    }
+
+    async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+        let response = self.send(request, true).await?;
+        let mut events = sse_events(response);
+        let mut model = request.model.clone().unwrap_or_else(|| self.model.clone());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut content = String::new();
+            // tool_use blocks by content block index; their input arrives as partial json fragments
+            let mut tool_blocks: Vec<(usize, ToolCall)> = Vec::new();
+            let mut usage = None;
+            let mut stopped = false;
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        let _ = tx.send(Err(err));
+                        return;
+                    }
+                };
+                let payload: MessagesStreamEvent = match serde_json::from_str(&event.data) {
+                    Ok(payload) => payload,
+                    Err(err) => {
+                        let _ = tx.send(Err(ProviderError::InvalidResponse(err.to_string())));
+                        return;
+                    }
+                };
+
+                match payload {
+                    MessagesStreamEvent::MessageStart { message } => {
+                        if let Some(start_model) = message.model {
+                            model = start_model;
+                        }
+                        usage = message.usage.map(TokenUsage::from);
+                    }
+                    MessagesStreamEvent::MessageDelta {
+                        usage: Some(delta_usage),
+                    } => {
+                        // output tokens are cumulative; the prompt side was settled by message_start
+                        usage
+                            .get_or_insert_with(TokenUsage::default)
+                            .completion_tokens = delta_usage.output_tokens;
+                    }
+                    MessagesStreamEvent::ContentBlockStart {
+                        index,
+                        content_block: ContentBlock::ToolUse { id, name, .. },
+                    } => tool_blocks.push((
+                        index,
+                        ToolCall {
+                            id,
+                            name,
+                            arguments: String::new(),
+                        },
+                    )),
+                    MessagesStreamEvent::ContentBlockDelta {
+                        delta: BlockDelta::TextDelta { text },
+                        ..
+                    } => {
+                        if text.is_empty() {
+                            continue;
+                        }
+                        content.push_str(&text);
+                        if tx.send(Ok(StreamEvent::Delta(text))).is_err() {
+                            // receiver is gone, nobody cares about the rest of the stream
+                            return;
+                        }
+                    }
+                    MessagesStreamEvent::ContentBlockDelta {
+                        index,
+                        delta: BlockDelta::InputJsonDelta { partial_json },
+                    } => {
+                        if let Some((_, call)) = tool_blocks.iter_mut().find(|(i, _)| *i == index) {
+                            call.arguments.push_str(&partial_json);
+                        }
+                    }
+                    MessagesStreamEvent::MessageStop => {
+                        stopped = true;
+                        break;
+                    }
+                    MessagesStreamEvent::Error { error } => {
+                        let _ = tx.send(Err(stream_error(error)));
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+            // a connection cut mid-reply also ends the body cleanly, so only message_stop says it's complete
+            if !stopped {
+                let _ = tx.send(Err(ProviderError::Transport(
+                    "stream ended before message_stop".into(),
+                )));
+                return;
+            }
+
+            let tool_calls = tool_blocks
+                .into_iter()
+                .map(|(_, mut call)| {
+                    // a tool with no arguments streams no input fragments at all
+                    if call.arguments.is_empty() {
+                        call.arguments = "{}".to_string();
+                    }
+                    call
+                })
+                .collect();
+            let _ = tx.send(Ok(StreamEvent::Done(Completion {
+                model,
+                content,
+                tool_calls,
+                usage,
+            })));
+        });
+
+        Ok(channel_stream(rx))
+    }
+}
+
+// errors that show up mid-stream come as an event instead of a status code. only the provider's own
+// trouble is worth retrying; anything it doesn't like about the request will fail the same way again
+fn stream_error(error: ApiError) -> ProviderError {
+    let message = if error.message.is_empty() {
+        "unknown streaming error".to_string()
+    } else {
+        error.message
+    };
+    match error.kind.as_str() {
+        "rate_limit_error" => ProviderError::RateLimited {
+            message,
+            retry_after: None,
+        },
+        "overloaded_error" => ProviderError::Unavailable {
+            status: 529,
+            message,
+            retry_after: None,
+        },
+        "api_error" => ProviderError::Unavailable {
+            status: 500,
+            message,
+            retry_after: None,
+        },
+        "authentication_error" | "permission_error" => ProviderError::Auth(message),
+        "not_found_error" => ProviderError::Rejected {
+            status: 404,
+            message,
+        },
+        "request_too_large" => ProviderError::Rejected {
+            status: 413,
+            message,
+        },
+        // invalid_request_error, and whatever kinds get added later
+        kind => ProviderError::Rejected {
+            status: 400,
+            message: format!("{}: {}", kind, message),
+        },
+    }
+}
+
+// the messages api wants strictly alternating user/assistant turns, with tool results sent as
+// tool_result blocks inside a user turn, so consecutive messages of the same role get merged
+fn messages_json(messages: &[ChatMessage]) -> Vec<Message> {
+    let mut turns: Vec<Message> = Vec::new();
+    for msg in messages {
+        let (role, blocks) = match msg.role {
+            Role::User => ("user", vec![ContentBlock::text(msg.content.clone())]),
+            Role::Tool => (
+                "user",
+                vec![ContentBlock::ToolResult {
+                    tool_use_id: msg.tool_call_id.clone().unwrap_or_default(),
+                    content: msg.content.clone(),
+                    cache_control: None,
+                }],
+            ),
+            Role::Assistant => {
+                let mut blocks = Vec::new();
+                if !msg.content.is_empty() {
+                    blocks.push(ContentBlock::text(msg.content.clone()));
+                }
+                for call in &msg.tool_calls {
+                    blocks.push(ContentBlock::ToolUse {
+                        id: call.id.clone(),
+                        name: call.name.clone(),
+                        input: serde_json::from_str(&call.arguments)
+                            .unwrap_or_else(|_| serde_json::json!({})),
+                        cache_control: None,
+                    });
+                }
+                ("assistant", blocks)
+            }
+        };
+        match turns.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => turns.push(Message {
+                role,
+                content: blocks,
+            }),
+        }
+    }
+    turns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    use crate::config::app_config::RetryConfig;
+    use crate::llm::retry::RetryingProvider;
+    use crate::testing::{stand_in, Canned};
+
+    fn provider(base_url: String) -> AnthropicProvider {
+        AnthropicProvider::new(&ProviderSettings {
+            api_key: Some("test-key".to_string()),
+            model: "claude-test".to_string(),
+            base_url,
+            supports_tools: true,
+            supports_streaming: true,
+        })
+    }
+
+    fn request() -> ChatRequest {
+        ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")])
+    }
+
+    fn message_start() -> (&'static str, serde_json::Value) {
+        (
+            "message_start",
+            json!({"type": "message_start", "message": {
+                "model": "claude-test-20250101",
+                "usage": {"input_tokens": 12, "output_tokens": 1},
+            }}),
+        )
+    }
+
+    fn text_delta(text: &str) -> (&'static str, serde_json::Value) {
+        (
+            "content_block_delta",
+            json!({"type": "content_block_delta", "index": 0,
+                "delta": {"type": "text_delta", "text": text}}),
+        )
+    }
+
+    fn message_stop() -> (&'static str, serde_json::Value) {
+        ("message_stop", json!({"type": "message_stop"}))
+    }
+
+    fn stream_error_event(kind: &str) -> (&'static str, serde_json::Value) {
+        (
+            "error",
+            json!({"type": "error", "error": {"type": kind, "message": "went wrong"}}),
+        )
+    }
+
+    async fn collect(stream: CompletionStream) -> Vec<Result<StreamEvent, ProviderError>> {
+        stream.collect().await
+    }
+
+    fn completion(events: &[Result<StreamEvent, ProviderError>]) -> &Completion {
+        match events.last() {
+            Some(Ok(StreamEvent::Done(completion))) => completion,
+            other => panic!("expected a completion, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_text_and_tool_use() {
+        let (base_url, requests) = stand_in(vec![Canned::event_stream(&[
+            message_start(),
+            ("ping", json!({"type": "ping"})),
+            text_delta("Let me "),
+            text_delta("look."),
+            (
+                "content_block_start",
+                json!({"type": "content_block_start", "index": 1, "content_block":
+                    {"type": "tool_use", "id": "toolu_1", "name": "run_command", "input": {}}}),
+            ),
+            (
+                "content_block_delta",
+                json!({"type": "content_block_delta", "index": 1,
+                    "delta": {"type": "input_json_delta", "partial_json": "{\"command\": "}}),
+            ),
+            (
+                "content_block_delta",
+                json!({"type": "content_block_delta", "index": 1,
+                    "delta": {"type": "input_json_delta", "partial_json": "\"ls\"}"}}),
+            ),
+            (
+                "message_delta",
+                json!({"type": "message_delta", "usage": {"output_tokens": 30}}),
+            ),
+            message_stop(),
+        ])])
+        .await;
+
+        let events = collect(provider(base_url).stream(&request()).await.unwrap()).await;
+
+        let deltas: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(StreamEvent::Delta(delta)) => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec!["Let me ", "look."]);
+        let completion = completion(&events);
+        assert_eq!(completion.model, "claude-test-20250101");
+        assert_eq!(completion.content, "Let me look.");
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].id, "toolu_1");
+        assert_eq!(completion.tool_calls[0].arguments, r#"{"command": "ls"}"#);
+        let usage = completion.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 30);
+
+        let sent = &requests.lock().unwrap()[0];
+        assert_eq!(sent["stream"], true);
+        assert_eq!(sent["system"][0]["text"], "sys");
+    }
+
+    #[tokio::test]
+    async fn stream_cut_off_before_message_stop_is_an_error() {
+        let (base_url, _) = stand_in(vec![Canned::event_stream(&[
+            message_start(),
+            text_delta("Let"),
+        ])])
+        .await;
+
+        let events = collect(provider(base_url).stream(&request()).await.unwrap()).await;
+
+        assert!(matches!(events[0], Ok(StreamEvent::Delta(_))));
+        assert!(
+            matches!(events.last(), Some(Err(ProviderError::Transport(_)))),
+            "{:?}",
+            events
+        );
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Ok(StreamEvent::Done(_)))));
+    }
+
+    #[tokio::test]
+    async fn overloaded_mid_stream_is_retryable() {
+        let (base_url, _) = stand_in(vec![Canned::event_stream(&[
+            message_start(),
+            stream_error_event("overloaded_error"),
+        ])])
+        .await;
+
+        let events = collect(provider(base_url).stream(&request()).await.unwrap()).await;
+
+        match events.last() {
+            Some(Err(err @ ProviderError::Unavailable { status: 529, .. })) => {
+                assert!(err.is_retryable())
+            }
+            other => panic!("expected an overload, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_request_mid_stream_is_not_retryable() {
+        let (base_url, _) = stand_in(vec![Canned::event_stream(&[
+            message_start(),
+            stream_error_event("invalid_request_error"),
+        ])])
+        .await;
+
+        let events = collect(provider(base_url).stream(&request()).await.unwrap()).await;
+
+        match events.last() {
+            Some(Err(
+                err @ ProviderError::Rejected {
+                    status: 400,
+                    message,
+                },
+            )) => {
+                assert!(!err.is_retryable());
+                assert!(!err.should_fall_back());
+                assert!(message.starts_with("invalid_request_error"), "{}", message);
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_the_providers_own_trouble_is_retryable() {
+        let retryable = |kind: &str| {
+            stream_error(ApiError {
+                kind: kind.to_string(),
+                message: String::new(),
+            })
+            .is_retryable()
+        };
+        assert!(retryable("overloaded_error"));
+        assert!(retryable("api_error"));
+        assert!(retryable("rate_limit_error"));
+        assert!(!retryable("invalid_request_error"));
+        assert!(!retryable("authentication_error"));
+        assert!(!retryable("not_found_error"));
+        assert!(!retryable("request_too_large"));
+        assert!(!retryable("some_future_error"));
+    }
+
+    #[tokio::test]
+    async fn error_status_is_classified() {
+        let error = |kind: &str| json!({"type": "error", "error": {"type": kind, "message": "no"}});
+        let (base_url, _) = stand_in(vec![
+            Canned::json(529, error("overloaded_error")).with_header("retry-after", "3"),
+            Canned::json(400, error("invalid_request_error")),
+            Canned::json(401, error("authentication_error")),
+        ])
+        .await;
+        let provider = provider(base_url);
+
+        match provider.complete(&request()).await {
+            Err(ProviderError::Unavailable {
+                status: 529,
+                retry_after,
+                ..
+            }) => assert_eq!(retry_after, Some(std::time::Duration::from_secs(3))),
+            other => panic!("expected an overload, got {:?}", other),
+        }
+        assert!(matches!(
+            provider.complete(&request()).await,
+            Err(ProviderError::Rejected { status: 400, .. })
+        ));
+        assert!(matches!(
+            provider.complete(&request()).await,
+            Err(ProviderError::Auth(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn overloaded_stream_is_opened_again() {
+        let overloaded =
+            json!({"type": "error", "error": {"type": "overloaded_error", "message": "busy"}});
+        let (base_url, requests) = stand_in(vec![
+            Canned::json(529, overloaded.clone()),
+            Canned::json(529, overloaded),
+            Canned::event_stream(&[message_start(), text_delta("hi"), message_stop()]),
+        ])
+        .await;
+        let retrying = RetryingProvider::new(
+            Arc::new(provider(base_url)),
+            RetryConfig {
+                max_attempts: 3,
+                base_delay_ms: 1,
+                max_delay_ms: 5,
+            },
+        );
+
+        let events = collect(retrying.stream(&request()).await.unwrap()).await;
+
+        assert_eq!(completion(&events).content, "hi");
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cache_reads_and_writes_are_counted_apart() {
+        let (base_url, _) = stand_in(vec![Canned::json(
+            200,
+            json!({"model": "claude-test", "content": [{"type": "text", "text": "ok"}],
+                "usage": {"input_tokens": 10, "output_tokens": 5,
+                    "cache_read_input_tokens": 100, "cache_creation_input_tokens": 40}}),
+        )])
+        .await;
+        let usage = provider(base_url)
+            .complete(&request())
+            .await
+            .unwrap()
+            .usage
+            .unwrap();
+        assert_eq!(usage.prompt_tokens, 150);
+        assert_eq!(usage.cached_prompt_tokens, 100);
+        assert_eq!(usage.cache_write_prompt_tokens, 40);
+    }
+}
@@ -0,0 +1,378 @@
+// record/replay of llm traffic: record mode writes every request and its completion to a cassette file,
+// replay mode answers from that file instead of a provider, so chats can be rerun deterministically
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::config::app_config::{CassetteConfig, CassetteMode, ProviderKind};
+use crate::error::IronError;
+use crate::llm::provider::{
+    single_completion_stream, ChatRequest, Completion, CompletionStream, LlmProvider, ModelInfo,
+    ProviderError, StreamEvent,
+};
+
+// 2 added model_info, which replays need to make the same requests the recording did
+const CASSETTE_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Interaction {
+    pub key: String,
+    pub provider: ProviderKind,
+    // kept for whoever has to figure out why a replay didn't match; only the key is compared
+    pub request: serde_json::Value,
+    pub completion: Completion,
+    // what the provider said about itself, which decides e.g. whether requests use tools
+    pub model_info: ModelInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CassetteFile {
+    version: u32,
+    interactions: Vec<Interaction>,
+}
+
+// shared by every provider of a registry, so one file holds a whole session
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Mutex<Vec<Interaction>>,
+    // replay position per key; a request that was made several times gets its answers in recorded order
+    served: Mutex<HashMap<String, usize>>,
+}
+
+impl Cassette {
+    // recording starts from an empty cassette; replaying needs one that exists
+    pub fn open(config: &CassetteConfig) -> Result<Self, IronError> {
+        let interactions = match config.mode {
+            CassetteMode::Record => Vec::new(),
+            CassetteMode::Replay => {
+                let text = fs::read_to_string(&config.path).map_err(|e| {
+                    IronError::Config(format!(
+                        "Failed to read cassette {}: {}",
+                        config.path.display(),
+                        e
+                    ))
+                })?;
+                let file: CassetteFile = serde_json::from_str(&text).map_err(|e| {
+                    IronError::Config(format!("Invalid cassette {}: {}", config.path.display(), e))
+                })?;
+                if file.version != CASSETTE_VERSION {
+                    return Err(IronError::Config(format!(
+                        "Cassette {} is version {}, expected {}",
+                        config.path.display(),
+                        file.version,
+                        CASSETTE_VERSION
+                    )));
+                }
+                file.interactions
+            }
+        };
+        Ok(Self {
+            path: config.path.clone(),
+            mode: config.mode,
+            interactions: Mutex::new(interactions),
+            served: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    // rewrites the whole file every time, so a crashed run still leaves a usable cassette
+    fn record(
+        &self,
+        provider: ProviderKind,
+        model_info: ModelInfo,
+        request: &ChatRequest,
+        completion: &Completion,
+    ) -> Result<(), ProviderError> {
+        let mut interactions = self.interactions.lock().map_err(poisoned)?;
+        interactions.push(Interaction {
+            key: request_key(provider, request),
+            provider,
+            request: serde_json::to_value(request).unwrap_or_default(),
+            completion: completion.clone(),
+            model_info,
+        });
+        let file = CassetteFile {
+            version: CASSETTE_VERSION,
+            interactions: interactions.clone(),
+        };
+        let text = serde_json::to_string_pretty(&file)
+            .map_err(|e| ProviderError::Config(format!("Failed to serialize cassette: {}", e)))?;
+        fs::write(&self.path, text).map_err(|e| {
+            ProviderError::Config(format!(
+                "Failed to write cassette {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    // as recorded; None for a provider the recording never called
+    fn model_info(&self, provider: ProviderKind) -> Option<ModelInfo> {
+        let interactions = self.interactions.lock().ok()?;
+        interactions
+            .iter()
+            .find(|interaction| interaction.provider == provider)
+            .map(|interaction| interaction.model_info.clone())
+    }
+
+    // an unmatched request is an error, never a live call: the test changed, or the cassette is stale
+    fn replay(
+        &self,
+        provider: ProviderKind,
+        request: &ChatRequest,
+    ) -> Result<Completion, ProviderError> {
+        let key = request_key(provider, request);
+        let interactions = self.interactions.lock().map_err(poisoned)?;
+        let matches: Vec<&Interaction> = interactions
+            .iter()
+            .filter(|interaction| interaction.key == key)
+            .collect();
+        if matches.is_empty() {
+            return Err(ProviderError::Config(format!(
+                "No recorded {:?} response in cassette {} for request {} ({} messages, last: {:?})",
+                provider,
+                self.path.display(),
+                key,
+                request.messages.len(),
+                request
+                    .messages
+                    .last()
+                    .map(|msg| msg.content.chars().take(80).collect::<String>()),
+            )));
+        }
+        let mut served = self.served.lock().map_err(poisoned)?;
+        let position = served.entry(key).or_insert(0);
+        // asked more often than recorded: the same request gets the same (last) answer
+        let interaction = matches[(*position).min(matches.len() - 1)];
+        *position += 1;
+        Ok(interaction.completion.clone())
+    }
+}
+
+// everything that goes into the request. templated system prompts differ with the directory and git
+// branch, so a cassette replays where it was recorded, or fails loudly instead of answering a changed prompt
+pub fn request_key(provider: ProviderKind, request: &ChatRequest) -> String {
+    let keyed = serde_json::json!({
+        "provider": provider,
+        "model": request.model,
+        "system_prompt": request.system_prompt,
+        "messages": request.messages,
+        "tools": request.tools,
+        "sampling": request.sampling,
+        "response_schema": request.response_schema,
+    });
+    format!("{:016x}", fnv1a(keyed.to_string().as_bytes()))
+}
+
+// std's hashers aren't guaranteed to be stable across releases, and cassettes outlive builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn poisoned<T>(err: std::sync::PoisonError<T>) -> ProviderError {
+    ProviderError::Config(format!("cassette lock poisoned: {}", err))
+}
+
+// wraps a provider when recording, and stands in for it when replaying
+#[derive(Debug)]
+pub struct CassetteProvider {
+    kind: ProviderKind,
+    // None when replaying
+    inner: Option<Arc<dyn LlmProvider>>,
+    // what model_info reports while replaying a cassette that never called this provider: what the
+    // configured provider would say about itself
+    configured: ModelInfo,
+    cassette: Arc<Cassette>,
+}
+
+impl CassetteProvider {
+    pub fn recording(
+        kind: ProviderKind,
+        inner: Arc<dyn LlmProvider>,
+        cassette: Arc<Cassette>,
+    ) -> Self {
+        Self {
+            kind,
+            configured: inner.model_info(),
+            inner: Some(inner),
+            cassette,
+        }
+    }
+
+    pub fn replaying(kind: ProviderKind, configured: ModelInfo, cassette: Arc<Cassette>) -> Self {
+        Self {
+            kind,
+            inner: None,
+            configured,
+            cassette,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for CassetteProvider {
+    fn model_info(&self) -> ModelInfo {
+        match &self.inner {
+            Some(inner) => inner.model_info(),
+            None => self
+                .cassette
+                .model_info(self.kind)
+                .unwrap_or_else(|| self.configured.clone()),
+        }
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+        let Some(inner) = &self.inner else {
+            return self.cassette.replay(self.kind, request);
+        };
+        let completion = inner.complete(request).await?;
+        self.cassette
+            .record(self.kind, inner.model_info(), request, &completion)?;
+        Ok(completion)
+    }
+
+    // replayed completions come back as a single delta; recorded streams are passed through untouched
+    async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+        let Some(inner) = &self.inner else {
+            return Ok(single_completion_stream(
+                self.cassette.replay(self.kind, request)?,
+            ));
+        };
+        let stream = inner.stream(request).await?;
+        let cassette = Arc::clone(&self.cassette);
+        let kind = self.kind;
+        let model_info = inner.model_info();
+        let request = request.clone();
+        Ok(stream
+            .map(move |event| {
+                if let Ok(StreamEvent::Done(completion)) = &event {
+                    cassette.record(kind, model_info.clone(), &request, completion)?;
+                }
+                event
+            })
+            .boxed())
+    }
+
+    async fn probe(&self) -> Result<ModelInfo, ProviderError> {
+        match &self.inner {
+            Some(inner) => inner.probe().await,
+            None => Ok(self.model_info()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    use crate::handlers::handler::handle_chat_action;
+    use crate::llm::mock::MockProvider;
+    use crate::llm::provider::ChatMessage;
+    use crate::state::app_state::{ChatState, ContextMessage, MessageType};
+    use crate::testing::{chat_state, Frontend};
+
+    fn cassette(path: &std::path::Path, mode: CassetteMode) -> Arc<Cassette> {
+        Arc::new(
+            Cassette::open(&CassetteConfig {
+                path: path.to_path_buf(),
+                mode,
+            })
+            .unwrap(),
+        )
+    }
+
+    fn configured() -> ModelInfo {
+        ModelInfo {
+            provider: "configured".to_string(),
+            model: "configured-model".to_string(),
+            supports_tools: false,
+            supports_streaming: false,
+        }
+    }
+
+    // the replies the FE got, in order
+    async fn run_action(state: Arc<ChatState>) -> Vec<String> {
+        let fe = Frontend::connect().await;
+        let prompt = ContextMessage {
+            message_type: MessageType::UserPrompt,
+            content: "say hi\n$ echo hi".to_string(),
+            timestamp: None,
+            tool_call_id: None,
+            command_result: None,
+        };
+        handle_chat_action(prompt, state, fe.write.clone())
+            .await
+            .unwrap();
+        fe.frames()
+            .await
+            .iter()
+            .filter(|frame| frame["type"] == "assistant_response")
+            .map(|frame| frame["output"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recorded_chat_action_replays_without_the_provider() {
+        let path = std::env::temp_dir().join(format!("iron-cassette-{}.json", Uuid::new_v4()));
+        let mock: Arc<dyn LlmProvider> = Arc::new(MockProvider::new("mock-model".to_string()));
+        let recording = CassetteProvider::recording(
+            ProviderKind::Mock,
+            Arc::clone(&mock),
+            cassette(&path, CassetteMode::Record),
+        );
+        let recorded = run_action(chat_state(Arc::new(recording))).await;
+        // the command's output went back to the model, so both calls were recorded
+        assert_eq!(recorded.len(), 2, "{:?}", recorded);
+        assert!(recorded[1].contains("hi"), "{:?}", recorded);
+
+        let replaying = CassetteProvider::replaying(
+            ProviderKind::Mock,
+            configured(),
+            cassette(&path, CassetteMode::Replay),
+        );
+        assert_eq!(replaying.model_info(), mock.model_info());
+        let replayed = run_action(chat_state(Arc::new(replaying))).await;
+        assert_eq!(replayed, recorded);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn provider_the_recording_never_called_reports_its_configured_info() {
+        let path = std::env::temp_dir().join(format!("iron-cassette-{}.json", Uuid::new_v4()));
+        fs::write(&path, r#"{"version": 2, "interactions": []}"#).unwrap();
+        let replaying = CassetteProvider::replaying(
+            ProviderKind::OpenAi,
+            configured(),
+            cassette(&path, CassetteMode::Replay),
+        );
+        assert_eq!(replaying.model_info(), configured());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn system_prompt_is_part_of_the_key() {
+        let request =
+            |system_prompt: &str| ChatRequest::new(system_prompt, vec![ChatMessage::user("hi")]);
+        assert_eq!(
+            request_key(ProviderKind::Mock, &request("a")),
+            request_key(ProviderKind::Mock, &request("a"))
+        );
+        assert_ne!(
+            request_key(ProviderKind::Mock, &request("a")),
+            request_key(ProviderKind::Mock, &request("b"))
+        );
+    }
+}
@@ -0,0 +1,304 @@
+// falls back down a prioritized list of providers/models when one is down or out of quota, for any provider
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::config::app_config::ProviderKind;
+use crate::llm::provider::{
+    ChatRequest, Completion, CompletionStream, LlmProvider, ModelInfo, ProviderError,
+};
+
+// one entry of a session's fallback chain
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FallbackTarget {
+    pub provider: ProviderKind,
+    // None means the provider's configured model
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct FallbackProvider {
+    // the session's own provider first, each with the model its requests go to
+    chain: Vec<(Arc<dyn LlmProvider>, Option<String>)>,
+}
+
+impl FallbackProvider {
+    // `primary` keeps whatever model the request already names
+    pub fn new(
+        primary: Arc<dyn LlmProvider>,
+        fallbacks: Vec<(Arc<dyn LlmProvider>, Option<String>)>,
+    ) -> Self {
+        let mut chain = vec![(primary, None)];
+        chain.extend(fallbacks);
+        Self { chain }
+    }
+
+    // each entry already retried on its own; only errors another provider could avoid move on to the next.
+    // the last entry's error is the one that comes back
+    async fn with_fallbacks<T, F, Fut>(
+        &self,
+        request: &ChatRequest,
+        call: F,
+    ) -> Result<T, ProviderError>
+    where
+        F: Fn(Arc<dyn LlmProvider>, ChatRequest) -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        let mut position = 0;
+        loop {
+            let (provider, model) = &self.chain[position];
+            let attempt = if position == 0 {
+                request.clone()
+            } else {
+                request.clone().with_model(model.clone())
+            };
+            let err = match call(Arc::clone(provider), attempt).await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if !err.should_fall_back() || position + 1 >= self.chain.len() {
+                return Err(err);
+            }
+            position += 1;
+            let (next, next_model) = &self.chain[position];
+            eprintln!(
+                "LLM call failed, falling back to {}: {}",
+                next_model
+                    .clone()
+                    .unwrap_or_else(|| next.model_info().model),
+                err
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for FallbackProvider {
+    // what the session asked for; completions say which model actually answered
+    fn model_info(&self) -> ModelInfo {
+        self.chain[0].0.model_info()
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+        self.with_fallbacks(request, |provider, request| async move {
+            provider.complete(&request).await
+        })
+        .await
+    }
+
+    // like retries, only opening the stream falls back; deltas already sent to the FE can't be taken back
+    async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+        self.with_fallbacks(request, |provider, request| async move {
+            provider.stream(&request).await
+        })
+        .await
+    }
+
+    async fn probe(&self) -> Result<ModelInfo, ProviderError> {
+        self.chain[0].0.probe().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, StreamExt};
+    use std::sync::Mutex;
+
+    use crate::llm::provider::{single_completion_stream, ChatMessage, StreamEvent};
+
+    // answers every call, or fails every call with `error`, and keeps the model each request named
+    #[derive(Debug)]
+    struct Scripted {
+        model: String,
+        error: Option<fn() -> ProviderError>,
+        // the stream opens fine and then breaks after its first delta
+        breaks_mid_stream: bool,
+        requested: Mutex<Vec<Option<String>>>,
+    }
+
+    impl Scripted {
+        fn answers(model: &str) -> Arc<Self> {
+            Self::new(model, None, false)
+        }
+
+        fn fails(model: &str, error: fn() -> ProviderError) -> Arc<Self> {
+            Self::new(model, Some(error), false)
+        }
+
+        fn new(
+            model: &str,
+            error: Option<fn() -> ProviderError>,
+            breaks_mid_stream: bool,
+        ) -> Arc<Self> {
+            Arc::new(Self {
+                model: model.to_string(),
+                error,
+                breaks_mid_stream,
+                requested: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn requested(&self) -> Vec<Option<String>> {
+            self.requested.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl LlmProvider for Scripted {
+        fn model_info(&self) -> ModelInfo {
+            ModelInfo {
+                provider: "scripted".to_string(),
+                model: self.model.clone(),
+                supports_tools: true,
+                supports_streaming: true,
+            }
+        }
+
+        async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+            self.requested.lock().unwrap().push(request.model.clone());
+            if let Some(error) = self.error {
+                return Err(error());
+            }
+            Ok(Completion {
+                model: request.model.clone().unwrap_or_else(|| self.model.clone()),
+                content: format!("from {}", self.model),
+                tool_calls: Vec::new(),
+                usage: None,
+            })
+        }
+
+        async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+            if !self.breaks_mid_stream {
+                return Ok(single_completion_stream(self.complete(request).await?));
+            }
+            self.requested.lock().unwrap().push(request.model.clone());
+            Ok(stream::iter(vec![
+                Ok(StreamEvent::Delta("par".to_string())),
+                Err(ProviderError::Transport("connection reset".into())),
+            ])
+            .boxed())
+        }
+    }
+
+    fn quota() -> ProviderError {
+        ProviderError::QuotaExhausted("out of credits".into())
+    }
+
+    fn unavailable() -> ProviderError {
+        ProviderError::Unavailable {
+            status: 503,
+            message: "down".into(),
+            retry_after: None,
+        }
+    }
+
+    fn request() -> ChatRequest {
+        ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")])
+            .with_model(Some("primary-model".to_string()))
+    }
+
+    fn chain(
+        primary: &Arc<Scripted>,
+        fallbacks: &[(&Arc<Scripted>, Option<&str>)],
+    ) -> FallbackProvider {
+        FallbackProvider::new(
+            primary.clone(),
+            fallbacks
+                .iter()
+                .map(|(provider, model)| {
+                    (
+                        Arc::clone(provider) as Arc<dyn LlmProvider>,
+                        model.map(str::to_string),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn quota_and_outages_move_down_the_chain_with_each_entrys_model() {
+        let primary = Scripted::fails("a", quota);
+        let second = Scripted::fails("b", unavailable);
+        let third = Scripted::answers("c");
+        let provider = chain(&primary, &[(&second, Some("b-small")), (&third, None)]);
+
+        let completion = provider.complete(&request()).await.unwrap();
+
+        assert_eq!(completion.content, "from c");
+        assert_eq!(primary.requested(), vec![Some("primary-model".to_string())]);
+        assert_eq!(second.requested(), vec![Some("b-small".to_string())]);
+        // no model of its own means the provider's configured one, not the primary's
+        assert_eq!(third.requested(), vec![None]);
+        assert_eq!(completion.model, "c");
+    }
+
+    #[tokio::test]
+    async fn rejected_and_auth_errors_dont_fall_back() {
+        for error in [
+            (|| ProviderError::Rejected {
+                status: 400,
+                message: "bad request".into(),
+            }) as fn() -> ProviderError,
+            || ProviderError::Auth("bad key".into()),
+        ] {
+            let primary = Scripted::fails("a", error);
+            let second = Scripted::answers("b");
+            let provider = chain(&primary, &[(&second, None)]);
+
+            let err = provider.complete(&request()).await.unwrap_err();
+
+            assert!(
+                matches!(err, ProviderError::Rejected { .. } | ProviderError::Auth(_)),
+                "{:?}",
+                err
+            );
+            assert!(second.requested().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn the_last_error_comes_back_when_every_entry_fails() {
+        let primary = Scripted::fails("a", unavailable);
+        let second = Scripted::fails("b", quota);
+        let provider = chain(&primary, &[(&second, None)]);
+
+        let err = provider.complete(&request()).await.unwrap_err();
+
+        assert!(matches!(err, ProviderError::QuotaExhausted(_)), "{:?}", err);
+        assert_eq!(primary.requested().len(), 1);
+        assert_eq!(second.requested().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_stream_that_fails_to_open_falls_back() {
+        let primary = Scripted::fails("a", unavailable);
+        let second = Scripted::answers("b");
+        let provider = chain(&primary, &[(&second, None)]);
+
+        let events: Vec<_> = provider.stream(&request()).await.unwrap().collect().await;
+
+        assert!(matches!(
+            events.last(),
+            Some(Ok(StreamEvent::Done(completion))) if completion.content == "from b"
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_stream_that_breaks_once_open_doesnt_fall_back() {
+        let primary = Scripted::new("a", None, true);
+        let second = Scripted::answers("b");
+        let provider = chain(&primary, &[(&second, None)]);
+
+        let events: Vec<_> = provider.stream(&request()).await.unwrap().collect().await;
+
+        assert!(matches!(&events[0], Ok(StreamEvent::Delta(delta)) if delta == "par"));
+        assert!(matches!(
+            events.last(),
+            Some(Err(ProviderError::Transport(_)))
+        ));
+        assert!(second.requested().is_empty());
+    }
+}
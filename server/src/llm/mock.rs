@@ -0,0 +1,111 @@
+// deterministic in-process provider, so local runs and tests never need a network
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::llm::provider::{
+    ChatRequest, Completion, LlmProvider, ModelInfo, ProviderError, Role, ToolCall,
+};
+use crate::llm::tools::RUN_COMMAND_TOOL;
+
+#[derive(Debug)]
+pub struct MockProvider {
+    model: String,
+    // scripted replies are handed out in order; once exhausted we fall back to echoing the last message
+    scripted: Mutex<VecDeque<Completion>>,
+}
+
+impl MockProvider {
+    pub fn new(model: String) -> Self {
+        Self {
+            model,
+            scripted: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn with_responses(model: String, responses: Vec<String>) -> Self {
+        let completions = responses
+            .into_iter()
+            .map(|content| Completion {
+                model: model.clone(),
+                content,
+                tool_calls: Vec::new(),
+                usage: None,
+            })
+            .collect();
+        Self::with_completions(model, completions)
+    }
+
+    pub fn with_completions(model: String, completions: Vec<Completion>) -> Self {
+        Self {
+            model,
+            scripted: Mutex::new(completions.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo {
+            provider: "mock".to_string(),
+            model: self.model.clone(),
+            supports_tools: true,
+            supports_streaming: true,
+        }
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+        let scripted = self
+            .scripted
+            .lock()
+            .map_err(|e| ProviderError::Config(e.to_string()))?
+            .pop_front();
+
+        if let Some(completion) = scripted {
+            return Ok(completion);
+        }
+
+        let model = request.model.clone().unwrap_or_else(|| self.model.clone());
+        let last = request.messages.last();
+        let last_content = last.map(|msg| msg.content.as_str()).unwrap_or("");
+
+        // a user message ending in a `$ <command>` line asks the mock to propose that command, which
+        // makes the tool loop exercisable offline
+        let proposed = last
+            .filter(|msg| msg.role == Role::User)
+            .and_then(|msg| msg.content.lines().last())
+            .and_then(|line| line.trim().strip_prefix("$ "))
+            .filter(|_| {
+                request
+                    .tools
+                    .iter()
+                    .any(|tool| tool.name == RUN_COMMAND_TOOL)
+            });
+        if let Some(command) = proposed {
+            return Ok(Completion {
+                model,
+                content: String::new(),
+                tool_calls: vec![ToolCall {
+                    id: format!("mock_call_{}", request.messages.len()),
+                    name: RUN_COMMAND_TOOL.to_string(),
+                    arguments: json!({
+                        "command": command,
+                        "kind": "read_only",
+                        "rationale": "requested by the user",
+                    })
+                    .to_string(),
+                }],
+                usage: None,
+            });
+        }
+
+        Ok(Completion {
+            model,
+            content: format!("mock response to: {}", last_content),
+            tool_calls: Vec::new(),
+            usage: None,
+        })
+    }
+}
@@ -0,0 +1,15 @@
+// exports llm providers
+pub mod anthropic;
+pub mod anthropic_api;
+pub mod cassette;
+pub mod fallback;
+pub mod mock;
+pub mod openai;
+pub mod openai_api;
+pub mod provider;
+pub mod registry;
+pub mod retry;
+pub mod sse;
+pub mod structured;
+pub mod tokens;
+pub mod tools;
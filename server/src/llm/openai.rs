@@ -0,0 +1,710 @@
+// openai chat-completions provider; also speaks to openai-compatible servers (ollama, llama.cpp, vllm)
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::{Client, StatusCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+
+use crate::config::app_config::{ProviderSettings, DEFAULT_OPENAI_BASE_URL};
+use crate::llm::openai_api::{
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, FunctionCall,
+    FunctionDefinition, JsonSchemaFormat, ModelList, RequestMessage, RequestTool, RequestToolCall,
+    ResponseFormat, StreamOptions,
+};
+use crate::llm::provider::{
+    channel_stream, single_completion_stream, ChatMessage, ChatRequest, Completion,
+    CompletionStream, LlmProvider, ModelInfo, ProviderError, Role, StreamEvent, TokenUsage,
+    ToolCall,
+};
+use crate::llm::sse::sse_events;
+
+#[derive(Debug)]
+pub struct OpenAiProvider {
+    client: Client,
+    api_key: Option<String>,
+    model: String,
+    base_url: String,
+    // talking to api.openai.com itself, rather than a compatible server
+    official: bool,
+    // start out as configured, and get switched off if the server turns out not to support them: tools
+    // when the server's model list says so, streaming when it answers a stream with a plain completion
+    supports_tools: AtomicBool,
+    supports_streaming: AtomicBool,
+}
+
+impl OpenAiProvider {
+    pub fn new(settings: &ProviderSettings) -> Self {
+        let base_url = settings.base_url.trim_end_matches('/').to_string();
+        Self {
+            client: Client::new(),
+            api_key: settings.api_key.clone(),
+            model: settings.model.clone(),
+            official: base_url == DEFAULT_OPENAI_BASE_URL,
+            base_url,
+            supports_tools: AtomicBool::new(settings.supports_tools),
+            supports_streaming: AtomicBool::new(settings.supports_streaming),
+        }
+    }
+
+    fn build_body(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        tools: bool,
+    ) -> ChatCompletionRequest {
+        // most local servers only know the older "system" role
+        let system_role = if self.official { "developer" } else { "system" };
+        let mut messages = vec![RequestMessage {
+            role: system_role.to_string(),
+            content: Some(request.system_prompt.clone()),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }];
+        messages.extend(request.messages.iter().map(request_message));
+
+        let tools = if tools {
+            request
+                .tools
+                .iter()
+                .map(|tool| RequestTool {
+                    kind: "function",
+                    function: FunctionDefinition {
+                        name: tool.name.clone(),
+                        description: tool.description.clone(),
+                        parameters: tool.parameters.clone(),
+                    },
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let model = request.model.clone().unwrap_or_else(|| self.model.clone());
+        // openai answers a 400 to reasoning settings on a model that doesn't reason; preferences are checked
+        // against the chat's model, but a route or fallback can send them to another
+        let reasoning = !self.official || is_reasoning_model(&model);
+        ChatCompletionRequest {
+            model,
+            stream,
+            messages,
+            store: self.official.then_some(true),
+            // compatible servers that don't know stream_options may reject it, and most send usage anyway
+            stream_options: (stream && self.official).then_some(StreamOptions {
+                include_usage: true,
+            }),
+            tools,
+            temperature: request.sampling.temperature,
+            max_completion_tokens: request.sampling.max_tokens.filter(|_| self.official),
+            max_tokens: request.sampling.max_tokens.filter(|_| !self.official),
+            reasoning_effort: request.sampling.reasoning_effort.filter(|_| reasoning),
+            // llama.cpp, ollama and vllm turn this into a grammar, so it holds for local models too
+            response_format: request
+                .response_schema
+                .as_ref()
+                .map(|format| ResponseFormat {
+                    kind: "json_schema",
+                    json_schema: JsonSchemaFormat {
+                        name: format.name.clone(),
+                        schema: format.schema.clone(),
+                        strict: true,
+                    },
+                }),
+        }
+    }
+
+    fn authorize(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder, ProviderError> {
+        match &self.api_key {
+            Some(api_key) => Ok(builder.header("Authorization", format!("Bearer {}", api_key))),
+            // local servers usually don't check auth at all
+            None if !self.official => Ok(builder),
+            None => Err(ProviderError::Config(
+                "Missing OPENAI_API_KEY env variable".into(),
+            )),
+        }
+    }
+
+    async fn post(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+        tools: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let builder = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .header("Content-Type", "application/json")
+            .json(&self.build_body(request, stream, tools));
+        Ok(self.authorize(builder)?.send().await?)
+    }
+
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let tools = self.supports_tools.load(Ordering::Relaxed) && !request.tools.is_empty();
+        let mut response = self.post(request, stream, tools).await?;
+
+        if tools && response.status() == StatusCode::BAD_REQUEST {
+            let body = response.text().await.unwrap_or_default();
+            if !tools_unsupported(&body) {
+                return Err(ProviderError::Rejected {
+                    status: StatusCode::BAD_REQUEST.as_u16(),
+                    message: body,
+                });
+            }
+            // only this request goes without; the provider is shared, and a server can be restarted with
+            // tool calling switched on
+            eprintln!(
+                "Model {} at {} rejected tool definitions, retrying without tool calling",
+                self.model, self.base_url
+            );
+            response = self.post(request, stream, false).await?;
+        }
+
+        if !response.status().is_success() {
+            return Err(ProviderError::from_response(response).await);
+        }
+        Ok(response)
+    }
+
+    fn parse_completion(
+        &self,
+        response: ChatCompletionResponse,
+    ) -> Result<Completion, ProviderError> {
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| ProviderError::InvalidResponse("completion has no choices".into()))?
+            .message;
+        let tool_calls = message
+            .tool_calls
+            .into_iter()
+            .map(|call| ToolCall {
+                id: call.id,
+                name: call.function.name,
+                arguments: call.function.arguments,
+            })
+            .collect();
+
+        Ok(Completion {
+            model: response.model.unwrap_or_else(|| self.model.clone()),
+            // a pure tool call comes back with null content, which is a perfectly good response
+            content: message.content.unwrap_or_default(),
+            tool_calls,
+            usage: response.usage.map(TokenUsage::from),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo {
+            provider: "openai".to_string(),
+            model: self.model.clone(),
+            supports_tools: self.supports_tools.load(Ordering::Relaxed),
+            supports_streaming: self.supports_streaming.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+        let response = self.send(request, false).await?;
+        self.parse_completion(response.json().await?)
+    }
+
+    async fn probe(&self) -> Result<ModelInfo, ProviderError> {
+        let builder = self.client.get(format!("{}/models", self.base_url));
+        let response = self.authorize(builder)?.send().await?;
+        if !response.status().is_success() {
+            return Err(ProviderError::from_response(response).await);
+        }
+        let served = response.json::<ModelList>().await?.data;
+
+        // llama.cpp serves whatever it was started with, under whatever id, so a single entry is a match
+        let entry = served
+            .iter()
+            .find(|entry| entry.id == self.model)
+            .or(if served.len() == 1 {
+                served.first()
+            } else {
+                None
+            });
+        let Some(entry) = entry else {
+            let ids: Vec<&str> = served.iter().map(|entry| entry.id.as_str()).collect();
+            return Err(ProviderError::Config(format!(
+                "Model {} is not served by {} (available: {})",
+                self.model,
+                self.base_url,
+                ids.join(", ")
+            )));
+        };
+
+        // some servers (lm studio, newer ollama) advertise what the model can do
+        if let Some(capabilities) = &entry.capabilities {
+            let tools = capabilities
+                .iter()
+                .any(|cap| cap == "tools" || cap == "tool_use");
+            if !tools {
+                self.supports_tools.store(false, Ordering::Relaxed);
+            }
+        }
+        Ok(self.model_info())
+    }
+
+    async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+        if !self.supports_streaming.load(Ordering::Relaxed) {
+            return Ok(single_completion_stream(self.complete(request).await?));
+        }
+        let response = self.send(request, true).await?;
+
+        // servers that ignore "stream": true just answer with a regular completion
+        let is_event_stream = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if !is_event_stream {
+            eprintln!(
+                "Model {} at {} did not stream its response, falling back to full completions",
+                self.model, self.base_url
+            );
+            self.supports_streaming.store(false, Ordering::Relaxed);
+            return Ok(single_completion_stream(
+                self.parse_completion(response.json().await?)?,
+            ));
+        }
+
+        let mut events = sse_events(response);
+        let mut model = request.model.clone().unwrap_or_else(|| self.model.clone());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut content = String::new();
+            // tool calls arrive in fragments keyed by index; arguments are concatenated across chunks
+            let mut tool_calls: Vec<ToolCall> = Vec::new();
+            let mut usage = None;
+            let mut finished = false;
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        let _ = tx.send(Err(err));
+                        return;
+                    }
+                };
+                if event.data == "[DONE]" {
+                    finished = true;
+                    break;
+                }
+                let chunk: ChatCompletionChunk = match serde_json::from_str(&event.data) {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        let _ = tx.send(Err(ProviderError::InvalidResponse(err.to_string())));
+                        return;
+                    }
+                };
+                if let Some(chunk_model) = chunk.model {
+                    model = chunk_model;
+                }
+                if let Some(chunk_usage) = chunk.usage {
+                    usage = Some(TokenUsage::from(chunk_usage));
+                }
+                let Some(choice) = chunk.choices.into_iter().next() else {
+                    continue;
+                };
+                for fragment in choice.delta.tool_calls {
+                    while tool_calls.len() <= fragment.index {
+                        tool_calls.push(ToolCall {
+                            id: String::new(),
+                            name: String::new(),
+                            arguments: String::new(),
+                        });
+                    }
+                    let call = &mut tool_calls[fragment.index];
+                    if let Some(id) = fragment.id {
+                        call.id = id;
+                    }
+                    if let Some(name) = fragment.function.name {
+                        call.name.push_str(&name);
+                    }
+                    if let Some(arguments) = fragment.function.arguments {
+                        call.arguments.push_str(&arguments);
+                    }
+                }
+                if let Some(delta) = choice.delta.content {
+                    if delta.is_empty() {
+                        continue;
+                    }
+                    content.push_str(&delta);
+                    if tx.send(Ok(StreamEvent::Delta(delta))).is_err() {
+                        // receiver is gone, nobody cares about the rest of the stream
+                        return;
+                    }
+                }
+            }
+            // a connection cut mid-reply also ends the body cleanly, so only [DONE] says it's complete
+            if !finished {
+                let _ = tx.send(Err(ProviderError::Transport(
+                    "stream ended before [DONE]".into(),
+                )));
+                return;
+            }
+            let _ = tx.send(Ok(StreamEvent::Done(Completion {
+                model,
+                content,
+                tool_calls,
+                usage,
+            })));
+        });
+
+        Ok(channel_stream(rx))
+    }
+}
+
+// openai's reasoning models (o1, o3-mini, gpt-5...) take a reasoning_effort but no temperature; the rest the
+// other way around. gpt-5-chat is gpt-5 without the reasoning
+pub fn is_reasoning_model(model: &str) -> bool {
+    let o_series = model
+        .strip_prefix('o')
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+    o_series || (model.starts_with("gpt-5") && !model.contains("-chat"))
+}
+
+// what compatible servers say when the model or the way it's served can't take tool definitions, e.g.
+// ollama's "... does not support tools" or llama.cpp's "tools param requires --jinja flag". a 400 that
+// merely mentions tools, like a tool result out of place in the history, doesn't count
+fn tools_unsupported(message: &str) -> bool {
+    let message = message.to_lowercase();
+    let about_tools = message.contains("tool") || message.contains("function");
+    let unsupported = ["not support", "unsupported", "not enabled", "requires --"]
+        .iter()
+        .any(|phrase| message.contains(phrase));
+    about_tools && unsupported
+}
+
+fn request_message(msg: &ChatMessage) -> RequestMessage {
+    let role = match msg.role {
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::Tool => "tool",
+    };
+    let tool_calls: Vec<RequestToolCall> = msg
+        .tool_calls
+        .iter()
+        .map(|call| RequestToolCall {
+            id: call.id.clone(),
+            kind: "function",
+            function: FunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            },
+        })
+        .collect();
+    // an assistant turn that only calls tools has no content rather than an empty one
+    let content = if msg.content.is_empty() && !tool_calls.is_empty() {
+        None
+    } else {
+        Some(msg.content.clone())
+    };
+    RequestMessage {
+        role: role.to_string(),
+        content,
+        tool_calls,
+        tool_call_id: msg.tool_call_id.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::testing::{stand_in, Canned};
+
+    fn provider(base_url: String) -> OpenAiProvider {
+        OpenAiProvider::new(&ProviderSettings {
+            api_key: Some("test-key".to_string()),
+            model: "gpt-test".to_string(),
+            base_url,
+            supports_tools: true,
+            supports_streaming: true,
+        })
+    }
+
+    fn completion(content: &str) -> Canned {
+        Canned::json(
+            200,
+            json!({"model": "gpt-test", "choices": [{"message": {"role": "assistant", "content": content}}]}),
+        )
+    }
+
+    fn request_with_tools() -> ChatRequest {
+        let mut request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+        request.tools = vec![crate::llm::tools::run_command_tool()];
+        request
+    }
+
+    fn has_tools(body: &serde_json::Value) -> bool {
+        body["tools"]
+            .as_array()
+            .is_some_and(|tools| !tools.is_empty())
+    }
+
+    fn chunk(content: &str) -> (&'static str, serde_json::Value) {
+        (
+            "",
+            json!({"model": "gpt-test-0613", "choices": [{"index": 0, "delta": {"content": content}}]}),
+        )
+    }
+
+    async fn collect(stream: CompletionStream) -> Vec<Result<StreamEvent, ProviderError>> {
+        stream.collect().await
+    }
+
+    #[tokio::test]
+    async fn streamed_reply_ends_with_the_completion() {
+        let mut body = Canned::event_stream(&[chunk("Hel"), chunk("lo")]).body;
+        body.push_str("data: [DONE]\n\n");
+        let (base_url, requests) = stand_in(vec![Canned::sse_body(body)]).await;
+
+        let request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+        let events = collect(provider(base_url).stream(&request).await.unwrap()).await;
+
+        let deltas: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(StreamEvent::Delta(delta)) => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec!["Hel", "lo"]);
+        match events.last() {
+            Some(Ok(StreamEvent::Done(completion))) => {
+                assert_eq!(completion.content, "Hello");
+                assert_eq!(completion.model, "gpt-test-0613");
+            }
+            other => panic!("expected a completion, got {:?}", other),
+        }
+        assert_eq!(requests.lock().unwrap()[0]["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn stream_cut_off_before_done_is_an_error() {
+        let (base_url, _) = stand_in(vec![Canned::event_stream(&[chunk("Hel")])]).await;
+
+        let request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+        let events = collect(provider(base_url).stream(&request).await.unwrap()).await;
+
+        assert!(matches!(events[0], Ok(StreamEvent::Delta(_))));
+        assert!(
+            matches!(events.last(), Some(Err(ProviderError::Transport(_)))),
+            "{:?}",
+            events
+        );
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Ok(StreamEvent::Done(_)))));
+    }
+
+    #[tokio::test]
+    async fn server_without_tool_support_gets_the_request_again_without_tools() {
+        let (base_url, requests) = stand_in(vec![
+            Canned::json(
+                400,
+                json!({"error": {"message": "registry.ollama.ai/library/gemma:2b does not support tools"}}),
+            ),
+            completion("no tools here"),
+            completion("again"),
+        ])
+        .await;
+        let provider = provider(base_url);
+
+        let completion = provider.complete(&request_with_tools()).await.unwrap();
+        assert_eq!(completion.content, "no tools here");
+        // only that request went without, the next one offers them again
+        provider.complete(&request_with_tools()).await.unwrap();
+        assert!(provider.model_info().supports_tools);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests.iter().map(has_tools).collect::<Vec<_>>(),
+            vec![true, false, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn other_rejections_that_mention_tools_dont_drop_them() {
+        let (base_url, requests) = stand_in(vec![Canned::json(
+            400,
+            json!({"error": {"message": "messages with role 'tool' must be a response to a preceding message with 'tool_calls'"}}),
+        )])
+        .await;
+        let provider = provider(base_url);
+
+        let err = provider.complete(&request_with_tools()).await.unwrap_err();
+        assert!(
+            matches!(err, ProviderError::Rejected { status: 400, .. }),
+            "{:?}",
+            err
+        );
+        assert!(provider.model_info().supports_tools);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tells_openai_reasoning_models_apart() {
+        for model in ["o1", "o3-mini", "o4-mini-2025-04-16", "gpt-5", "gpt-5-mini"] {
+            assert!(is_reasoning_model(model), "{}", model);
+        }
+        for model in [
+            "gpt-4o",
+            "gpt-4.1-mini",
+            "gpt-5-chat-latest",
+            "omni-moderation-latest",
+        ] {
+            assert!(!is_reasoning_model(model), "{}", model);
+        }
+    }
+
+    #[test]
+    fn reasoning_effort_only_goes_to_openai_models_that_reason() {
+        let official = OpenAiProvider::new(&ProviderSettings {
+            api_key: Some("test-key".to_string()),
+            model: "gpt-4o-mini".to_string(),
+            base_url: DEFAULT_OPENAI_BASE_URL.to_string(),
+            supports_tools: true,
+            supports_streaming: true,
+        });
+        let mut request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+        request.sampling.reasoning_effort = Some(crate::llm::provider::ReasoningEffort::Low);
+        assert!(official
+            .build_body(&request, false, false)
+            .reasoning_effort
+            .is_none());
+
+        request.model = Some("o4-mini".to_string());
+        assert!(official
+            .build_body(&request, false, false)
+            .reasoning_effort
+            .is_some());
+        // a compatible server gets what it's asked for
+        request.model = None;
+        let local = provider("http://localhost:11434/v1".to_string());
+        assert!(local
+            .build_body(&request, false, false)
+            .reasoning_effort
+            .is_some());
+    }
+
+    #[test]
+    fn tells_unsupported_tools_from_other_tool_errors() {
+        assert!(tools_unsupported("tools param requires --jinja flag"));
+        assert!(tools_unsupported(
+            "\"auto\" tool choice requires --enable-auto-tool-choice and --tool-call-parser to be set"
+        ));
+        assert!(tools_unsupported(
+            "Function calling is not supported by this model"
+        ));
+        assert!(!tools_unsupported(
+            "Invalid 'tools[0].function.name': string too long"
+        ));
+        assert!(!tools_unsupported("context length exceeded"));
+    }
+
+    #[tokio::test]
+    async fn server_that_doesnt_stream_gets_full_completions_from_then_on() {
+        let (base_url, requests) =
+            stand_in(vec![completion("all at once"), completion("again")]).await;
+        let provider = provider(base_url);
+        let request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+
+        let events = collect(provider.stream(&request).await.unwrap()).await;
+        match events.last() {
+            Some(Ok(StreamEvent::Done(completion))) => {
+                assert_eq!(completion.content, "all at once")
+            }
+            other => panic!("expected a completion, got {:?}", other),
+        }
+        assert!(!provider.model_info().supports_streaming);
+
+        collect(provider.stream(&request).await.unwrap()).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0]["stream"], true);
+        assert_eq!(requests[1]["stream"], false);
+    }
+
+    #[tokio::test]
+    async fn custom_base_url_works_without_a_key() {
+        let (base_url, _) = stand_in(vec![completion("local")]).await;
+        let provider = OpenAiProvider::new(&ProviderSettings {
+            api_key: None,
+            model: "llama".to_string(),
+            base_url: format!("{}/", base_url),
+            supports_tools: true,
+            supports_streaming: false,
+        });
+        let request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+        assert_eq!(provider.complete(&request).await.unwrap().content, "local");
+    }
+
+    #[tokio::test]
+    async fn openai_itself_needs_a_key() {
+        let provider = OpenAiProvider::new(&ProviderSettings {
+            api_key: None,
+            model: "gpt-test".to_string(),
+            base_url: DEFAULT_OPENAI_BASE_URL.to_string(),
+            supports_tools: true,
+            supports_streaming: true,
+        });
+        let request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+        let err = provider.complete(&request).await.unwrap_err();
+        assert!(matches!(err, ProviderError::Config(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn probe_finds_the_model_and_what_it_can_do() {
+        let (base_url, _) = stand_in(vec![Canned::json(
+            200,
+            json!({"data": [
+                {"id": "other"},
+                {"id": "gpt-test", "capabilities": ["completion"]},
+            ]}),
+        )])
+        .await;
+        let info = provider(base_url).probe().await.unwrap();
+        assert_eq!(info.model, "gpt-test");
+        // it advertised what it can do, and tools weren't among them
+        assert!(!info.supports_tools);
+    }
+
+    #[tokio::test]
+    async fn probe_takes_a_lone_model_whatever_its_id() {
+        let (base_url, _) = stand_in(vec![Canned::json(
+            200,
+            json!({"data": [{"id": "/models/llama-3-8b.gguf"}]}),
+        )])
+        .await;
+        let info = provider(base_url).probe().await.unwrap();
+        assert!(info.supports_tools);
+    }
+
+    #[tokio::test]
+    async fn probe_names_what_is_served_when_the_model_isnt() {
+        let (base_url, _) = stand_in(vec![Canned::json(
+            200,
+            json!({"data": [{"id": "llama"}, {"id": "qwen"}]}),
+        )])
+        .await;
+        match provider(base_url).probe().await {
+            Err(ProviderError::Config(message)) => {
+                assert!(message.contains("llama, qwen"), "{}", message)
+            }
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,431 @@
+// provider-agnostic llm types; chat handlers only talk to the LlmProvider trait
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    // raw json, exactly as the model produced it; may not parse
+    pub arguments: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    // json schema for the tool's arguments
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    // set on assistant messages that requested tools
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    // set on tool messages, pointing back at the call they answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::assistant_with_tool_calls(content, Vec::new())
+    }
+
+    pub fn assistant_with_tool_calls(
+        content: impl Into<String>,
+        tool_calls: Vec<ToolCall>,
+    ) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: content.into(),
+            tool_calls,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Tool,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: Some(tool_call_id.into()),
+        }
+    }
+}
+
+// how hard a reasoning model should think before answering
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Minimal,
+    Low,
+    Medium,
+    High,
+}
+
+// None everywhere means "whatever the provider defaults to"
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct SamplingParams {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    // caps the length of the reply, not the context
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ChatRequest {
+    // None means "use the provider's default model"
+    pub model: Option<String>,
+    pub system_prompt: String,
+    pub messages: Vec<ChatMessage>,
+    pub tools: Vec<ToolSpec>,
+    pub sampling: SamplingParams,
+    // asks for a json reply matching the schema; providers that can't enforce it leave it to the prompt
+    pub response_schema: Option<ResponseSchema>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ResponseSchema {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+impl ChatRequest {
+    pub fn new(system_prompt: impl Into<String>, messages: Vec<ChatMessage>) -> Self {
+        Self {
+            model: None,
+            system_prompt: system_prompt.into(),
+            messages,
+            tools: Vec::new(),
+            sampling: SamplingParams::default(),
+            response_schema: None,
+        }
+    }
+
+    pub fn with_tools(mut self, tools: Vec<ToolSpec>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_model(mut self, model: Option<String>) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: SamplingParams) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn with_response_schema(mut self, schema: ResponseSchema) -> Self {
+        self.response_schema = Some(schema);
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Completion {
+    pub model: String,
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    // None when the provider didn't report any (mock, some local servers)
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // the part of prompt_tokens served from the provider's prompt cache, billed at a discount
+    pub cached_prompt_tokens: u64,
+    // the part of prompt_tokens written to the prompt cache, which anthropic bills above the input rate
+    #[serde(default)]
+    pub cache_write_prompt_tokens: u64,
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cached_prompt_tokens += other.cached_prompt_tokens;
+        self.cache_write_prompt_tokens += other.cache_write_prompt_tokens;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ModelInfo {
+    pub provider: String,
+    pub model: String,
+    pub supports_tools: bool,
+    pub supports_streaming: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    Delta(String),
+    Done(Completion),
+}
+
+pub type CompletionStream = BoxStream<'static, Result<StreamEvent, ProviderError>>;
+
+// the whole completion as a single delta, for providers (or models) that can't stream
+pub fn single_completion_stream(completion: Completion) -> CompletionStream {
+    let mut events = Vec::new();
+    if !completion.content.is_empty() {
+        events.push(Ok(StreamEvent::Delta(completion.content.clone())));
+    }
+    events.push(Ok(StreamEvent::Done(completion)));
+    stream::iter(events).boxed()
+}
+
+// lets providers produce stream events from a spawned task
+pub fn channel_stream(
+    rx: mpsc::UnboundedReceiver<Result<StreamEvent, ProviderError>>,
+) -> CompletionStream {
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+    .boxed()
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    // the provider is misconfigured (e.g. a missing api key)
+    Config(String),
+    // the provider didn't accept our credentials (401/403); waiting won't help, fixing the key will
+    Auth(String),
+    // too many requests or tokens (429); retry_after is what the provider asked us to wait
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    // the provider is down or overloaded (5xx, anthropic's 529)
+    Unavailable {
+        status: u16,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    // the account is out of credits or over its quota; waiting won't help, another provider might
+    QuotaExhausted(String),
+    // the provider refused the request itself (other 4xx); sending it again won't change anything
+    Rejected {
+        status: u16,
+        message: String,
+    },
+    // the request never got a usable http response
+    Transport(String),
+    // the provider answered, but not with something we understand
+    InvalidResponse(String),
+}
+
+impl ProviderError {
+    // classifies a non-2xx response by its status, keeping the body as the message
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status().as_u16();
+        let retry_after = retry_after(response.headers());
+        let message = response.text().await.unwrap_or_default();
+        match status {
+            401 | 403 => ProviderError::Auth(message),
+            // openai reports an exhausted quota as a 429, anthropic an empty credit balance as a 400
+            429 | 400 if is_quota_message(&message) => ProviderError::QuotaExhausted(message),
+            429 => ProviderError::RateLimited {
+                message,
+                retry_after,
+            },
+            500..=599 => ProviderError::Unavailable {
+                status,
+                message,
+                retry_after,
+            },
+            _ => ProviderError::Rejected { status, message },
+        }
+    }
+
+    // whether the same request has a chance of going through later
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ProviderError::RateLimited { .. }
+                | ProviderError::Unavailable { .. }
+                | ProviderError::Transport(_)
+        )
+    }
+
+    // whether a different provider or model could answer where this one couldn't
+    pub fn should_fall_back(&self) -> bool {
+        self.is_retryable() || matches!(self, ProviderError::QuotaExhausted(_))
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ProviderError::RateLimited { retry_after, .. }
+            | ProviderError::Unavailable { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+fn is_quota_message(message: &str) -> bool {
+    message.contains("insufficient_quota") || message.contains("credit balance is too low")
+}
+
+// Retry-After is either a number of seconds or an http date
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        // "inf" parses too, and would overflow a Duration
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Config(msg) => write!(f, "provider config error: {}", msg),
+            ProviderError::Auth(msg) => write!(f, "provider rejected the api key: {}", msg),
+            ProviderError::RateLimited {
+                message,
+                retry_after: Some(wait),
+            } => write!(
+                f,
+                "provider rate limit hit, retry in {}s: {}",
+                wait.as_secs().max(1),
+                message
+            ),
+            ProviderError::RateLimited { message, .. } => {
+                write!(f, "provider rate limit hit: {}", message)
+            }
+            ProviderError::Unavailable {
+                status, message, ..
+            } => write!(f, "provider unavailable ({}): {}", status, message),
+            ProviderError::QuotaExhausted(msg) => write!(f, "provider quota exhausted: {}", msg),
+            ProviderError::Rejected { status, message } => {
+                write!(f, "provider rejected the request ({}): {}", status, message)
+            }
+            ProviderError::Transport(msg) => write!(f, "provider transport error: {}", msg),
+            ProviderError::InvalidResponse(msg) => write!(f, "invalid provider response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            ProviderError::InvalidResponse(err.to_string())
+        } else {
+            ProviderError::Transport(err.to_string())
+        }
+    }
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync + fmt::Debug {
+    fn model_info(&self) -> ModelInfo;
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError>;
+
+    // providers without native streaming emit the whole completion as a single delta
+    async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+        Ok(single_completion_stream(self.complete(request).await?))
+    }
+
+    // checks that the provider is reachable and serves the configured model, refining model_info
+    async fn probe(&self) -> Result<ModelInfo, ProviderError> {
+        Ok(self.model_info())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(retry_after(&headers("7")), Some(Duration::from_secs(7)));
+        assert_eq!(
+            retry_after(&headers(" 0.5 ")),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(retry_after(&headers("0")), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_as_an_http_date() {
+        let at = chrono::Utc::now() + chrono::Duration::seconds(30);
+        let wait = retry_after(&headers(&at.to_rfc2822())).unwrap();
+        assert!(wait > Duration::from_secs(28) && wait <= Duration::from_secs(30));
+        // a date that's already passed gives no hint
+        let past = chrono::Utc::now() - chrono::Duration::seconds(30);
+        assert_eq!(retry_after(&headers(&past.to_rfc2822())), None);
+    }
+
+    #[test]
+    fn unusable_retry_after_is_ignored() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        for value in ["-1", "soon", "NaN", "inf", "1e300"] {
+            assert_eq!(retry_after(&headers(value)), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let unavailable = ProviderError::Unavailable {
+            status: 503,
+            message: String::new(),
+            retry_after: Some(Duration::from_secs(2)),
+        };
+        assert!(unavailable.is_retryable());
+        assert_eq!(unavailable.retry_after(), Some(Duration::from_secs(2)));
+        assert!(ProviderError::Transport(String::new()).is_retryable());
+        assert!(!ProviderError::Auth(String::new()).is_retryable());
+        assert!(!ProviderError::InvalidResponse(String::new()).is_retryable());
+        let quota = ProviderError::QuotaExhausted(String::new());
+        assert!(!quota.is_retryable());
+        assert!(quota.should_fall_back());
+    }
+}
@@ -0,0 +1,82 @@
+// every configured provider, so each session can pick the one it talks to
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::app_config::{CassetteMode, LlmConfig, ProviderKind};
+use crate::error::IronError;
+use crate::llm::anthropic::AnthropicProvider;
+use crate::llm::cassette::{Cassette, CassetteProvider};
+use crate::llm::mock::MockProvider;
+use crate::llm::openai::OpenAiProvider;
+use crate::llm::provider::LlmProvider;
+use crate::llm::retry::RetryingProvider;
+
+#[derive(Debug)]
+pub struct ProviderRegistry {
+    default_kind: ProviderKind,
+    providers: HashMap<ProviderKind, Arc<dyn LlmProvider>>,
+}
+
+impl ProviderRegistry {
+    pub fn new(
+        default_kind: ProviderKind,
+        providers: HashMap<ProviderKind, Arc<dyn LlmProvider>>,
+    ) -> Self {
+        Self {
+            default_kind,
+            providers,
+        }
+    }
+
+    pub fn from_config(config: &LlmConfig) -> Result<Self, IronError> {
+        let cassette = match &config.cassette {
+            Some(cassette) => Some(Arc::new(Cassette::open(cassette)?)),
+            None => None,
+        };
+
+        let mut providers: HashMap<ProviderKind, Arc<dyn LlmProvider>> = HashMap::new();
+        let mut remote = vec![
+            (
+                ProviderKind::OpenAi,
+                Arc::new(OpenAiProvider::new(&config.openai)) as Arc<dyn LlmProvider>,
+            ),
+            (
+                ProviderKind::Anthropic,
+                Arc::new(AnthropicProvider::new(&config.anthropic)),
+            ),
+        ];
+        if let Some(local) = &config.local {
+            remote.push((ProviderKind::Local, Arc::new(OpenAiProvider::new(local))));
+        }
+        for (kind, provider) in remote {
+            let provider: Arc<dyn LlmProvider> =
+                Arc::new(RetryingProvider::new(provider, config.retry));
+            // recording sits outside the retries, so only the answer that made it gets written down
+            let provider: Arc<dyn LlmProvider> = match &cassette {
+                Some(cassette) if cassette.mode() == CassetteMode::Replay => Arc::new(
+                    CassetteProvider::replaying(kind, provider.model_info(), Arc::clone(cassette)),
+                ),
+                Some(cassette) => Arc::new(CassetteProvider::recording(
+                    kind,
+                    provider,
+                    Arc::clone(cassette),
+                )),
+                None => provider,
+            };
+            providers.insert(kind, provider);
+        }
+        providers.insert(
+            ProviderKind::Mock,
+            Arc::new(MockProvider::new("mock".to_string())),
+        );
+        Ok(Self::new(config.provider, providers))
+    }
+
+    pub fn default_kind(&self) -> ProviderKind {
+        self.default_kind
+    }
+
+    pub fn get(&self, kind: ProviderKind) -> Option<Arc<dyn LlmProvider>> {
+        self.providers.get(&kind).cloned()
+    }
+}
@@ -0,0 +1,245 @@
+// retries rate limits and provider outages with exponential backoff, for any provider
+use async_trait::async_trait;
+use rand::Rng;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::app_config::RetryConfig;
+use crate::llm::provider::{
+    ChatRequest, Completion, CompletionStream, LlmProvider, ModelInfo, ProviderError,
+};
+
+#[derive(Debug)]
+pub struct RetryingProvider {
+    inner: Arc<dyn LlmProvider>,
+    config: RetryConfig,
+}
+
+impl RetryingProvider {
+    pub fn new(inner: Arc<dyn LlmProvider>, config: RetryConfig) -> Self {
+        Self { inner, config }
+    }
+
+    // how long to wait before retrying after `attempt` failed tries, or None to give up
+    fn delay(&self, attempt: u32, err: &ProviderError) -> Option<Duration> {
+        if !err.is_retryable() || attempt >= self.config.max_attempts {
+            return None;
+        }
+        let max_delay = Duration::from_millis(self.config.max_delay_ms);
+        if let Some(wait) = err.retry_after() {
+            // the provider knows best, unless it wants us to sit around for minutes
+            return (wait <= max_delay).then_some(wait);
+        }
+        // full jitter, so concurrent chats that hit the same limit don't retry in lockstep
+        let ceiling = self
+            .config
+            .base_delay_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.config.max_delay_ms);
+        Some(Duration::from_millis(
+            rand::thread_rng().gen_range(ceiling / 2..=ceiling),
+        ))
+    }
+
+    async fn with_retries<T, F, Fut>(&self, mut call: F) -> Result<T, ProviderError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match call().await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            let Some(wait) = self.delay(attempt, &err) else {
+                return Err(err);
+            };
+            eprintln!(
+                "LLM call failed (attempt {}/{}), retrying in {}ms: {}",
+                attempt,
+                self.config.max_attempts,
+                wait.as_millis(),
+                err
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for RetryingProvider {
+    fn model_info(&self) -> ModelInfo {
+        self.inner.model_info()
+    }
+
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+        self.with_retries(|| self.inner.complete(request)).await
+    }
+
+    // only opening the stream is retried; once deltas have gone out to the FE, a retry would repeat them
+    async fn stream(&self, request: &ChatRequest) -> Result<CompletionStream, ProviderError> {
+        self.with_retries(|| self.inner.stream(request)).await
+    }
+
+    async fn probe(&self) -> Result<ModelInfo, ProviderError> {
+        self.inner.probe().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::llm::mock::MockProvider;
+
+    fn retrying(inner: Arc<dyn LlmProvider>) -> RetryingProvider {
+        RetryingProvider::new(
+            inner,
+            RetryConfig {
+                max_attempts: 4,
+                base_delay_ms: 100,
+                max_delay_ms: 1_000,
+            },
+        )
+    }
+
+    fn unavailable(retry_after: Option<Duration>) -> ProviderError {
+        ProviderError::Unavailable {
+            status: 503,
+            message: "down".to_string(),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_cap() {
+        let provider = retrying(Arc::new(MockProvider::new("mock".to_string())));
+        let err = unavailable(None);
+        for (attempt, ceiling) in [(1, 100), (2, 200), (3, 400)] {
+            for _ in 0..50 {
+                let wait = provider.delay(attempt, &err).unwrap();
+                assert!(
+                    wait >= Duration::from_millis(ceiling / 2)
+                        && wait <= Duration::from_millis(ceiling),
+                    "attempt {} waited {:?}",
+                    attempt,
+                    wait
+                );
+            }
+        }
+        // far enough in, the doubling is capped instead of overflowing
+        let capped = RetryingProvider::new(
+            Arc::new(MockProvider::new("mock".to_string())),
+            RetryConfig {
+                max_attempts: 100,
+                base_delay_ms: 100,
+                max_delay_ms: 1_000,
+            },
+        );
+        for attempt in [5, 40, 99] {
+            let wait = capped.delay(attempt, &err).unwrap();
+            assert!(wait >= Duration::from_millis(500) && wait <= Duration::from_millis(1_000));
+        }
+    }
+
+    #[test]
+    fn retry_after_replaces_the_backoff() {
+        let provider = retrying(Arc::new(MockProvider::new("mock".to_string())));
+        let wait = Duration::from_millis(750);
+        assert_eq!(provider.delay(1, &unavailable(Some(wait))), Some(wait));
+        // rather than stalling the chat, a wait past the cap fails the call
+        assert_eq!(
+            provider.delay(1, &unavailable(Some(Duration::from_secs(60)))),
+            None
+        );
+    }
+
+    #[test]
+    fn gives_up_on_permanent_errors_and_after_the_last_attempt() {
+        let provider = retrying(Arc::new(MockProvider::new("mock".to_string())));
+        assert_eq!(
+            provider.delay(1, &ProviderError::Auth("bad key".into())),
+            None
+        );
+        assert!(provider.delay(3, &unavailable(None)).is_some());
+        assert_eq!(provider.delay(4, &unavailable(None)), None);
+    }
+
+    // fails with `error` until it has been called `failures` times
+    #[derive(Debug)]
+    struct Flaky {
+        failures: usize,
+        calls: AtomicUsize,
+        error: fn() -> ProviderError,
+    }
+
+    #[async_trait]
+    impl LlmProvider for Flaky {
+        fn model_info(&self) -> ModelInfo {
+            MockProvider::new("flaky".to_string()).model_info()
+        }
+
+        async fn complete(&self, request: &ChatRequest) -> Result<Completion, ProviderError> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err((self.error)());
+            }
+            MockProvider::new("flaky".to_string())
+                .complete(request)
+                .await
+        }
+    }
+
+    fn flaky(failures: usize, error: fn() -> ProviderError) -> Arc<Flaky> {
+        Arc::new(Flaky {
+            failures,
+            calls: AtomicUsize::new(0),
+            error,
+        })
+    }
+
+    fn fast(inner: Arc<Flaky>) -> RetryingProvider {
+        RetryingProvider::new(
+            inner,
+            RetryConfig {
+                max_attempts: 3,
+                base_delay_ms: 1,
+                max_delay_ms: 5,
+            },
+        )
+    }
+
+    fn request() -> ChatRequest {
+        ChatRequest::new(
+            "sys".to_string(),
+            vec![crate::llm::provider::ChatMessage::user("hi")],
+        )
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let inner = flaky(2, || ProviderError::Transport("reset".into()));
+        let completion = fast(inner.clone()).complete(&request()).await.unwrap();
+        assert_eq!(completion.content, "mock response to: hi");
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn last_error_comes_back_once_attempts_run_out() {
+        let inner = flaky(5, || ProviderError::Transport("reset".into()));
+        let err = fast(inner.clone()).complete(&request()).await.unwrap_err();
+        assert!(matches!(err, ProviderError::Transport(_)));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_not_retried() {
+        let inner = flaky(1, || ProviderError::Auth("bad key".into()));
+        let err = fast(inner.clone()).complete(&request()).await.unwrap_err();
+        assert!(matches!(err, ProviderError::Auth(_)));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,203 @@
+// minimal server-sent events parsing for streaming provider responses
+use futures_util::stream::{self, BoxStream, StreamExt};
+
+use crate::llm::provider::ProviderError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SseParser {
+    // raw bytes, since a chunk boundary can land in the middle of a utf-8 sequence
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    // returns the next complete event, if the buffer holds one
+    pub fn next_event(&mut self) -> Option<SseEvent> {
+        while let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if let Some(event) = self.dispatch() {
+                    return Some(event);
+                }
+                continue;
+            }
+            if line.starts_with(':') {
+                // comment / keep-alive
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, ""),
+            };
+            match field {
+                "event" => self.event = Some(value.to_string()),
+                "data" => self.data.push(value.to_string()),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // flushes whatever is left once the underlying stream has ended
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buffer.is_empty() {
+            self.buffer.push(b'\n');
+            if let Some(event) = self.next_event() {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = self.data.drain(..).collect::<Vec<String>>().join("\n");
+        Some(SseEvent { event, data })
+    }
+}
+
+pub fn sse_events(
+    response: reqwest::Response,
+) -> BoxStream<'static, Result<SseEvent, ProviderError>> {
+    let bytes = response.bytes_stream().boxed();
+    stream::unfold(
+        (bytes, SseParser::default(), false),
+        |(mut bytes, mut parser, finished)| async move {
+            if finished {
+                return None;
+            }
+            loop {
+                if let Some(event) = parser.next_event() {
+                    return Some((Ok(event), (bytes, parser, false)));
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => parser.push(&chunk),
+                    Some(Err(err)) => return Some((Err(err.into()), (bytes, parser, true))),
+                    None => {
+                        return parser
+                            .finish()
+                            .map(|event| (Ok(event), (bytes, parser, true)))
+                    }
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(parser: &mut SseParser) -> Vec<SseEvent> {
+        std::iter::from_fn(|| parser.next_event()).collect()
+    }
+
+    fn data(data: &str) -> SseEvent {
+        SseEvent {
+            event: None,
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn events_end_at_a_blank_line() {
+        let mut parser = SseParser::default();
+        parser.push(b"event: ping\ndata: {}\n\ndata: second\n\n");
+        assert_eq!(
+            events(&mut parser),
+            vec![
+                SseEvent {
+                    event: Some("ping".to_string()),
+                    data: "{}".to_string(),
+                },
+                data("second"),
+            ]
+        );
+    }
+
+    #[test]
+    fn event_split_across_chunks() {
+        let mut parser = SseParser::default();
+        parser.push(b"da");
+        assert_eq!(parser.next_event(), None);
+        parser.push(b"ta: hel");
+        assert_eq!(parser.next_event(), None);
+        parser.push(b"lo\n");
+        // not over until the blank line
+        assert_eq!(parser.next_event(), None);
+        parser.push(b"\n");
+        assert_eq!(parser.next_event(), Some(data("hello")));
+    }
+
+    #[test]
+    fn utf8_sequence_split_across_chunks() {
+        let text = "data: héllo\n\n".as_bytes();
+        let split = text.iter().position(|b| *b == 0xc3).unwrap() + 1;
+        let mut parser = SseParser::default();
+        parser.push(&text[..split]);
+        parser.push(&text[split..]);
+        assert_eq!(events(&mut parser), vec![data("héllo")]);
+    }
+
+    #[test]
+    fn multi_line_data_is_joined_with_newlines() {
+        let mut parser = SseParser::default();
+        parser.push(b"data: first\ndata:second\ndata\n\n");
+        assert_eq!(events(&mut parser), vec![data("first\nsecond\n")]);
+    }
+
+    #[test]
+    fn comments_and_unknown_fields_are_skipped() {
+        let mut parser = SseParser::default();
+        parser.push(b": keep-alive\n\n:another\nid: 7\nretry: 10\ndata: kept\n\n");
+        assert_eq!(events(&mut parser), vec![data("kept")]);
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let mut parser = SseParser::default();
+        parser.push(b"event: done\r\ndata: x\r\n\r\n");
+        assert_eq!(
+            events(&mut parser),
+            vec![SseEvent {
+                event: Some("done".to_string()),
+                data: "x".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn event_without_data_is_dropped_along_with_its_name() {
+        let mut parser = SseParser::default();
+        parser.push(b"event: ping\n\ndata: next\n\n");
+        assert_eq!(events(&mut parser), vec![data("next")]);
+    }
+
+    #[test]
+    fn finish_flushes_an_unterminated_event() {
+        let mut parser = SseParser::default();
+        parser.push(b"data: last");
+        assert_eq!(parser.next_event(), None);
+        assert_eq!(parser.finish(), Some(data("last")));
+        assert_eq!(parser.finish(), None);
+    }
+}
@@ -0,0 +1,219 @@
+// json-schema replies for models that can't call tools: the command comes back as a field of a json
+// object instead of a run_command call, and gets turned into one so the rest of the server can't tell
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::llm::provider::{ChatMessage, ResponseSchema, Role, ToolCall};
+use crate::llm::tools::{parse_run_command, CommandKind, RUN_COMMAND_TOOL};
+
+pub const STRUCTURED_TURN_SCHEMA: &str = "assistant_turn";
+
+// appended to the system prompt, which was written for tool calling
+pub const STRUCTURED_OUTPUT_INSTRUCTIONS: &str = "\
+You can't call tools in this conversation, so ignore any instructions about the run_command tool.
+Instead, reply with ONLY a JSON object, with no other text around it, of this form:
+{\"explanation\": string, \"command\": string or null, \"command_type\": \"read_only\" or \"modify\" or null, \"done\": boolean}
+- explanation: what you're doing and why, or your answer to the user; this is what the user sees
+- command: the exact shell command to run next, or null if no command is needed
+- command_type: \"read_only\" for commands that only read or display information, \"modify\" for anything that changes files or system state; null when command is null
+- done: true once the user's request is fully handled
+The output of each command comes back to you in the next user message.";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StructuredTurn {
+    pub explanation: String,
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub command_type: Option<CommandKind>,
+    #[serde(default)]
+    pub done: bool,
+}
+
+impl StructuredTurn {
+    // the command as a run_command call, as if the model had made it
+    pub fn tool_calls(&self) -> Vec<ToolCall> {
+        let (Some(command), Some(kind)) = (&self.command, self.command_type) else {
+            return Vec::new();
+        };
+        vec![ToolCall {
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            name: RUN_COMMAND_TOOL.to_string(),
+            arguments: json!({ "command": command, "kind": kind }).to_string(),
+        }]
+    }
+}
+
+pub fn structured_turn_schema() -> ResponseSchema {
+    ResponseSchema {
+        name: STRUCTURED_TURN_SCHEMA.to_string(),
+        // strict mode wants every property required, so optional ones are nullable instead
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "explanation": { "type": "string" },
+                "command": { "type": ["string", "null"] },
+                "command_type": { "type": ["string", "null"], "enum": ["read_only", "modify", null] },
+                "done": { "type": "boolean" }
+            },
+            "required": ["explanation", "command", "command_type", "done"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+// the error is sent back to the model, so it can fix its reply
+pub fn parse_structured_turn(content: &str) -> Result<StructuredTurn, String> {
+    let turn: StructuredTurn = serde_json::from_str(strip_code_fence(content)).map_err(|e| {
+        format!(
+            "Your reply wasn't a valid JSON object of the required form: {}",
+            e
+        )
+    })?;
+    let command = turn.command.as_deref().map(str::trim);
+    match (command, turn.command_type) {
+        (Some(""), _) => Err("command can't be empty; use null when there's nothing to run".into()),
+        (Some(_), None) => Err("command_type is required whenever command is set".into()),
+        _ => Ok(turn),
+    }
+}
+
+// some models wrap their json in ```json fences even when told not to
+fn strip_code_fence(content: &str) -> &str {
+    let trimmed = content.trim();
+    trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map_or(trimmed, str::trim)
+}
+
+// assistant turns and tool results in the history become the json replies and user messages the model
+// would have seen in structured mode; servers without tool support tend to reject tool messages anyway.
+// not idempotent, so only apply it to what's about to be sent
+pub fn flatten_tool_messages(messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+    messages
+        .into_iter()
+        .map(|msg| match msg.role {
+            Role::Assistant => {
+                let command = msg
+                    .tool_calls
+                    .iter()
+                    .find_map(|call| parse_run_command(call).ok());
+                let turn = StructuredTurn {
+                    explanation: msg.content,
+                    command_type: command.as_ref().map(|args| args.kind),
+                    command: command.map(|args| args.command),
+                    done: false,
+                };
+                // keeps the field order of the schema, which json!() wouldn't
+                ChatMessage::assistant(serde_json::to_string(&turn).unwrap_or_default())
+            }
+            Role::Tool => ChatMessage::user(format!("<command_output>\n{}", msg.content)),
+            _ => msg,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_turn_with_a_command() {
+        let turn = parse_structured_turn(
+            r#"{"explanation": "Listing files", "command": "ls -la", "command_type": "read_only", "done": false}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            turn,
+            StructuredTurn {
+                explanation: "Listing files".to_string(),
+                command: Some("ls -la".to_string()),
+                command_type: Some(CommandKind::ReadOnly),
+                done: false,
+            }
+        );
+    }
+
+    #[test]
+    fn optional_fields_can_be_null_or_left_out() {
+        let turn = parse_structured_turn(
+            r#"{"explanation": "All done", "command": null, "command_type": null, "done": true}"#,
+        )
+        .unwrap();
+        assert_eq!(turn.command, None);
+        assert!(turn.done);
+
+        let turn = parse_structured_turn(r#"{"explanation": "Hi"}"#).unwrap();
+        assert_eq!(turn.command, None);
+        assert_eq!(turn.command_type, None);
+        assert!(!turn.done);
+    }
+
+    #[test]
+    fn code_fences_are_stripped() {
+        for content in [
+            "```json\n{\"explanation\": \"fenced\"}\n```",
+            "```\n{\"explanation\": \"fenced\"}\n```",
+            "  {\"explanation\": \"fenced\"}  \n",
+        ] {
+            assert_eq!(
+                parse_structured_turn(content).unwrap().explanation,
+                "fenced",
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn prose_and_malformed_json_are_rejected() {
+        for content in [
+            "Sure! I'll list the files.",
+            r#"{"explanation": "cut off"#,
+            r#"{"command": "ls"}"#,
+            r#"{"explanation": "x", "command_type": "dangerous"}"#,
+        ] {
+            let err = parse_structured_turn(content).unwrap_err();
+            assert!(
+                err.starts_with("Your reply wasn't a valid JSON object"),
+                "{}: {}",
+                content,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn command_needs_a_type_and_some_text() {
+        assert_eq!(
+            parse_structured_turn(r#"{"explanation": "x", "command": "ls"}"#).unwrap_err(),
+            "command_type is required whenever command is set"
+        );
+        assert_eq!(
+            parse_structured_turn(
+                r#"{"explanation": "x", "command": "  ", "command_type": "read_only"}"#
+            )
+            .unwrap_err(),
+            "command can't be empty; use null when there's nothing to run"
+        );
+    }
+
+    #[test]
+    fn command_becomes_a_run_command_call() {
+        let turn = parse_structured_turn(
+            r#"{"explanation": "x", "command": "rm a", "command_type": "modify", "done": false}"#,
+        )
+        .unwrap();
+        let calls = turn.tool_calls();
+        assert_eq!(calls.len(), 1);
+        let args = parse_run_command(&calls[0]).unwrap();
+        assert_eq!(args.command, "rm a");
+        assert_eq!(args.kind, CommandKind::Modify);
+
+        let answer = parse_structured_turn(r#"{"explanation": "x"}"#).unwrap();
+        assert!(answer.tool_calls().is_empty());
+    }
+}
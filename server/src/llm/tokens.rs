@@ -0,0 +1,95 @@
+// per-model token estimates and context window sizes
+use std::fmt;
+
+use crate::llm::provider::ChatMessage;
+
+// role markers and message framing cost a few tokens on every provider
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+// what we assume for models we've never heard of (most local models)
+const FALLBACK_CONTEXT_WINDOW: usize = 8_192;
+
+pub trait TokenCounter: Send + Sync + fmt::Debug {
+    fn count(&self, text: &str) -> usize;
+
+    fn count_message(&self, message: &ChatMessage) -> usize {
+        let tool_tokens: usize = message
+            .tool_calls
+            .iter()
+            .map(|call| self.count(&call.name) + self.count(&call.arguments))
+            .sum();
+        MESSAGE_OVERHEAD_TOKENS + self.count(&message.content) + tool_tokens
+    }
+
+    fn count_messages(&self, messages: &[ChatMessage]) -> usize {
+        messages.iter().map(|msg| self.count_message(msg)).sum()
+    }
+}
+
+// no tokenizer ships for every model we talk to, so estimate from a per-family chars/token ratio.
+// ratios are on the pessimistic side so we trim a little early rather than get rejected
+#[derive(Debug, Clone, Copy)]
+pub struct CharRatioCounter {
+    pub chars_per_token: f32,
+}
+
+impl TokenCounter for CharRatioCounter {
+    fn count(&self, text: &str) -> usize {
+        (text.chars().count() as f32 / self.chars_per_token).ceil() as usize
+    }
+}
+
+pub fn counter_for_model(model: &str) -> CharRatioCounter {
+    let model = model.to_lowercase();
+    let chars_per_token = if model.starts_with("gpt-") || is_openai_reasoning_model(&model) {
+        3.8
+    } else if model.starts_with("claude") {
+        3.4
+    } else {
+        3.2
+    };
+    CharRatioCounter { chars_per_token }
+}
+
+pub fn context_window_for_model(model: &str) -> usize {
+    let model = model.to_lowercase();
+    if model.starts_with("gpt-4.1") {
+        1_047_576
+    } else if model.starts_with("gpt-5") {
+        400_000
+    } else if model.starts_with("gpt-4o") || model.starts_with("gpt-4-turbo") {
+        128_000
+    } else if is_openai_reasoning_model(&model) || model.starts_with("claude") {
+        200_000
+    } else if model.starts_with("gpt-3.5") {
+        16_385
+    } else {
+        FALLBACK_CONTEXT_WINDOW
+    }
+}
+
+fn is_openai_reasoning_model(model: &str) -> bool {
+    ["o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| model == *prefix || model.starts_with(&format!("{}-", prefix)))
+}
+
+// keeps the end of `text`, which for a transcript is the part that matters most
+pub fn tail_within_budget(text: &str, budget: usize, counter: &dyn TokenCounter) -> String {
+    if counter.count(text) <= budget {
+        return text.to_string();
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let mut start = 0;
+    let mut end = chars.len();
+    // binary search for the longest suffix that fits
+    while start < end {
+        let mid = (start + end) / 2;
+        let suffix: String = chars[mid..].iter().collect();
+        if counter.count(&suffix) <= budget {
+            end = mid;
+        } else {
+            start = mid + 1;
+        }
+    }
+    chars[start..].iter().collect()
+}
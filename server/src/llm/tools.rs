@@ -0,0 +1,194 @@
+// tools exposed to the model; run_command replaces the old COMMAND (READ-ONLY)/(MODIFY) text markers
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::llm::provider::{ToolCall, ToolSpec};
+use crate::state::app_state::CliCommandType;
+
+pub const RUN_COMMAND_TOOL: &str = "run_command";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandKind {
+    ReadOnly,
+    Modify,
+}
+
+impl From<CommandKind> for CliCommandType {
+    fn from(value: CommandKind) -> Self {
+        match value {
+            CommandKind::ReadOnly => CliCommandType::ReadOnlyCliCommand,
+            CommandKind::Modify => CliCommandType::WriteExecuteCliCommand,
+        }
+    }
+}
+
+// the schema below doesn't allow anything else, so a stray field means the model got the call wrong
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RunCommandArgs {
+    pub command: String,
+    pub kind: CommandKind,
+    #[serde(default)]
+    pub rationale: String,
+    // for commands known to take long, like a full build; capped by the server
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    // None leaves it to the session's preference
+    #[serde(default)]
+    pub tty: Option<bool>,
+}
+
+pub fn run_command_tool() -> ToolSpec {
+    ToolSpec {
+        name: RUN_COMMAND_TOOL.to_string(),
+        description: "Run a shell command in the user's terminal and get its output back. Commands run one after another in the same shell, so the working directory and exported variables carry over to the next command."
+            .to_string(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "The exact shell command to run, e.g. `ls -la src`."
+                },
+                "kind": {
+                    "type": "string",
+                    "enum": ["read_only", "modify"],
+                    "description": "read_only for commands that only read or display information (ls, cat, grep); modify for anything that changes files or system state (rm, mv, mkdir, builds)."
+                },
+                "rationale": {
+                    "type": "string",
+                    "description": "One sentence on why this command is the next step."
+                },
+                "timeout_secs": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "description": "Optional. How many seconds the command may run before it's killed; only set it for commands expected to take longer than a couple of minutes, like full builds or test suites."
+                },
+                "tty": {
+                    "type": "boolean",
+                    "description": "Optional. Whether to run the command in a terminal (TTY). Set it to true for programs that refuse to run, hide progress or drop colors without one, or that ask questions the user should answer while they run. A command run in a terminal starts in the shell's directory, but its own `cd` or `export` don't carry over."
+                }
+            },
+            "required": ["command", "kind", "rationale"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+// the error string is meant to be sent back to the model as the tool result, so it can correct itself
+pub fn parse_run_command(call: &ToolCall) -> Result<RunCommandArgs, String> {
+    if call.name != RUN_COMMAND_TOOL {
+        return Err(format!("Unknown tool: {}", call.name));
+    }
+    let args: RunCommandArgs = serde_json::from_str(&call.arguments)
+        .map_err(|e| format!("Invalid arguments for {}: {}", RUN_COMMAND_TOOL, e))?;
+    if args.command.trim().is_empty() {
+        return Err(format!("{} needs a non-empty command", RUN_COMMAND_TOOL));
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(arguments: serde_json::Value) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            name: RUN_COMMAND_TOOL.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_required_and_optional_arguments() {
+        let args = parse_run_command(&call(json!({
+            "command": "cargo test",
+            "kind": "modify",
+            "rationale": "run the tests",
+            "timeout_secs": 600,
+            "tty": true,
+        })))
+        .unwrap();
+        assert_eq!(
+            args,
+            RunCommandArgs {
+                command: "cargo test".to_string(),
+                kind: CommandKind::Modify,
+                rationale: "run the tests".to_string(),
+                timeout_secs: Some(600),
+                tty: Some(true),
+            }
+        );
+    }
+
+    #[test]
+    fn optional_arguments_can_be_left_out() {
+        let args = parse_run_command(&call(json!({"command": "ls", "kind": "read_only"}))).unwrap();
+        assert_eq!(args.kind, CommandKind::ReadOnly);
+        assert_eq!(args.rationale, "");
+        assert_eq!(args.timeout_secs, None);
+        assert_eq!(args.tty, None);
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        let mut call = call(json!({}));
+        call.arguments = r#"{"command": "ls", "kind": "read_"#.to_string();
+        let err = parse_run_command(&call).unwrap_err();
+        assert!(
+            err.starts_with("Invalid arguments for run_command"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn missing_arguments_are_named() {
+        let err = parse_run_command(&call(json!({"kind": "read_only"}))).unwrap_err();
+        assert!(err.contains("missing field `command`"), "{}", err);
+        let err = parse_run_command(&call(json!({"command": "ls"}))).unwrap_err();
+        assert!(err.contains("missing field `kind`"), "{}", err);
+    }
+
+    #[test]
+    fn extra_arguments_are_rejected() {
+        let err = parse_run_command(&call(json!({
+            "command": "ls",
+            "kind": "read_only",
+            "cwd": "/tmp",
+        })))
+        .unwrap_err();
+        assert!(err.contains("unknown field `cwd`"), "{}", err);
+    }
+
+    #[test]
+    fn wrong_types_and_values_are_rejected() {
+        assert!(parse_run_command(&call(json!({"command": ["ls"], "kind": "read_only"}))).is_err());
+        assert!(parse_run_command(&call(json!({"command": "ls", "kind": "dangerous"}))).is_err());
+        assert!(parse_run_command(&call(json!({
+            "command": "ls",
+            "kind": "read_only",
+            "timeout_secs": -1,
+        })))
+        .is_err());
+    }
+
+    #[test]
+    fn blank_command_is_rejected() {
+        let err =
+            parse_run_command(&call(json!({"command": "  ", "kind": "read_only"}))).unwrap_err();
+        assert_eq!(err, "run_command needs a non-empty command");
+    }
+
+    #[test]
+    fn other_tools_are_rejected() {
+        let mut call = call(json!({"command": "ls", "kind": "read_only"}));
+        call.name = "delete_everything".to_string();
+        assert_eq!(
+            parse_run_command(&call).unwrap_err(),
+            "Unknown tool: delete_everything"
+        );
+    }
+}
@@ -1,17 +1,64 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use uuid::Uuid;
 
+use server::config::app_config::AppConfig;
+use server::db::session::SessionStore;
 use server::http_server::start_http_server;
+use server::llm::registry::ProviderRegistry;
 use server::state::app_state::{ChatState, SharedChatState};
 use server::websocket_server::start_websocket_server;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    let chat_state: SharedChatState = Arc::new(ChatState {
-        chat_id: Uuid::new_v4(),
-        chat_context: Mutex::new(Vec::new()),
+    let config = AppConfig::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let config = Arc::new(config);
+    let providers = Arc::new(
+        ProviderRegistry::from_config(&config.llm)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?,
+    );
+    if let Some(cassette) = &config.llm.cassette {
+        println!(
+            "LLM cassette {} in {:?} mode",
+            cassette.path.display(),
+            cassette.mode
+        );
+    }
+    if let Some(default_provider) = providers.get(providers.default_kind()) {
+        // a failed probe isn't fatal, the server may just come up after we do
+        match default_provider.probe().await {
+            Ok(info) => println!(
+                "Using {} model {} (tools: {}, streaming: {})",
+                info.provider, info.model, info.supports_tools, info.supports_streaming
+            ),
+            Err(err) => eprintln!("LLM provider probe failed: {}", err),
+        }
+    }
+    for (source, route) in config.routes.routes() {
+        println!(
+            "Routing {:?} calls to provider {:?}, model {:?}",
+            source, route.provider, route.model
+        );
+    }
+    // picks up the chat the last run left off with, if it was saved
+    let saved = match config.session_file.clone().map(SessionStore::new) {
+        Some(store) => store.load().unwrap_or_else(|err| {
+            eprintln!("Starting a new chat: {}", err);
+            None
+        }),
+        None => None,
+    };
+    let chat_state: SharedChatState = Arc::new(match saved {
+        Some(saved) => {
+            println!(
+                "Resuming chat {} with profile {}",
+                saved.chat_id, saved.profile
+            );
+            ChatState::restore(saved, providers, config)
+        }
+        None => ChatState::new(Uuid::new_v4(), providers, config),
     });
 
     let chat_state_clone = Arc::clone(&chat_state);
@@ -22,6 +69,6 @@ async fn main() -> std::io::Result<()> {
         }
     });
 
-    start_http_server().await?;
+    start_http_server(chat_state).await?;
     Ok(())
 }
@@ -0,0 +1,138 @@
+// values for prompt variables, looked up every time a prompt is rendered so they follow the user around
+use chrono::Local;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::prompts::template::PromptVar;
+
+// a git that's stuck on a network filesystem or a lock shouldn't hold up the chat
+const GIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// what the variables stand for at the moment, gathered up front since git has to run for some of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment {
+    working_dir: PathBuf,
+    git_branch: Option<String>,
+    repo_root: Option<String>,
+}
+
+impl Environment {
+    // git only runs for the variables in `vars`, in `working_dir`
+    pub async fn capture(working_dir: PathBuf, vars: &[PromptVar]) -> Self {
+        let wants = |var| vars.contains(&var);
+        let (git_branch, repo_root) = tokio::join!(
+            async {
+                match wants(PromptVar::GitBranch) {
+                    true => git(&working_dir, &["rev-parse", "--abbrev-ref", "HEAD"]).await,
+                    false => None,
+                }
+            },
+            async {
+                match wants(PromptVar::ProjectName) {
+                    true => git(&working_dir, &["rev-parse", "--show-toplevel"]).await,
+                    false => None,
+                }
+            }
+        );
+        Self {
+            working_dir,
+            git_branch,
+            repo_root,
+        }
+    }
+
+    pub fn value(&self, var: PromptVar) -> String {
+        match var {
+            PromptVar::Os => format!("{} ({})", env::consts::OS, env::consts::ARCH),
+            PromptVar::Shell => shell(),
+            PromptVar::WorkingDir => self.working_dir.display().to_string(),
+            PromptVar::GitBranch => self
+                .git_branch
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+            // the repository's name when in one, otherwise the directory's
+            PromptVar::ProjectName => match &self.repo_root {
+                Some(root) => file_name(root),
+                None => file_name(&self.working_dir.display().to_string()),
+            },
+            PromptVar::Date => Local::now().format("%Y-%m-%d (%A)").to_string(),
+        }
+    }
+}
+
+fn shell() -> String {
+    env::var("SHELL")
+        .or_else(|_| env::var("COMSPEC"))
+        .map(|path| file_name(&path))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+async fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(GIT_TIMEOUT, output).await.ok()?.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(
+        || path.to_string(),
+        |name| name.to_string_lossy().to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn git_only_runs_for_the_variables_that_need_it() {
+        let here = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let environment = Environment::capture(here.clone(), &[PromptVar::WorkingDir]).await;
+        assert_eq!(environment.git_branch, None);
+        assert_eq!(environment.repo_root, None);
+        assert_eq!(
+            environment.value(PromptVar::WorkingDir),
+            here.display().to_string()
+        );
+        // without the repository's root, the project is named after the directory
+        assert_eq!(environment.value(PromptVar::ProjectName), "server");
+    }
+
+    #[tokio::test]
+    async fn outside_a_repository() {
+        let environment = Environment::capture(
+            PathBuf::from("/"),
+            &[PromptVar::GitBranch, PromptVar::ProjectName],
+        )
+        .await;
+        assert_eq!(environment.value(PromptVar::GitBranch), "none");
+        assert_eq!(environment.value(PromptVar::ProjectName), "/");
+    }
+
+    #[tokio::test]
+    async fn lookups_happen_in_the_given_directory() {
+        let here = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let environment = Environment::capture(here.clone(), &[PromptVar::ProjectName]).await;
+        // the server may be built from an export rather than a checkout
+        if let Some(root) = &environment.repo_root {
+            assert!(
+                here.starts_with(root),
+                "{} isn't above {}",
+                root,
+                here.display()
+            );
+            assert_eq!(environment.value(PromptVar::ProjectName), file_name(root));
+        }
+    }
+}
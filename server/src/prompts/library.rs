@@ -0,0 +1,187 @@
+// every prompt and profile the server uses, read and checked once at startup
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::IronError;
+use crate::prompts::environment::Environment;
+use crate::prompts::profile::{PromptProfile, DEFAULT_PROFILE};
+use crate::prompts::template::PromptTemplate;
+
+pub const DEFAULT_PROMPTS_DIR: &str = "prompts";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PromptName {
+    // the assistant's system prompt
+    System,
+    // the mock user that keeps autorun going
+    Continuation,
+    // summarizes older history once the context fills up
+    Compaction,
+    // proposes a plan before anything runs, for sessions that want one
+    Planning,
+}
+
+impl PromptName {
+    pub const ALL: [PromptName; 4] = [
+        PromptName::System,
+        PromptName::Continuation,
+        PromptName::Compaction,
+        PromptName::Planning,
+    ];
+
+    // the file the default profile uses
+    pub fn file_name(self) -> &'static str {
+        match self {
+            PromptName::System => "default_system_prompt.txt",
+            PromptName::Continuation => "continuation_system_prompt.txt",
+            PromptName::Compaction => "compaction_system_prompt.txt",
+            PromptName::Planning => "planning_system_prompt.txt",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptLibrary {
+    // keyed by file name
+    templates: HashMap<String, PromptTemplate>,
+    profiles: HashMap<String, PromptProfile>,
+    // what new sessions start with
+    default_profile: String,
+}
+
+// no prompts at all, just the default profile pointing at them; load() is what fills it in
+impl Default for PromptLibrary {
+    fn default() -> Self {
+        Self {
+            templates: HashMap::new(),
+            profiles: HashMap::from([(
+                DEFAULT_PROFILE.to_string(),
+                PromptProfile::default_profile(),
+            )]),
+            default_profile: DEFAULT_PROFILE.to_string(),
+        }
+    }
+}
+
+impl PromptLibrary {
+    // reads every prompt file in `dir` and every profile in `dir`/profiles, then checks that each
+    // profile's prompts exist. reports every problem at once, rather than one per restart
+    pub fn load(dir: &Path, default_profile: &str) -> Result<Self, IronError> {
+        let mut library = Self::default();
+        let mut problems = Vec::new();
+
+        let entries = fs::read_dir(dir).map_err(|e| {
+            IronError::Config(format!(
+                "Failed to read prompts dir {}: {}",
+                dir.display(),
+                e
+            ))
+        })?;
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_none_or(|ext| ext != "txt") {
+                continue;
+            }
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            match fs::read_to_string(&path) {
+                Ok(source) => match PromptTemplate::parse(&source) {
+                    Ok(template) => {
+                        library.templates.insert(file_name.to_string(), template);
+                    }
+                    Err(err) => problems.push(format!("{}: {}", path.display(), err)),
+                },
+                Err(err) => problems.push(format!("Failed to read {}: {}", path.display(), err)),
+            }
+        }
+
+        // a prompts dir without profiles just gets the built-in default one
+        let profiles_dir = dir.join("profiles");
+        if let Ok(entries) = fs::read_dir(&profiles_dir) {
+            for path in entries.flatten().map(|entry| entry.path()) {
+                if path.extension().is_none_or(|ext| ext != "json") {
+                    continue;
+                }
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                let profile = fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| {
+                        serde_json::from_str::<PromptProfile>(&text).map_err(|e| e.to_string())
+                    });
+                match profile {
+                    Ok(mut profile) => {
+                        profile.name = name.to_string();
+                        library.profiles.insert(profile.name.clone(), profile);
+                    }
+                    Err(err) => problems.push(format!("{}: {}", path.display(), err)),
+                }
+            }
+        }
+
+        for profile in library.profiles.values() {
+            for name in PromptName::ALL {
+                let file = profile.prompt_file(name);
+                if !library.templates.contains_key(file) {
+                    problems.push(format!(
+                        "profile {} uses {}, which isn't in {}",
+                        profile.name,
+                        file,
+                        dir.display()
+                    ));
+                }
+            }
+        }
+
+        if library.profiles.contains_key(default_profile) {
+            library.default_profile = default_profile.to_string();
+        } else {
+            problems.push(format!("default profile {} doesn't exist", default_profile));
+        }
+
+        if !problems.is_empty() {
+            problems.sort();
+            return Err(IronError::Config(format!(
+                "Invalid prompts:\n{}",
+                problems.join("\n")
+            )));
+        }
+        Ok(library)
+    }
+
+    pub fn default_profile(&self) -> &PromptProfile {
+        &self.profiles[&self.default_profile]
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&PromptProfile, IronError> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| IronError::Config(format!("No prompt profile named {}", name)))
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.profiles.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn template(
+        &self,
+        profile: &PromptProfile,
+        name: PromptName,
+    ) -> Result<&PromptTemplate, IronError> {
+        let file = profile.prompt_file(name);
+        self.templates
+            .get(file)
+            .ok_or_else(|| IronError::Config(format!("Prompt {} isn't loaded", file)))
+    }
+
+    pub fn render(
+        &self,
+        profile: &PromptProfile,
+        name: PromptName,
+        environment: &Environment,
+    ) -> Result<String, IronError> {
+        Ok(self
+            .template(profile, name)?
+            .render(|var| environment.value(var)))
+    }
+}
@@ -0,0 +1,5 @@
+// exports prompt templates, profiles and the library they're loaded into
+pub mod environment;
+pub mod library;
+pub mod profile;
+pub mod template;
@@ -0,0 +1,171 @@
+// prompt profiles: which prompts a session uses, what it may run, and the preferences it starts with
+use serde::{Deserialize, Serialize};
+
+use crate::config::app_config::ProviderKind;
+use crate::llm::fallback::FallbackTarget;
+use crate::llm::provider::ReasoningEffort;
+use crate::llm::tools::CommandKind;
+use crate::prompts::library::PromptName;
+use crate::state::app_state::UserChatPreferences;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+// read from <prompts dir>/profiles/<name>.json; anything left out falls back to the default profile's
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptProfile {
+    // the file name, not part of the json
+    #[serde(skip_deserializing)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // prompt files, relative to the prompts dir
+    #[serde(default = "default_system_prompt")]
+    pub system_prompt: String,
+    #[serde(default = "default_continuation_prompt")]
+    pub continuation_prompt: String,
+    // an empty list means the assistant can't run commands at all
+    #[serde(default = "all_command_kinds")]
+    pub allowed_command_kinds: Vec<CommandKind>,
+    // set on the session when the profile is picked; whatever the profile leaves out stays as it was
+    #[serde(default)]
+    pub preferences: ProfilePreferences,
+}
+
+// the same fields as UserChatPreferences, each of them optional
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProfilePreferences {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autorun_readonly: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autorun_all: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ProviderKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallbacks: Option<Vec<FallbackTarget>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan_first: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tty: Option<bool>,
+}
+
+impl ProfilePreferences {
+    pub fn apply_to(&self, preferences: &mut UserChatPreferences) {
+        let sampling = &mut preferences.sampling;
+        set(&mut preferences.depth, self.depth);
+        set(&mut preferences.autorun_readonly, self.autorun_readonly);
+        set(&mut preferences.autorun_all, self.autorun_all);
+        set(&mut preferences.provider, self.provider.map(Some));
+        set(&mut preferences.model, self.model.clone().map(Some));
+        set(&mut sampling.temperature, self.temperature.map(Some));
+        set(&mut sampling.max_tokens, self.max_tokens.map(Some));
+        set(
+            &mut sampling.reasoning_effort,
+            self.reasoning_effort.map(Some),
+        );
+        set(&mut preferences.fallbacks, self.fallbacks.clone());
+        set(&mut preferences.plan_first, self.plan_first);
+        set(
+            &mut preferences.command_timeout_secs,
+            self.command_timeout_secs.map(Some),
+        );
+        set(&mut preferences.tty, self.tty);
+    }
+}
+
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+impl PromptProfile {
+    pub fn default_profile() -> Self {
+        Self {
+            name: DEFAULT_PROFILE.to_string(),
+            description: "General purpose command-line assistant".to_string(),
+            system_prompt: default_system_prompt(),
+            continuation_prompt: default_continuation_prompt(),
+            allowed_command_kinds: all_command_kinds(),
+            preferences: ProfilePreferences::default(),
+        }
+    }
+
+    // the prompt file this profile uses for `name`; compaction and planning are the same for every profile
+    pub fn prompt_file(&self, name: PromptName) -> &str {
+        match name {
+            PromptName::System => &self.system_prompt,
+            PromptName::Continuation => &self.continuation_prompt,
+            PromptName::Compaction | PromptName::Planning => name.file_name(),
+        }
+    }
+
+    pub fn allows(&self, kind: CommandKind) -> bool {
+        self.allowed_command_kinds.contains(&kind)
+    }
+}
+
+fn default_system_prompt() -> String {
+    PromptName::System.file_name().to_string()
+}
+
+fn default_continuation_prompt() -> String {
+    PromptName::Continuation.file_name().to_string()
+}
+
+fn all_command_kinds() -> Vec<CommandKind> {
+    vec![CommandKind::ReadOnly, CommandKind::Modify]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn preferences_the_profile_leaves_out_stay_as_they_were() {
+        let profile: PromptProfile = serde_json::from_value(json!({
+            "preferences": {"depth": 8, "autorun_all": false, "temperature": 0.2}
+        }))
+        .unwrap();
+        let mut preferences = UserChatPreferences {
+            autorun_readonly: false,
+            autorun_all: true,
+            model: Some("picked-by-the-user".to_string()),
+            tty: true,
+            ..UserChatPreferences::default()
+        };
+
+        profile.preferences.apply_to(&mut preferences);
+
+        assert_eq!(preferences.depth, 8);
+        assert!(!preferences.autorun_all);
+        assert_eq!(preferences.sampling.temperature, Some(0.2));
+        assert!(!preferences.autorun_readonly);
+        assert_eq!(preferences.model.as_deref(), Some("picked-by-the-user"));
+        assert!(preferences.tty);
+    }
+
+    #[test]
+    fn profile_without_preferences_changes_nothing() {
+        let profile: PromptProfile = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(profile.preferences, ProfilePreferences::default());
+        assert_eq!(
+            serde_json::to_value(&profile.preferences).unwrap(),
+            json!({})
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// prompt templates: `{{ variable }}` gets replaced when the prompt is rendered, everything else is kept as is
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PromptVar {
+    Os,
+    Shell,
+    WorkingDir,
+    GitBranch,
+    ProjectName,
+    Date,
+}
+
+impl PromptVar {
+    pub const ALL: [PromptVar; 6] = [
+        PromptVar::Os,
+        PromptVar::Shell,
+        PromptVar::WorkingDir,
+        PromptVar::GitBranch,
+        PromptVar::ProjectName,
+        PromptVar::Date,
+    ];
+
+    // what templates call it
+    pub fn name(self) -> &'static str {
+        match self {
+            PromptVar::Os => "os",
+            PromptVar::Shell => "shell",
+            PromptVar::WorkingDir => "cwd",
+            PromptVar::GitBranch => "git_branch",
+            PromptVar::ProjectName => "project_name",
+            PromptVar::Date => "date",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|var| var.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Var(PromptVar),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl PromptTemplate {
+    // rejects unknown variables and unclosed braces, so a broken prompt fails at boot instead of mid-chat
+    pub fn parse(source: &str) -> Result<Self, TemplateError> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            let offset = source.len() - rest.len() + start;
+            let line = source[..offset].matches('\n').count() + 1;
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or_else(|| TemplateError {
+                line,
+                message: "unclosed {{".to_string(),
+            })?;
+            let name = after[..end].trim();
+            let var = PromptVar::parse(name).ok_or_else(|| TemplateError {
+                line,
+                message: format!(
+                    "unknown variable {{{{ {} }}}}, expected one of: {}",
+                    name,
+                    PromptVar::ALL.map(PromptVar::name).join(", ")
+                ),
+            })?;
+            segments.push(Segment::Var(var));
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn variables(&self) -> Vec<PromptVar> {
+        let mut vars = Vec::new();
+        for segment in &self.segments {
+            if let Segment::Var(var) = segment {
+                if !vars.contains(var) {
+                    vars.push(*var);
+                }
+            }
+        }
+        vars
+    }
+
+    // `value` is asked once per variable the template uses
+    pub fn render(&self, value: impl Fn(PromptVar) -> String) -> String {
+        let values: HashMap<PromptVar, String> = self
+            .variables()
+            .into_iter()
+            .map(|var| (var, value(var)))
+            .collect();
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::Var(var) => values[var].as_str(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn value(var: PromptVar) -> String {
+        format!("<{}>", var.name())
+    }
+
+    #[test]
+    fn variables_are_replaced_and_everything_else_kept() {
+        let template =
+            PromptTemplate::parse("In {{cwd}} on {{ git_branch }}, {single} braces stay.\n")
+                .unwrap();
+        assert_eq!(
+            template.render(value),
+            "In <cwd> on <git_branch>, {single} braces stay.\n"
+        );
+        assert_eq!(
+            template.variables(),
+            vec![PromptVar::WorkingDir, PromptVar::GitBranch]
+        );
+    }
+
+    #[test]
+    fn text_without_variables_is_unchanged() {
+        let template = PromptTemplate::parse("just text").unwrap();
+        assert!(template.variables().is_empty());
+        assert_eq!(template.render(value), "just text");
+        assert_eq!(PromptTemplate::parse("").unwrap().render(value), "");
+    }
+
+    #[test]
+    fn each_variable_is_looked_up_once() {
+        let template = PromptTemplate::parse("{{os}}{{date}}{{os}}").unwrap();
+        let lookups = Cell::new(0);
+        let rendered = template.render(|var| {
+            lookups.set(lookups.get() + 1);
+            value(var)
+        });
+        assert_eq!(rendered, "<os><date><os>");
+        assert_eq!(lookups.get(), 2);
+    }
+
+    #[test]
+    fn unknown_variable_is_rejected_with_its_line() {
+        let err = PromptTemplate::parse("first\nsecond {{ os }}\nthird {{ user }}").unwrap_err();
+        assert_eq!(err.line, 3);
+        assert!(
+            err.message
+                .starts_with("unknown variable {{ user }}, expected one of: os, shell"),
+            "{}",
+            err.message
+        );
+        assert!(err.to_string().starts_with("line 3: unknown variable"));
+    }
+
+    #[test]
+    fn empty_variable_is_rejected() {
+        let err = PromptTemplate::parse("{{ }}").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert!(err.message.starts_with("unknown variable"));
+    }
+
+    #[test]
+    fn unclosed_variable_is_rejected_with_its_line() {
+        let err = PromptTemplate::parse("ok {{os}}\n\nthen {{cwd").unwrap_err();
+        assert_eq!(
+            err,
+            TemplateError {
+                line: 3,
+                message: "unclosed {{".to_string(),
+            }
+        );
+        // a closing brace further down still closes it, and the name is what's in between
+        assert!(PromptTemplate::parse("{{cwd\n}}").is_ok());
+        assert!(PromptTemplate::parse("{{cwd} }").is_err());
+    }
+
+    #[test]
+    fn every_variable_parses_by_name() {
+        for var in PromptVar::ALL {
+            assert_eq!(PromptVar::parse(var.name()), Some(var));
+        }
+        assert_eq!(PromptVar::parse("CWD"), None);
+    }
+}
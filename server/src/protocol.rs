@@ -0,0 +1,190 @@
+// typed websocket frames sent from the server to the frontend
+use futures_util::stream::SplitSink;
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::error::{ErrorKind, IronError};
+use crate::exec::result::CommandResult;
+use crate::exec::runner::{KillReason, OutputStream};
+use crate::handlers::handler::{AssistantResponse, ResponseStatus};
+use crate::prompts::profile::PromptProfile;
+use crate::state::app_state::{CliCommandType, ContextMessage, UserChatPreferences};
+use crate::state::context_window::ContextReport;
+use crate::state::plan::Plan;
+use crate::state::redaction::RedactionReport;
+use crate::state::usage::{UsageSummary, UsageTotals};
+use uuid::Uuid;
+
+pub type WsWriteStream = Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FrontendFrame {
+    // an incremental chunk of the assistant's in-progress reply
+    AssistantDelta {
+        content: String,
+    },
+    // the assistant's complete reply, sent once the stream has finished
+    AssistantResponse(AssistantResponse),
+    // parts of the chat context were left out of the last request to fit the model's window
+    ContextTrimmed(ContextReport),
+    // something went wrong handling the last message; the chat is still usable
+    Error(ErrorEvent),
+    // sent after every chat action, whether or not it succeeded
+    Usage(UsageEvent),
+    // the session's preferences as they now stand; sent when the session starts and after every change
+    Preferences(UserChatPreferences),
+    // the session's prompt profile; sent when the session starts and after switching
+    Profile(ProfileEvent),
+    // secrets were masked in what was sent to the provider; sent after a chat action that masked new ones
+    SecretsRedacted(RedactionReport),
+    // a plan waiting for approval, or the progress of the one being carried out
+    Plan(Plan),
+    // a command the assistant asked for that the preferences don't let run on its own. it runs once the
+    // FE acks it with a user_ack_cmd carrying the same id, and is dropped on a user_cancel_cmd
+    CommandProposed {
+        command_id: String,
+        command: String,
+        command_type: CliCommandType,
+    },
+    // a command the assistant asked for is about to run; its output follows under the same id
+    CommandStarted {
+        command_id: String,
+        command: String,
+        // it runs in a pseudo-terminal: its output is meant for a terminal view, and it takes keystrokes
+        tty: bool,
+    },
+    // output of a running command as it's printed, a line or so at a time
+    CommandOutput {
+        command_id: String,
+        stream: OutputStream,
+        content: String,
+    },
+    // the command is over and no more output will follow
+    CommandFinished(CommandFinishedEvent),
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandFinishedEvent {
+    pub command_id: String,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub killed: Option<KillReason>,
+}
+
+impl CommandFinishedEvent {
+    pub fn new(command_id: String, result: &CommandResult) -> Self {
+        Self {
+            command_id,
+            exit_code: result.exit_code,
+            signal: result.signal,
+            duration_ms: result.duration_ms,
+            killed: result.killed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProfileEvent {
+    pub active: PromptProfile,
+    // every profile the session could switch to
+    pub available: Vec<String>,
+}
+
+// frames from the frontend that change the session rather than add to the chat
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlFrame {
+    SetPreferences {
+        preferences: UserChatPreferences,
+    },
+    // also changes whichever preferences the profile sets
+    SelectProfile {
+        profile: String,
+    },
+    // starts carrying out a proposed plan; `steps` replaces its steps when the user edited them
+    ApprovePlan {
+        plan_id: Uuid,
+        #[serde(default)]
+        steps: Option<Vec<String>>,
+    },
+    RejectPlan {
+        plan_id: Uuid,
+    },
+    // the FE's terminal view changed size
+    ResizeTerminal {
+        cols: u16,
+        rows: u16,
+    },
+    // keystrokes for a command running in a terminal, e.g. "y\n" to answer a prompt
+    CommandInput {
+        command_id: String,
+        data: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum IncomingFrame {
+    Control(ControlFrame),
+    Message(ContextMessage),
+}
+
+// anything with a "type" is a control frame; everything else is a chat message, as before
+pub fn parse_frontend_frame(text: &str) -> Result<IncomingFrame, IronError> {
+    let parse = || -> Result<IncomingFrame, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+        if value.get("type").is_some() {
+            Ok(IncomingFrame::Control(serde_json::from_value(value)?))
+        } else {
+            Ok(IncomingFrame::Message(serde_json::from_value(value)?))
+        }
+    };
+    parse().map_err(|e| IronError::Protocol(format!("Couldn't parse message: {}", e)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageEvent {
+    pub action_id: Uuid,
+    // what the action that just finished cost
+    pub action: UsageTotals,
+    // everything this session has spent so far, including mock user turns
+    pub session: UsageSummary,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorEvent {
+    pub kind: ErrorKind,
+    pub status: ResponseStatus,
+    pub message: String,
+    // set when the provider said how long to wait before trying again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl From<&IronError> for ErrorEvent {
+    fn from(err: &IronError) -> Self {
+        Self {
+            kind: err.kind(),
+            status: err.status(),
+            message: err.to_string(),
+            retry_after_secs: err.retry_after().map(|wait| wait.as_secs().max(1)),
+        }
+    }
+}
+
+pub async fn send_frame(
+    write_stream: &WsWriteStream,
+    frame: &FrontendFrame,
+) -> Result<(), IronError> {
+    let text = serde_json::to_string(frame)?;
+    let mut ws = write_stream.lock().await; // Lock the write stream before using it
+    ws.send(Message::Text(text.into())).await?;
+    Ok(())
+}
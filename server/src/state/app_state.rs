@@ -1,23 +1,71 @@
 // shared state management
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::From;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
 use uuid::Uuid;
 
+use serde_json::json;
+
+use crate::config::app_config::{AppConfig, ContextConfig, ProviderKind};
+use crate::db::session::{SavedSession, SessionStore};
+use crate::error::IronError;
+use crate::exec::result::CommandResult;
+use crate::exec::runner::{RunLimits, TerminalIo, TerminalSize};
+use crate::exec::shell::ShellSession;
+use crate::llm::fallback::{FallbackProvider, FallbackTarget};
+use crate::llm::openai::is_reasoning_model;
+use crate::llm::provider::{
+    ChatMessage, ChatRequest, Completion, LlmProvider, Role, SamplingParams, ToolCall,
+};
+use crate::llm::registry::ProviderRegistry;
+use crate::llm::tools::RUN_COMMAND_TOOL;
+use crate::prompts::environment::Environment;
+use crate::prompts::library::PromptName;
+use crate::prompts::profile::PromptProfile;
+use crate::state::compaction::ConversationSummary;
+use crate::state::plan::{Plan, PlanStatus};
+use crate::state::redaction::{RedactionReport, SecretLedger};
+use crate::state::usage::{UsageLedger, UsageRecord, UsageSource, UsageSummary, UsageTotals};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContextMessage {
     pub message_type: MessageType,
     pub content: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub timestamp: Option<DateTime<Utc>>,
+    // links a cli command and its output to the tool call that proposed it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    // the whole result behind a CliOutput; `content` is how it's rendered for the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_result: Option<CommandResult>,
 }
 
+// the FE sends the whole set when changing any of them; fields it leaves out go back to their defaults
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct UserChatPreferences {
     pub depth: u16,
     pub autorun_readonly: bool,
     pub autorun_all: bool,
+    // None means the server's default provider
+    pub provider: Option<ProviderKind>,
+    // None means the provider's configured model
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+    // tried in order when the provider above is down or out of quota
+    pub fallbacks: Vec<FallbackTarget>,
+    // new requests get a plan to approve before anything runs
+    pub plan_first: bool,
+    // None means the server's default command timeout
+    pub command_timeout_secs: Option<u64>,
+    // commands run in a pseudo-terminal unless the model says otherwise
+    pub tty: bool,
 }
 
 impl Default for UserChatPreferences {
@@ -26,10 +74,95 @@ impl Default for UserChatPreferences {
             depth: 5,               // Default depth
             autorun_readonly: true, // Default readonly autorun
             autorun_all: false,     // Default rwx autorun
+            provider: None,         // Default server-configured provider
+            model: None,            // Default provider-configured model
+            sampling: SamplingParams::default(),
+            fallbacks: Vec::new(),
+            plan_first: false,
+            command_timeout_secs: None,
+            tty: false,
         }
     }
 }
 
+impl UserChatPreferences {
+    // whether a command of this kind runs as soon as the model asks for it, or waits for the user
+    pub fn autoruns(&self, command_type: CliCommandType) -> bool {
+        self.autorun_all
+            || (self.autorun_readonly && command_type == CliCommandType::ReadOnlyCliCommand)
+    }
+
+    fn validate(&self) -> Result<(), IronError> {
+        if let Some(temperature) = self.sampling.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(IronError::Config(format!(
+                    "temperature must be between 0 and 2, got {}",
+                    temperature
+                )));
+            }
+        }
+        if self.sampling.max_tokens == Some(0) {
+            return Err(IronError::Config("max_tokens must be positive".into()));
+        }
+        if self
+            .model
+            .as_deref()
+            .is_some_and(|model| model.trim().is_empty())
+        {
+            return Err(IronError::Config("model can't be empty".into()));
+        }
+        if self.command_timeout_secs == Some(0) {
+            return Err(IronError::Config(
+                "command_timeout_secs must be positive".into(),
+            ));
+        }
+        if self.fallbacks.iter().any(|target| {
+            target
+                .model
+                .as_deref()
+                .is_some_and(|model| model.trim().is_empty())
+        }) {
+            return Err(IronError::Config("fallback model can't be empty".into()));
+        }
+        Ok(())
+    }
+}
+
+// what the provider would reject anyway, caught before the chat's next call fails on it
+fn validate_sampling(
+    sampling: &SamplingParams,
+    kind: ProviderKind,
+    model: &str,
+) -> Result<(), IronError> {
+    match (kind, sampling.temperature) {
+        (ProviderKind::Anthropic, Some(temperature)) if temperature > 1.0 => {
+            return Err(IronError::Config(format!(
+                "temperature must be between 0 and 1 for Anthropic models, got {}",
+                temperature
+            )));
+        }
+        (ProviderKind::OpenAi, Some(temperature))
+            if temperature != 1.0 && is_reasoning_model(model) =>
+        {
+            return Err(IronError::Config(format!(
+                "{} is a reasoning model and only takes the default temperature; leave it unset",
+                model
+            )));
+        }
+        _ => {}
+    }
+    if kind == ProviderKind::OpenAi
+        && sampling.reasoning_effort.is_some()
+        && !is_reasoning_model(model)
+    {
+        return Err(IronError::Config(format!(
+            "{} doesn't take a reasoning effort; only reasoning models (o-series, gpt-5) do",
+            model
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum MessageType {
     UserPrompt,
@@ -62,57 +195,1008 @@ impl From<CliCommandType> for MessageType {
 pub struct ChatState {
     pub chat_id: Uuid,
     pub chat_context: Mutex<Vec<ContextMessage>>,
+    // stands in for the oldest part of chat_context when talking to the model
+    pub summary: Mutex<Option<ConversationSummary>>,
+    pub usage: Mutex<UsageLedger>,
+    // the plan the session is proposing or carrying out, if it works that way
+    pub plan: Mutex<Option<Plan>>,
+    // where the session's commands run, so the directory and environment carry over between them
+    pub shell: ShellSession,
+    // bumped to kill every command the session is running
+    pub command_cancel: watch::Sender<u64>,
+    // the FE's terminal view, which commands in a pseudo-terminal follow
+    pub terminal_size: watch::Sender<TerminalSize>,
+    // where the FE's keystrokes go, for each command running in a pseudo-terminal
+    pub command_input: Mutex<HashMap<String, mpsc::UnboundedSender<Vec<u8>>>>,
+    // commands that didn't autorun, each waiting for the user to approve it
+    pub command_approvals: Mutex<HashMap<String, oneshot::Sender<bool>>>,
+    // placeholders for the secrets masked so far, kept for the whole session so they stay the same
+    pub redactions: Mutex<SecretLedger>,
     pub user_preferences: Mutex<UserChatPreferences>,
+    // the name of the prompt profile the chat uses
+    pub profile: Mutex<String>,
+    pub providers: Arc<ProviderRegistry>,
+    pub config: Arc<AppConfig>,
 }
 
 pub type SharedChatState = Arc<ChatState>;
 
 impl ChatState {
-    pub fn new(chat_id: Uuid) -> Self {
+    pub fn new(chat_id: Uuid, providers: Arc<ProviderRegistry>, config: Arc<AppConfig>) -> Self {
+        let profile = config.prompts.default_profile();
+        let mut preferences = UserChatPreferences::default();
+        profile.preferences.apply_to(&mut preferences);
         Self {
             chat_id,
             chat_context: Mutex::new(Vec::new()),
-            user_preferences: Mutex::new(UserChatPreferences::default()),
+            summary: Mutex::new(None),
+            usage: Mutex::new(UsageLedger::default()),
+            plan: Mutex::new(None),
+            shell: ShellSession::new(chat_id),
+            command_cancel: watch::channel(0).0,
+            terminal_size: watch::channel(TerminalSize::default()).0,
+            command_input: Mutex::new(HashMap::new()),
+            command_approvals: Mutex::new(HashMap::new()),
+            redactions: Mutex::new(SecretLedger::default()),
+            user_preferences: Mutex::new(preferences),
+            profile: Mutex::new(profile.name.clone()),
+            providers,
+            config,
+        }
+    }
+
+    // a chat saved by an earlier run of the server. a profile or provider that's gone since then is
+    // logged and left at the default
+    pub fn restore(
+        saved: SavedSession,
+        providers: Arc<ProviderRegistry>,
+        config: Arc<AppConfig>,
+    ) -> Self {
+        let chat = Self::new(saved.chat_id, providers, config);
+        if let Err(err) = chat.set_profile(&saved.profile) {
+            eprintln!("Couldn't restore profile of chat {}: {}", chat.chat_id, err);
         }
+        if let Err(err) = chat.set_preferences(saved.preferences) {
+            eprintln!(
+                "Couldn't restore preferences of chat {}: {}",
+                chat.chat_id, err
+            );
+        }
+        chat
+    }
+
+    // called after every change to what SavedSession holds. a failed write is only logged, the change
+    // itself still stands for this run
+    fn save(&self) -> Result<(), IronError> {
+        let Some(path) = &self.config.session_file else {
+            return Ok(());
+        };
+        let session = SavedSession {
+            chat_id: self.chat_id,
+            profile: lock(&self.profile)?.clone(),
+            preferences: self.get_preferences()?,
+        };
+        if let Err(err) = SessionStore::new(path.clone()).save(&session) {
+            eprintln!("Couldn't save chat {}: {}", self.chat_id, err);
+        }
+        Ok(())
+    }
+
+    // the provider this session talks to, as chosen in its preferences, backed by its fallback chain
+    pub fn llm(&self) -> Result<Arc<dyn LlmProvider>, IronError> {
+        let preferences = lock(&self.user_preferences)?.clone();
+        let primary = self.provider(
+            preferences
+                .provider
+                .unwrap_or_else(|| self.providers.default_kind()),
+        )?;
+        if preferences.fallbacks.is_empty() {
+            return Ok(primary);
+        }
+        let fallbacks = preferences
+            .fallbacks
+            .into_iter()
+            .map(|target| Ok((self.provider(target.provider)?, target.model)))
+            .collect::<Result<Vec<_>, IronError>>()?;
+        Ok(Arc::new(FallbackProvider::new(primary, fallbacks)))
+    }
+
+    fn provider(&self, kind: ProviderKind) -> Result<Arc<dyn LlmProvider>, IronError> {
+        self.providers
+            .get(kind)
+            .ok_or_else(|| IronError::Config(format!("No LLM provider registered for {:?}", kind)))
+    }
+
+    pub fn get_preferences(&self) -> Result<UserChatPreferences, IronError> {
+        Ok(lock(&self.user_preferences)?.clone())
+    }
+
+    pub fn set_provider(&self, provider: ProviderKind) -> Result<(), IronError> {
+        let mut preferences = self.get_preferences()?;
+        preferences.provider = Some(provider);
+        self.set_preferences(preferences)
+    }
+
+    // takes effect from the next provider call on, including ones of an action already running
+    pub fn set_preferences(&self, new_preferences: UserChatPreferences) -> Result<(), IronError> {
+        self.store_preferences(new_preferences)?;
+        self.save()
+    }
+
+    // set_preferences without saving the session, for callers that change more before they save
+    fn store_preferences(&self, new_preferences: UserChatPreferences) -> Result<(), IronError> {
+        new_preferences.validate()?;
+        // the sampling goes to the fallbacks too, each with its own model
+        let primary = FallbackTarget {
+            provider: new_preferences
+                .provider
+                .unwrap_or_else(|| self.providers.default_kind()),
+            model: new_preferences.model.clone(),
+        };
+        for target in std::iter::once(&primary).chain(&new_preferences.fallbacks) {
+            let provider = self.provider(target.provider)?;
+            let model = match &target.model {
+                Some(model) => model.clone(),
+                None => provider.model_info().model,
+            };
+            validate_sampling(&new_preferences.sampling, target.provider, &model)?;
+        }
+        *lock(&self.user_preferences)? = new_preferences;
+        Ok(())
+    }
+
+    pub fn get_profile(&self) -> Result<PromptProfile, IronError> {
+        let name = lock(&self.profile)?.clone();
+        Ok(self.config.prompts.profile(&name)?.clone())
+    }
+
+    // switching profiles mid-chat keeps the history; the next call just gets the new prompts
+    pub fn set_profile(&self, name: &str) -> Result<PromptProfile, IronError> {
+        let profile = self.config.prompts.profile(name)?.clone();
+        let mut preferences = self.get_preferences()?;
+        profile.preferences.apply_to(&mut preferences);
+        self.store_preferences(preferences)?;
+        *lock(&self.profile)? = profile.name.clone();
+        self.save()?;
+        Ok(profile)
+    }
+
+    // the git lookups some variables need run here, before rendering, so they don't block the runtime
+    pub async fn render_prompt(&self, name: PromptName) -> Result<String, IronError> {
+        let profile = self.get_profile()?;
+        let variables = self.config.prompts.template(&profile, name)?.variables();
+        // where the session's commands run, not where the server was started
+        let environment = Environment::capture(self.shell.working_dir(), &variables).await;
+        self.config.prompts.render(&profile, name, &environment)
+    }
+
+    // the model this session's calls go to
+    pub fn model(&self) -> Result<String, IronError> {
+        let preferred = lock(&self.user_preferences)?.model.clone();
+        match preferred {
+            Some(model) => Ok(model),
+            None => Ok(self.llm()?.model_info().model),
+        }
+    }
+
+    // the session's model and sampling settings
+    pub fn apply_preferences(&self, request: ChatRequest) -> Result<ChatRequest, IronError> {
+        let preferences = lock(&self.user_preferences)?;
+        Ok(request
+            .with_model(preferences.model.clone())
+            .with_sampling(preferences.sampling))
+    }
+
+    // auxiliary calls go wherever the routing table sends them; the assistant, and anything without a
+    // route, follows the session's preferences
+    pub fn llm_for(&self, source: UsageSource) -> Result<Arc<dyn LlmProvider>, IronError> {
+        match self
+            .config
+            .routes
+            .route(source)
+            .and_then(|route| route.provider)
+        {
+            Some(kind) => self.provider(kind),
+            None => self.llm(),
+        }
+    }
+
+    pub fn model_for(&self, source: UsageSource) -> Result<String, IronError> {
+        match self.config.routes.route(source) {
+            Some(route) => match (&route.model, route.provider) {
+                (Some(model), _) => Ok(model.clone()),
+                (None, Some(_)) => Ok(self.llm_for(source)?.model_info().model),
+                (None, None) => self.model(),
+            },
+            None => self.model(),
+        }
+    }
+
+    // every provider call goes through here: the right model and sampling, and no secrets
+    pub fn prepare_request(
+        &self,
+        source: UsageSource,
+        request: ChatRequest,
+    ) -> Result<ChatRequest, IronError> {
+        let request = match self.config.routes.route(source) {
+            Some(route) => request
+                .with_model(Some(self.model_for(source)?))
+                .with_sampling(route.sampling),
+            None => self.apply_preferences(request)?,
+        };
+        self.redact_request(request)
+    }
+
+    // the chat context itself keeps the secrets, only what leaves the machine is masked
+    fn redact_request(&self, mut request: ChatRequest) -> Result<ChatRequest, IronError> {
+        if !self.config.redaction.enabled {
+            return Ok(request);
+        }
+        let rules = &self.config.redaction;
+        let mut redactions = lock(&self.redactions)?;
+        request.system_prompt = redactions.redact(rules, &request.system_prompt);
+        for message in &mut request.messages {
+            message.content = redactions.redact(rules, &message.content);
+            for call in &mut message.tool_calls {
+                call.arguments = redactions.redact_json(rules, &call.arguments);
+            }
+        }
+        Ok(request)
+    }
+
+    // the command's own timeout, else the session's, else the server's; never more than the server allows
+    pub fn command_limits(&self, requested_secs: Option<u64>) -> Result<RunLimits, IronError> {
+        let exec = &self.config.exec;
+        let secs = requested_secs
+            .or(lock(&self.user_preferences)?.command_timeout_secs)
+            .unwrap_or(exec.command_timeout_secs)
+            .clamp(1, exec.max_command_timeout_secs);
+        Ok(RunLimits {
+            timeout: Duration::from_secs(secs),
+            kill_grace: Duration::from_millis(exec.kill_grace_ms),
+        })
+    }
+
+    // the command's own choice, else the session's
+    pub fn use_tty(&self, requested: Option<bool>) -> Result<bool, IronError> {
+        Ok(requested.unwrap_or(lock(&self.user_preferences)?.tty))
+    }
+
+    // takes the FE's input for `command_id` until close_terminal
+    pub fn open_terminal(&self, command_id: &str) -> Result<TerminalIo, IronError> {
+        let (input_tx, input) = mpsc::unbounded_channel();
+        lock(&self.command_input)?.insert(command_id.to_string(), input_tx);
+        Ok(TerminalIo {
+            size: self.terminal_size.subscribe(),
+            input,
+        })
+    }
+
+    pub fn close_terminal(&self, command_id: &str) -> Result<(), IronError> {
+        lock(&self.command_input)?.remove(command_id);
+        Ok(())
+    }
+
+    pub fn send_command_input(&self, command_id: &str, data: String) -> Result<(), IronError> {
+        let inputs = lock(&self.command_input)?;
+        let input = inputs.get(command_id).ok_or_else(|| {
+            IronError::Protocol(format!(
+                "No command {} is running in a terminal",
+                command_id
+            ))
+        })?;
+        input
+            .send(data.into_bytes())
+            .map_err(|_| IronError::Protocol(format!("Command {} has finished", command_id)))
+    }
+
+    // applies to the running commands right away, and to the ones started later
+    pub fn resize_terminal(&self, size: TerminalSize) -> Result<(), IronError> {
+        if size.cols == 0 || size.rows == 0 {
+            return Err(IronError::Protocol(format!(
+                "A terminal can't be {}x{}",
+                size.cols, size.rows
+            )));
+        }
+        self.terminal_size.send_replace(size);
+        Ok(())
+    }
+
+    // resolves to true once the user approves `command_id`, and to false if the commands get cancelled first
+    pub fn await_approval(&self, command_id: &str) -> Result<oneshot::Receiver<bool>, IronError> {
+        let (approve_tx, approved) = oneshot::channel();
+        lock(&self.command_approvals)?.insert(command_id.to_string(), approve_tx);
+        Ok(approved)
+    }
+
+    pub fn approve_command(&self, command_id: &str) -> Result<(), IronError> {
+        let approve_tx = lock(&self.command_approvals)?
+            .remove(command_id)
+            .ok_or_else(|| {
+                IronError::Protocol(format!("No command {} is waiting for approval", command_id))
+            })?;
+        approve_tx
+            .send(true)
+            .map_err(|_| IronError::Protocol(format!("Command {} was dropped", command_id)))
+    }
+
+    // also turns down every command still waiting for approval
+    pub fn cancel_commands(&self) {
+        self.command_cancel
+            .send_modify(|generation| *generation += 1);
+        if let Ok(mut approvals) = self.command_approvals.lock() {
+            for (_, approve_tx) in approvals.drain() {
+                let _ = approve_tx.send(false);
+            }
+        }
+    }
+
+    pub fn get_plan(&self) -> Result<Option<Plan>, IronError> {
+        Ok(lock(&self.plan)?.clone())
+    }
+
+    // the plan being carried out, not one still waiting for approval
+    pub fn active_plan(&self) -> Result<Option<Plan>, IronError> {
+        Ok(self
+            .get_plan()?
+            .filter(|plan| plan.status == PlanStatus::Approved))
+    }
+
+    // a new request in a plan_first session gets planned, unless a plan is already underway. replying to
+    // a proposed plan instead of approving it asks for a new one
+    pub fn needs_plan(&self) -> Result<bool, IronError> {
+        Ok(lock(&self.user_preferences)?.plan_first && self.active_plan()?.is_none())
+    }
+
+    pub fn propose_plan(&self, plan: Plan) -> Result<(), IronError> {
+        *lock(&self.plan)? = Some(plan);
+        Ok(())
+    }
+
+    pub fn approve_plan(
+        &self,
+        plan_id: Uuid,
+        edited_steps: Option<Vec<String>>,
+    ) -> Result<Plan, IronError> {
+        let mut current = lock(&self.plan)?;
+        let plan = proposed_plan(&mut current, plan_id)?;
+        plan.approve(edited_steps)?;
+        Ok(plan.clone())
+    }
+
+    pub fn reject_plan(&self, plan_id: Uuid) -> Result<Plan, IronError> {
+        let mut current = lock(&self.plan)?;
+        let mut plan = proposed_plan(&mut current, plan_id)?.clone();
+        plan.status = PlanStatus::Rejected;
+        *current = None;
+        Ok(plan)
+    }
+
+    // applies the step markers of an assistant reply to the active plan; the updated plan if anything changed
+    pub fn track_plan(&self, reply: &str) -> Result<Option<Plan>, IronError> {
+        let mut current = lock(&self.plan)?;
+        let Some(plan) = current
+            .as_mut()
+            .filter(|plan| plan.status == PlanStatus::Approved)
+        else {
+            return Ok(None);
+        };
+        Ok(plan.track(reply).then(|| plan.clone()))
+    }
+
+    pub fn take_redaction_report(&self) -> Result<Option<RedactionReport>, IronError> {
+        Ok(lock(&self.redactions)?.take_report())
+    }
+
+    // the context budget has to leave room for however long a reply the session asked for
+    pub fn context_config(&self) -> Result<ContextConfig, IronError> {
+        let mut config = self.config.context.clone();
+        if let Some(max_tokens) = lock(&self.user_preferences)?.sampling.max_tokens {
+            config.reserved_output_tokens = config.reserved_output_tokens.max(max_tokens as usize);
+        }
+        Ok(config)
     }
 
     pub fn add_message_to_state(
         &self,
         message_type: MessageType,
         content: String,
-    ) -> Result<(), String> {
-        // or should it be .map_err instead of expect? how do you error handle correctly in rust?
-        let mut context = self.chat_context.lock().expect("Failed to acquire lock");
+    ) -> Result<(), IronError> {
+        let mut context = lock(&self.chat_context)?;
+        context.push(ContextMessage {
+            message_type,
+            content,
+            timestamp: Some(chrono::Utc::now()),
+            tool_call_id: None,
+            command_result: None,
+        });
+        Ok(())
+    }
+
+    pub fn add_tool_message_to_state(
+        &self,
+        message_type: MessageType,
+        content: String,
+        tool_call_id: String,
+    ) -> Result<(), IronError> {
+        let mut context = lock(&self.chat_context)?;
         context.push(ContextMessage {
             message_type,
             content,
             timestamp: Some(chrono::Utc::now()),
+            tool_call_id: Some(tool_call_id),
+            command_result: None,
         });
         Ok(())
     }
 
-    pub fn get_full_context(&self) -> Result<String, String> {
-        let context = self.chat_context.lock().map_err(|e| e.to_string())?;
-        Ok(context
-            .iter()
-            .map(|msg| {
-                format!(
-                    "[{}] {}: {}",
-                    msg.timestamp
-                        .map_or("No timestamp".to_string(), |ts| ts.to_string()),
-                    match msg.message_type {
-                        MessageType::UserPrompt => "User",
-                        MessageType::AssistantResponse => "Assistant",
-                        MessageType::ReadOnlyCliCommand => "ReadOnlyCliCommand",
-                        MessageType::WriteExecuteCliCommand => "WriteExecuteCliCommand",
-                        MessageType::CliOutput => "Output",
-                        MessageType::UserCancelCmd => "Cancel",
-                        MessageType::UserAckCmd => "Ack",
-                    },
-                    msg.content
-                )
-            })
-            .collect::<Vec<String>>()
-            .join("\n"))
+    pub fn add_command_result(
+        &self,
+        result: CommandResult,
+        tool_call_id: Option<String>,
+    ) -> Result<(), IronError> {
+        let mut context = lock(&self.chat_context)?;
+        context.push(ContextMessage {
+            message_type: MessageType::CliOutput,
+            content: result.render(),
+            timestamp: Some(chrono::Utc::now()),
+            tool_call_id,
+            command_result: Some(result),
+        });
+        Ok(())
+    }
+
+    // the chat context as provider messages: user prompts, assistant turns, and commands as tool calls
+    // answered by their outputs. earlier messages never change, so providers can cache the prefix
+    // once history has been compacted, the summary replaces the messages it covers
+    pub fn get_messages(&self) -> Result<Vec<ChatMessage>, IronError> {
+        let context = lock(&self.chat_context)?;
+        let summary = lock(&self.summary)?;
+        Ok(match summary.as_ref() {
+            Some(summary) => {
+                let mut messages = vec![summary.to_message()];
+                messages.extend(render_messages(&context[summary.covers..]));
+                messages
+            }
+            None => render_messages(&context),
+        })
+    }
+
+    // the raw history, summarized or not
+    pub fn get_context(&self) -> Result<Vec<ContextMessage>, IronError> {
+        let context = lock(&self.chat_context)?;
+        Ok(context.clone())
+    }
+
+    pub fn get_summary(&self) -> Result<Option<ConversationSummary>, IronError> {
+        let summary = lock(&self.summary)?;
+        Ok(summary.clone())
+    }
+
+    pub fn set_summary(&self, new_summary: ConversationSummary) -> Result<(), IronError> {
+        let mut summary = lock(&self.summary)?;
+        *summary = Some(new_summary);
+        Ok(())
+    }
+
+    // calls whose provider didn't report usage aren't recorded
+    pub fn record_usage(
+        &self,
+        action_id: Option<Uuid>,
+        source: UsageSource,
+        completion: &Completion,
+    ) -> Result<(), IronError> {
+        let Some(usage) = completion.usage else {
+            return Ok(());
+        };
+        lock(&self.usage)?.record(UsageRecord {
+            action_id,
+            source,
+            model: completion.model.clone(),
+            usage,
+            cost_usd: self.config.prices.cost(&completion.model, &usage),
+            timestamp: chrono::Utc::now(),
+        });
+        Ok(())
+    }
+
+    pub fn usage_summary(&self) -> Result<UsageSummary, IronError> {
+        Ok(lock(&self.usage)?.summary())
+    }
+
+    pub fn action_usage(&self, action_id: Uuid) -> Result<UsageTotals, IronError> {
+        Ok(lock(&self.usage)?.action_totals(action_id))
+    }
+
+    // a flat transcript, for callers that read the conversation from the outside (e.g. the mock user)
+    pub fn get_full_context(&self) -> Result<String, IronError> {
+        let context = lock(&self.chat_context)?;
+        Ok(transcript(&context))
+    }
+}
+
+fn proposed_plan(current: &mut Option<Plan>, plan_id: Uuid) -> Result<&mut Plan, IronError> {
+    match current {
+        Some(plan) if plan.id == plan_id && plan.status == PlanStatus::Proposed => Ok(plan),
+        _ => Err(IronError::Protocol(format!(
+            "Plan {} isn't waiting for approval",
+            plan_id
+        ))),
+    }
+}
+
+// a poisoned lock means some handler panicked mid-update; report it rather than panic here too
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, IronError> {
+    mutex
+        .lock()
+        .map_err(|e| IronError::Storage(format!("chat state lock poisoned: {}", e)))
+}
+
+pub(crate) fn transcript(context: &[ContextMessage]) -> String {
+    context
+        .iter()
+        .map(|msg| {
+            format!(
+                "[{}] {}: {}",
+                msg.timestamp
+                    .map_or("No timestamp".to_string(), |ts| ts.to_string()),
+                match msg.message_type {
+                    MessageType::UserPrompt => "User",
+                    MessageType::AssistantResponse => "Assistant",
+                    MessageType::ReadOnlyCliCommand => "ReadOnlyCliCommand",
+                    MessageType::WriteExecuteCliCommand => "WriteExecuteCliCommand",
+                    MessageType::CliOutput => "Output",
+                    MessageType::UserCancelCmd => "Cancel",
+                    MessageType::UserAckCmd => "Ack",
+                },
+                msg.content
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn render_messages(context: &[ContextMessage]) -> Vec<ChatMessage> {
+    let mut messages: Vec<ChatMessage> = Vec::new();
+    // tool calls that haven't been answered yet; every call needs a result before the next turn
+    let mut pending: Vec<String> = Vec::new();
+
+    for msg in context {
+        let answers_pending = msg.message_type == MessageType::CliOutput
+            && msg
+                .tool_call_id
+                .as_ref()
+                .is_some_and(|id| pending.contains(id));
+        let is_tool_call = matches!(
+            msg.message_type,
+            MessageType::ReadOnlyCliCommand | MessageType::WriteExecuteCliCommand
+        ) && msg.tool_call_id.is_some();
+        if !answers_pending && !is_tool_call {
+            close_pending(&mut messages, &mut pending);
+        }
+
+        match (&msg.message_type, &msg.tool_call_id) {
+            (MessageType::UserPrompt, _) => messages.push(ChatMessage::user(msg.content.clone())),
+            (MessageType::AssistantResponse, _) => {
+                messages.push(ChatMessage::assistant(msg.content.clone()))
+            }
+            (MessageType::ReadOnlyCliCommand | MessageType::WriteExecuteCliCommand, Some(id)) => {
+                let kind = if msg.message_type == MessageType::ReadOnlyCliCommand {
+                    "read_only"
+                } else {
+                    "modify"
+                };
+                let call = ToolCall {
+                    id: id.clone(),
+                    name: RUN_COMMAND_TOOL.to_string(),
+                    arguments: json!({ "command": msg.content, "kind": kind }).to_string(),
+                };
+                // the call belongs to the assistant turn that proposed it
+                match messages.last_mut() {
+                    Some(last) if last.role == Role::Assistant => last.tool_calls.push(call),
+                    _ => messages.push(ChatMessage::assistant_with_tool_calls("", vec![call])),
+                }
+                pending.push(id.clone());
+            }
+            (MessageType::CliOutput, Some(id)) if answers_pending => {
+                pending.retain(|pending_id| pending_id != id);
+                messages.push(ChatMessage::tool_result(id.clone(), msg.content.clone()));
+            }
+            // commands and outputs that didn't come from a tool call are just narrated to the model
+            (MessageType::ReadOnlyCliCommand, _) => messages.push(ChatMessage::user(format!(
+                "I ran a read-only command:\n{}",
+                msg.content
+            ))),
+            (MessageType::WriteExecuteCliCommand, _) => messages.push(ChatMessage::user(format!(
+                "I ran a command:\n{}",
+                msg.content
+            ))),
+            (MessageType::CliOutput, _) => messages.push(ChatMessage::user(format!(
+                "<command_output>\n{}",
+                msg.content
+            ))),
+            (MessageType::UserCancelCmd, _) => {
+                messages.push(ChatMessage::user("I cancelled the command."))
+            }
+            (MessageType::UserAckCmd, _) => {
+                messages.push(ChatMessage::user("I approved the command."))
+            }
+        }
+    }
+    close_pending(&mut messages, &mut pending);
+    messages
+}
+
+fn close_pending(messages: &mut Vec<ChatMessage>, pending: &mut Vec<String>) {
+    for id in pending.drain(..) {
+        messages.push(ChatMessage::tool_result(id, "(no output was recorded)"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::llm::mock::MockProvider;
+    use crate::llm::provider::ReasoningEffort;
+    use crate::testing::{self, chat_state, chat_state_with};
+
+    fn context(
+        message_type: MessageType,
+        content: &str,
+        tool_call_id: Option<&str>,
+    ) -> ContextMessage {
+        ContextMessage {
+            message_type,
+            content: content.to_string(),
+            timestamp: None,
+            tool_call_id: tool_call_id.map(str::to_string),
+            command_result: None,
+        }
+    }
+
+    fn call(id: &str, command: &str, kind: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            name: RUN_COMMAND_TOOL.to_string(),
+            arguments: json!({ "command": command, "kind": kind }).to_string(),
+        }
+    }
+
+    #[test]
+    fn tool_calls_go_with_the_turn_that_made_them_and_their_results_follow() {
+        let messages = render_messages(&[
+            context(MessageType::UserPrompt, "what's here?", None),
+            context(MessageType::AssistantResponse, "Let me look.", None),
+            context(MessageType::ReadOnlyCliCommand, "ls", Some("call_1")),
+            context(
+                MessageType::WriteExecuteCliCommand,
+                "touch x",
+                Some("call_2"),
+            ),
+            context(MessageType::CliOutput, "a b", Some("call_1")),
+            context(MessageType::CliOutput, "", Some("call_2")),
+            context(MessageType::AssistantResponse, "Done.", None),
+        ]);
+
+        assert_eq!(
+            messages,
+            vec![
+                ChatMessage::user("what's here?"),
+                ChatMessage::assistant_with_tool_calls(
+                    "Let me look.",
+                    vec![
+                        call("call_1", "ls", "read_only"),
+                        call("call_2", "touch x", "modify")
+                    ],
+                ),
+                ChatMessage::tool_result("call_1", "a b"),
+                ChatMessage::tool_result("call_2", ""),
+                ChatMessage::assistant("Done."),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_call_without_a_result_gets_one_before_the_conversation_moves_on() {
+        let messages = render_messages(&[
+            context(MessageType::ReadOnlyCliCommand, "ls", Some("call_1")),
+            context(MessageType::UserPrompt, "never mind", None),
+            context(MessageType::ReadOnlyCliCommand, "pwd", Some("call_2")),
+        ]);
+
+        assert_eq!(
+            messages,
+            vec![
+                // no assistant turn before it, so the call gets one of its own
+                ChatMessage::assistant_with_tool_calls("", vec![call("call_1", "ls", "read_only")]),
+                ChatMessage::tool_result("call_1", "(no output was recorded)"),
+                ChatMessage::user("never mind"),
+                ChatMessage::assistant_with_tool_calls(
+                    "",
+                    vec![call("call_2", "pwd", "read_only")]
+                ),
+                // the end of the history closes it too
+                ChatMessage::tool_result("call_2", "(no output was recorded)"),
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_without_a_tool_call_are_narrated() {
+        let messages = render_messages(&[
+            context(MessageType::ReadOnlyCliCommand, "ls", None),
+            context(MessageType::CliOutput, "a b", None),
+            context(MessageType::WriteExecuteCliCommand, "rm a", None),
+            context(MessageType::UserAckCmd, "", None),
+            context(MessageType::UserCancelCmd, "", None),
+            // output for a call that was never made isn't a tool result either
+            context(MessageType::CliOutput, "stray", Some("call_9")),
+        ]);
+
+        assert_eq!(
+            messages,
+            vec![
+                ChatMessage::user("I ran a read-only command:\nls"),
+                ChatMessage::user("<command_output>\na b"),
+                ChatMessage::user("I ran a command:\nrm a"),
+                ChatMessage::user("I approved the command."),
+                ChatMessage::user("I cancelled the command."),
+                ChatMessage::user("<command_output>\nstray"),
+            ]
+        );
+    }
+
+    #[test]
+    fn autorun_follows_the_command_type() {
+        let read_only = CliCommandType::ReadOnlyCliCommand;
+        let modify = CliCommandType::WriteExecuteCliCommand;
+        let preferences = |autorun_readonly, autorun_all| UserChatPreferences {
+            autorun_readonly,
+            autorun_all,
+            ..Default::default()
+        };
+
+        assert!(!preferences(false, false).autoruns(read_only));
+        assert!(!preferences(false, false).autoruns(modify));
+        assert!(preferences(true, false).autoruns(read_only));
+        assert!(!preferences(true, false).autoruns(modify));
+        assert!(preferences(false, true).autoruns(read_only));
+        assert!(preferences(false, true).autoruns(modify));
+    }
+
+    // openai and anthropic stand-ins serving the models named, openai being the default
+    fn state_with(openai_model: &str, anthropic_model: &str) -> Arc<ChatState> {
+        state_with_config(openai_model, anthropic_model, testing::config())
+    }
+
+    fn state_with_config(
+        openai_model: &str,
+        anthropic_model: &str,
+        config: AppConfig,
+    ) -> Arc<ChatState> {
+        let providers: HashMap<ProviderKind, Arc<dyn LlmProvider>> = HashMap::from([
+            (
+                ProviderKind::OpenAi,
+                Arc::new(MockProvider::new(openai_model.to_string())) as Arc<dyn LlmProvider>,
+            ),
+            (
+                ProviderKind::Anthropic,
+                Arc::new(MockProvider::new(anthropic_model.to_string())) as Arc<dyn LlmProvider>,
+            ),
+        ]);
+        Arc::new(ChatState::new(
+            Uuid::new_v4(),
+            Arc::new(ProviderRegistry::new(ProviderKind::OpenAi, providers)),
+            Arc::new(config),
+        ))
+    }
+
+    fn with_sampling(
+        temperature: Option<f32>,
+        reasoning_effort: Option<ReasoningEffort>,
+    ) -> UserChatPreferences {
+        UserChatPreferences {
+            sampling: SamplingParams {
+                temperature,
+                reasoning_effort,
+                ..SamplingParams::default()
+            },
+            ..UserChatPreferences::default()
+        }
+    }
+
+    #[test]
+    fn temperature_has_to_suit_the_provider() {
+        let state = state_with("gpt-4o-mini", "claude-sonnet-4-5");
+        state
+            .set_preferences(with_sampling(Some(1.5), None))
+            .unwrap();
+        // anthropic stops at 1, and switching to it doesn't get around that
+        assert!(matches!(
+            state.set_provider(ProviderKind::Anthropic),
+            Err(IronError::Config(_))
+        ));
+        assert_eq!(state.get_preferences().unwrap().provider, None);
+        let mut preferences = with_sampling(Some(1.5), None);
+        preferences.fallbacks = vec![FallbackTarget {
+            provider: ProviderKind::Anthropic,
+            model: None,
+        }];
+        assert!(state.set_preferences(preferences).is_err());
+
+        state
+            .set_preferences(with_sampling(Some(0.7), None))
+            .unwrap();
+        state.set_provider(ProviderKind::Anthropic).unwrap();
+        assert!(matches!(
+            state.set_preferences(with_sampling(Some(2.5), None)),
+            Err(IronError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn reasoning_settings_have_to_suit_the_model() {
+        let state = state_with("gpt-4o-mini", "claude-sonnet-4-5");
+        let effort = Some(ReasoningEffort::High);
+        assert!(matches!(
+            state.set_preferences(with_sampling(None, effort)),
+            Err(IronError::Config(_))
+        ));
+
+        let mut preferences = with_sampling(None, effort);
+        preferences.model = Some("o3-mini".to_string());
+        state.set_preferences(preferences.clone()).unwrap();
+        // reasoning models take no temperature
+        preferences.sampling.temperature = Some(0.2);
+        assert!(state.set_preferences(preferences).is_err());
+
+        // anthropic turns it into a thinking budget
+        let mut preferences = with_sampling(None, effort);
+        preferences.provider = Some(ProviderKind::Anthropic);
+        state.set_preferences(preferences).unwrap();
+    }
+
+    #[test]
+    fn each_kind_of_call_gets_its_routes_model_and_sampling() {
+        let path = std::env::temp_dir().join(format!("iron-routes-{}.json", Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"{"mock_user": {"provider": "anthropic", "temperature": 0.1},
+                "compaction": {"model": "cheap-model"}}"#,
+        )
+        .unwrap();
+        let mut config = testing::config();
+        config.routes.load(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let state = state_with_config("gpt-4o-mini", "claude-sonnet-4-5", config);
+        let mut preferences = with_sampling(Some(0.7), None);
+        preferences.model = Some("gpt-4.1".to_string());
+        state.set_preferences(preferences).unwrap();
+
+        let prepared = |source| {
+            let request = ChatRequest::new("sys".to_string(), vec![ChatMessage::user("hi")]);
+            state.prepare_request(source, request).unwrap()
+        };
+        let serving = |source| state.llm_for(source).unwrap().model_info().model;
+
+        // the assistant follows the preferences
+        let assistant = prepared(UsageSource::Assistant);
+        assert_eq!(assistant.model.as_deref(), Some("gpt-4.1"));
+        assert_eq!(assistant.sampling.temperature, Some(0.7));
+        assert_eq!(state.model_for(UsageSource::Assistant).unwrap(), "gpt-4.1");
+        assert_eq!(serving(UsageSource::Assistant), "gpt-4o-mini");
+
+        // a route with a provider and no model gets that provider's model, and only the route's sampling
+        let mock_user = prepared(UsageSource::MockUser);
+        assert_eq!(mock_user.model.as_deref(), Some("claude-sonnet-4-5"));
+        assert_eq!(mock_user.sampling.temperature, Some(0.1));
+        assert_eq!(serving(UsageSource::MockUser), "claude-sonnet-4-5");
+
+        // a route with a model and no provider stays with the session's provider
+        let compaction = prepared(UsageSource::Compaction);
+        assert_eq!(compaction.model.as_deref(), Some("cheap-model"));
+        assert_eq!(compaction.sampling.temperature, None);
+        assert_eq!(serving(UsageSource::Compaction), "gpt-4o-mini");
+    }
+
+    #[test]
+    fn switching_profiles_keeps_the_preferences_the_profile_leaves_alone() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let mut preferences = state.get_preferences().unwrap();
+        preferences.model = Some("my-model".to_string());
+        preferences.tty = true;
+        state.set_preferences(preferences).unwrap();
+
+        // rust.json sets depth and the autorun flags, nothing else
+        state.set_profile("rust").unwrap();
+
+        let preferences = state.get_preferences().unwrap();
+        assert_eq!(preferences.depth, 8);
+        assert_eq!(preferences.model.as_deref(), Some("my-model"));
+        assert!(preferences.tty);
+    }
+
+    #[test]
+    fn profile_and_preferences_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("iron-restart-test-{}", Uuid::new_v4()));
+        let config = AppConfig {
+            session_file: Some(dir.join("session.json")),
+            ..testing::config()
+        };
+        let provider: Arc<dyn LlmProvider> = Arc::new(MockProvider::new("mock-model".to_string()));
+        let before = chat_state_with(Arc::clone(&provider), config.clone());
+        before.set_profile("ops").unwrap();
+        let mut preferences = before.get_preferences().unwrap();
+        preferences.plan_first = true;
+        before.set_preferences(preferences).unwrap();
+
+        let saved = SessionStore::new(dir.join("session.json"))
+            .load()
+            .unwrap()
+            .unwrap();
+        let after = ChatState::restore(saved, Arc::clone(&before.providers), Arc::new(config));
+
+        assert_eq!(after.chat_id, before.chat_id);
+        assert_eq!(after.get_profile().unwrap().name, "ops");
+        assert!(after.get_preferences().unwrap().plan_first);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn secrets_are_masked_in_the_system_prompt_and_tool_calls_too() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let key = "sk-abcdefghijklmnopqrstuvwx";
+        let mut proposal = ChatMessage::assistant("");
+        proposal.tool_calls.push(ToolCall {
+            id: "call_1".to_string(),
+            name: RUN_COMMAND_TOOL.to_string(),
+            arguments:
+                json!({"command": format!("export OPENAI_API_KEY={}", key), "kind": "modify"})
+                    .to_string(),
+        });
+        let request = ChatRequest::new(format!("The user's key is {}", key), vec![proposal]);
+
+        let sent = state.redact_request(request).unwrap();
+
+        assert_eq!(sent.system_prompt, "The user's key is [REDACTED_API_KEY_1]");
+        let arguments: serde_json::Value =
+            serde_json::from_str(&sent.messages[0].tool_calls[0].arguments).unwrap();
+        assert_eq!(
+            arguments["command"],
+            "export OPENAI_API_KEY=[REDACTED_API_KEY_1]"
+        );
+    }
+
+    #[test]
+    fn only_the_proposed_plan_can_be_approved_or_rejected() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let plan = Plan::parse("1. a").unwrap();
+        state.propose_plan(plan.clone()).unwrap();
+
+        let err = state.approve_plan(Uuid::new_v4(), None).unwrap_err();
+        assert!(matches!(err, IronError::Protocol(_)), "{}", err);
+        assert_eq!(
+            state.approve_plan(plan.id, None).unwrap().status,
+            PlanStatus::Approved
+        );
+        // approved already, so it's no longer waiting
+        let err = state.reject_plan(plan.id).unwrap_err();
+        assert!(matches!(err, IronError::Protocol(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn prompt_shows_the_shells_directory() {
+        let state = chat_state(Arc::new(MockProvider::new("mock-model".to_string())));
+        let (_cancel_tx, cancel) = watch::channel(0);
+        let limits = state.command_limits(None).unwrap();
+        state
+            .shell
+            .run("cd /tmp", limits, cancel, None)
+            .await
+            .unwrap();
+
+        let prompt = state.render_prompt(PromptName::System).await.unwrap();
+        assert!(prompt.contains("/tmp"), "{}", prompt);
     }
 }
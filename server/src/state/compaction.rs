@@ -0,0 +1,215 @@
+// folds older chat history into a running summary once the context gets too big
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::llm::provider::{ChatMessage, ChatRequest};
+use crate::llm::tokens::{tail_within_budget, TokenCounter};
+use crate::state::app_state::{transcript, ContextMessage, MessageType};
+
+// how much of the budget stays as raw history after a compaction, so the model still sees recent turns verbatim
+const KEEP_RECENT_PERCENT: usize = 25;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationSummary {
+    pub content: String,
+    // the summary stands in for chat_context[..covers]; the raw messages stay in the chat context
+    pub covers: usize,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ConversationSummary {
+    // how the summary is shown to the model, in place of the messages it covers
+    pub fn to_message(&self) -> ChatMessage {
+        ChatMessage::user(format!(
+            "[Summary of the earlier conversation, {} messages]\n{}",
+            self.covers, self.content
+        ))
+    }
+}
+
+// decides when to compact and where to cut
+#[derive(Debug, Clone, Copy)]
+pub struct Compactor {
+    pub budget: usize,
+    pub compact_at_percent: usize,
+}
+
+impl Compactor {
+    pub fn should_compact(&self, context_tokens: usize) -> bool {
+        self.compact_at_percent > 0 && context_tokens > self.budget * self.compact_at_percent / 100
+    }
+
+    // the index the next summary should cover up to, or None if there's nothing worth folding in.
+    // the newest messages worth KEEP_RECENT_PERCENT of the budget are kept, and the cut never separates
+    // a command from its output
+    pub fn cut(
+        &self,
+        context: &[ContextMessage],
+        summarized: usize,
+        counter: &dyn TokenCounter,
+    ) -> Option<usize> {
+        let keep_tokens = self.budget * KEEP_RECENT_PERCENT / 100;
+        // the newest message is always kept, it's usually the prompt being answered
+        let mut cut = context.len().checked_sub(1)?;
+        let mut kept = counter.count(&context[cut].content);
+        while cut > summarized {
+            let tokens = counter.count(&context[cut - 1].content);
+            if kept + tokens > keep_tokens {
+                break;
+            }
+            kept += tokens;
+            cut -= 1;
+        }
+        while cut < context.len() - 1 && !starts_turn(&context[cut]) {
+            cut += 1;
+        }
+        (cut > summarized && starts_turn(&context[cut])).then_some(cut)
+    }
+}
+
+fn starts_turn(message: &ContextMessage) -> bool {
+    matches!(
+        message.message_type,
+        MessageType::UserPrompt | MessageType::AssistantResponse
+    )
+}
+
+// asks for a summary of `messages`, folding in `previous` if there is one. the transcript is cut from
+// the front if it's too big on its own, the previous summary already stands for what came before it
+pub fn summarization_request(
+    system_prompt: String,
+    previous: Option<&ConversationSummary>,
+    messages: &[ContextMessage],
+    budget: usize,
+    counter: &dyn TokenCounter,
+) -> ChatRequest {
+    let mut content = String::new();
+    let mut budget = budget;
+    if let Some(previous) = previous {
+        content.push_str(&format!(
+            "<previous_summary>\n{}\n</previous_summary>\n\n",
+            previous.content
+        ));
+        budget = budget.saturating_sub(counter.count(&content));
+    }
+    content.push_str(&format!(
+        "<conversation>\n{}\n</conversation>",
+        tail_within_budget(&transcript(messages), budget, counter)
+    ));
+    ChatRequest::new(system_prompt, vec![ChatMessage::user(content)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tokens::CharRatioCounter;
+
+    const COUNTER: CharRatioCounter = CharRatioCounter {
+        chars_per_token: 1.0,
+    };
+
+    // keeps the newest 100 tokens
+    const COMPACTOR: Compactor = Compactor {
+        budget: 400,
+        compact_at_percent: 75,
+    };
+
+    fn message(message_type: MessageType, tokens: usize) -> ContextMessage {
+        ContextMessage {
+            message_type,
+            content: "x".repeat(tokens),
+            timestamp: None,
+            tool_call_id: None,
+            command_result: None,
+        }
+    }
+
+    fn prompt(tokens: usize) -> ContextMessage {
+        message(MessageType::UserPrompt, tokens)
+    }
+
+    fn reply(tokens: usize) -> ContextMessage {
+        message(MessageType::AssistantResponse, tokens)
+    }
+
+    #[test]
+    fn compacts_past_the_threshold_unless_turned_off() {
+        assert!(!COMPACTOR.should_compact(300));
+        assert!(COMPACTOR.should_compact(301));
+        let off = Compactor {
+            compact_at_percent: 0,
+            ..COMPACTOR
+        };
+        assert!(!off.should_compact(10_000));
+    }
+
+    #[test]
+    fn keeps_the_newest_turns_within_the_recent_share() {
+        let context = vec![prompt(50), reply(50), prompt(50), reply(50), prompt(30)];
+        // 30 + 50 fit in the 100 kept tokens, the prompt before them doesn't
+        assert_eq!(COMPACTOR.cut(&context, 0, &COUNTER), Some(3));
+    }
+
+    #[test]
+    fn never_separates_a_command_from_its_output() {
+        let context = vec![
+            prompt(50),
+            reply(50),
+            message(MessageType::ReadOnlyCliCommand, 10),
+            message(MessageType::CliOutput, 40),
+            reply(20),
+            prompt(20),
+        ];
+        // the kept share would start at the command, so the cut moves on to the reply after its output
+        assert_eq!(COMPACTOR.cut(&context, 0, &COUNTER), Some(4));
+    }
+
+    #[test]
+    fn nothing_to_cut_when_the_recent_share_holds_everything_new() {
+        let context = vec![prompt(50), reply(50), prompt(20), reply(20)];
+        assert_eq!(COMPACTOR.cut(&context, 2, &COUNTER), None);
+        assert_eq!(COMPACTOR.cut(&[], 0, &COUNTER), None);
+    }
+
+    #[test]
+    fn newest_message_is_kept_even_when_it_is_over_the_share() {
+        let context = vec![prompt(50), reply(50), prompt(500)];
+        assert_eq!(COMPACTOR.cut(&context, 0, &COUNTER), Some(2));
+    }
+
+    #[test]
+    fn cut_only_moves_past_what_is_already_summarized() {
+        let context = vec![prompt(50), reply(50), prompt(50), reply(50), prompt(30)];
+        assert_eq!(COMPACTOR.cut(&context, 3, &COUNTER), None);
+        assert_eq!(COMPACTOR.cut(&context, 2, &COUNTER), Some(3));
+    }
+
+    #[test]
+    fn summarization_request_folds_in_the_previous_summary() {
+        let previous = ConversationSummary {
+            content: "they set up the repo".to_string(),
+            covers: 4,
+            created_at: Utc::now(),
+        };
+        let mut messages = vec![prompt(0), reply(0)];
+        messages[0].content = "now run the tests".to_string();
+        messages[1].content = "running cargo test".to_string();
+
+        let request = summarization_request(
+            "sys".to_string(),
+            Some(&previous),
+            &messages,
+            1_000,
+            &COUNTER,
+        );
+
+        assert_eq!(request.system_prompt, "sys");
+        let content = &request.messages[0].content;
+        assert!(content.starts_with(
+            "<previous_summary>\nthey set up the repo\n</previous_summary>\n\n<conversation>\n"
+        ));
+        assert!(content.contains("now run the tests"));
+        assert!(content.contains("running cargo test"));
+        assert!(content.ends_with("</conversation>"));
+    }
+}
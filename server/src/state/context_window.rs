@@ -0,0 +1,452 @@
+// fits the chat context into a model's token budget
+use serde::Serialize;
+use std::fmt;
+
+use crate::config::app_config::ContextConfig;
+use crate::llm::provider::{ChatMessage, Role, ToolSpec};
+use crate::llm::tokens::{context_window_for_model, counter_for_model, TokenCounter};
+
+// what the builder had to leave out; sent to the FE so users know the model isn't seeing everything
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct ContextReport {
+    pub budget: usize,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+    pub dropped_messages: usize,
+    pub truncated_messages: usize,
+    // folded into the conversation summary before the request was built
+    pub summarized_messages: usize,
+    // names of the strategies that actually elided something, in the order they ran
+    pub strategies: Vec<String>,
+}
+
+impl ContextReport {
+    pub fn elided_anything(&self) -> bool {
+        self.dropped_messages > 0 || self.truncated_messages > 0 || self.summarized_messages > 0
+    }
+}
+
+pub struct ContextWindow<'a> {
+    pub messages: Vec<ChatMessage>,
+    // parallel to `messages`; pinned messages are never dropped or truncated
+    pub pinned: Vec<bool>,
+    pub budget: usize,
+    pub counter: &'a dyn TokenCounter,
+    pub report: ContextReport,
+}
+
+impl ContextWindow<'_> {
+    pub fn tokens(&self) -> usize {
+        self.counter.count_messages(&self.messages)
+    }
+
+    pub fn fits(&self) -> bool {
+        self.tokens() <= self.budget
+    }
+}
+
+pub trait TruncationStrategy: Send + Sync + fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    // must leave the window valid: every tool call still followed by its result
+    fn apply(&self, window: &mut ContextWindow);
+}
+
+// keeps the first user prompt, which usually states what the whole session is about
+#[derive(Debug, Clone, Copy)]
+pub struct PinFirstPrompt;
+
+impl TruncationStrategy for PinFirstPrompt {
+    fn name(&self) -> &'static str {
+        "pin_first_prompt"
+    }
+
+    fn apply(&self, window: &mut ContextWindow) {
+        if let Some(index) = window
+            .messages
+            .iter()
+            .position(|msg| msg.role == Role::User)
+        {
+            window.pinned[index] = true;
+        }
+    }
+}
+
+// cuts the middle out of command outputs that are bigger than `max_tokens`
+#[derive(Debug, Clone, Copy)]
+pub struct TruncateLargeOutputs {
+    pub max_tokens: usize,
+}
+
+impl TruncationStrategy for TruncateLargeOutputs {
+    fn name(&self) -> &'static str {
+        "truncate_large_outputs"
+    }
+
+    fn apply(&self, window: &mut ContextWindow) {
+        let mut truncated = 0;
+        for (msg, pinned) in window.messages.iter_mut().zip(&window.pinned) {
+            if msg.role != Role::Tool || *pinned {
+                continue;
+            }
+            let tokens = window.counter.count(&msg.content);
+            if tokens <= self.max_tokens {
+                continue;
+            }
+            let chars: Vec<char> = msg.content.chars().collect();
+            let keep = chars.len() * self.max_tokens / tokens;
+            // the head usually says what the output is, the tail is where errors and summaries end up
+            let head = keep * 2 / 3;
+            let tail = keep - head;
+            msg.content = format!(
+                "{}\n[... {} tokens of output elided ...]\n{}",
+                chars[..head].iter().collect::<String>(),
+                tokens - self.max_tokens,
+                chars[chars.len() - tail..].iter().collect::<String>()
+            );
+            truncated += 1;
+        }
+        if truncated > 0 {
+            window.report.truncated_messages += truncated;
+            window.report.strategies.push(self.name().to_string());
+        }
+    }
+}
+
+// drops the oldest unpinned turns until the window fits; the newest turn is always kept
+#[derive(Debug, Clone, Copy)]
+pub struct DropOldest;
+
+impl TruncationStrategy for DropOldest {
+    fn name(&self) -> &'static str {
+        "drop_oldest"
+    }
+
+    fn apply(&self, window: &mut ContextWindow) {
+        // a unit is a message plus the tool results answering it, so calls and results go together
+        let mut units: Vec<(usize, usize)> = Vec::new();
+        for (index, msg) in window.messages.iter().enumerate() {
+            match units.last_mut() {
+                Some((_, end)) if msg.role == Role::Tool => *end = index + 1,
+                _ => units.push((index, index + 1)),
+            }
+        }
+        if units.len() < 2 {
+            return;
+        }
+
+        let unit_tokens = |(start, end): (usize, usize)| {
+            window.counter.count_messages(&window.messages[start..end])
+        };
+        let marker_tokens = window.counter.count_message(&omitted_marker(0));
+        let mut tokens = window.tokens() + marker_tokens;
+        let mut dropped_units = Vec::new();
+        for &unit in &units[..units.len() - 1] {
+            if tokens <= window.budget {
+                break;
+            }
+            if window.pinned[unit.0..unit.1].iter().any(|pinned| *pinned) {
+                continue;
+            }
+            tokens -= unit_tokens(unit);
+            dropped_units.push(unit);
+        }
+        if dropped_units.is_empty() {
+            return;
+        }
+
+        let dropped_messages: usize = dropped_units.iter().map(|(start, end)| end - start).sum();
+        let marker_at = dropped_units[0].0;
+        let mut messages = Vec::new();
+        let mut pinned = Vec::new();
+        for (index, (msg, is_pinned)) in window
+            .messages
+            .drain(..)
+            .zip(window.pinned.drain(..))
+            .enumerate()
+        {
+            if index == marker_at {
+                messages.push(omitted_marker(dropped_messages));
+                pinned.push(false);
+            }
+            if dropped_units
+                .iter()
+                .any(|(start, end)| (*start..*end).contains(&index))
+            {
+                continue;
+            }
+            messages.push(msg);
+            pinned.push(is_pinned);
+        }
+        window.messages = messages;
+        window.pinned = pinned;
+        window.report.dropped_messages += dropped_messages;
+        window.report.strategies.push(self.name().to_string());
+    }
+}
+
+fn omitted_marker(dropped_messages: usize) -> ChatMessage {
+    ChatMessage::user(format!(
+        "[{} earlier messages were omitted to fit the context window]",
+        dropped_messages
+    ))
+}
+
+#[derive(Debug)]
+pub struct ContextBuilder {
+    budget: usize,
+    counter: Box<dyn TokenCounter>,
+    strategies: Vec<Box<dyn TruncationStrategy>>,
+}
+
+impl ContextBuilder {
+    pub fn new(budget: usize, counter: Box<dyn TokenCounter>) -> Self {
+        Self {
+            budget,
+            counter,
+            strategies: Vec::new(),
+        }
+    }
+
+    // budget is whatever the model's window leaves after the system prompt, tools and the reply
+    pub fn for_model(
+        model: &str,
+        config: &ContextConfig,
+        system_prompt: &str,
+        tools: &[ToolSpec],
+    ) -> Self {
+        let counter = counter_for_model(model);
+        let window = config
+            .max_context_tokens
+            .unwrap_or_else(|| context_window_for_model(model));
+        let tool_tokens: usize = tools
+            .iter()
+            .map(|tool| {
+                counter.count(&tool.name)
+                    + counter.count(&tool.description)
+                    + counter.count(&tool.parameters.to_string())
+            })
+            .sum();
+        let fixed = config.reserved_output_tokens + counter.count(system_prompt) + tool_tokens;
+
+        Self::new(window.saturating_sub(fixed), Box::new(counter)).with_strategies(vec![
+            Box::new(PinFirstPrompt),
+            Box::new(TruncateLargeOutputs {
+                max_tokens: config.max_output_tokens,
+            }),
+            Box::new(DropOldest),
+        ])
+    }
+
+    pub fn with_strategies(mut self, strategies: Vec<Box<dyn TruncationStrategy>>) -> Self {
+        self.strategies = strategies;
+        self
+    }
+
+    pub fn counter(&self) -> &dyn TokenCounter {
+        self.counter.as_ref()
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // strategies run in order, and only while the window is still over budget
+    pub fn build(&self, messages: Vec<ChatMessage>) -> (Vec<ChatMessage>, ContextReport) {
+        let pinned = vec![false; messages.len()];
+        let mut window = ContextWindow {
+            messages,
+            pinned,
+            budget: self.budget,
+            counter: self.counter.as_ref(),
+            report: ContextReport {
+                budget: self.budget,
+                ..ContextReport::default()
+            },
+        };
+        window.report.tokens_before = window.tokens();
+
+        for strategy in &self.strategies {
+            if window.fits() {
+                break;
+            }
+            strategy.apply(&mut window);
+        }
+
+        window.report.tokens_after = window.tokens();
+        (window.messages, window.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::provider::ToolCall;
+    use crate::llm::tokens::CharRatioCounter;
+
+    // a token per character, plus the per-message overhead
+    fn builder(budget: usize, strategies: Vec<Box<dyn TruncationStrategy>>) -> ContextBuilder {
+        ContextBuilder::new(
+            budget,
+            Box::new(CharRatioCounter {
+                chars_per_token: 1.0,
+            }),
+        )
+        .with_strategies(strategies)
+    }
+
+    fn all_strategies(max_tokens: usize) -> Vec<Box<dyn TruncationStrategy>> {
+        vec![
+            Box::new(PinFirstPrompt),
+            Box::new(TruncateLargeOutputs { max_tokens }),
+            Box::new(DropOldest),
+        ]
+    }
+
+    fn call(id: &str) -> ChatMessage {
+        ChatMessage::assistant_with_tool_calls(
+            "",
+            vec![ToolCall {
+                id: id.to_string(),
+                name: "run_command".to_string(),
+                arguments: "{}".to_string(),
+            }],
+        )
+    }
+
+    fn contents(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|msg| msg.content.as_str()).collect()
+    }
+
+    #[test]
+    fn messages_that_fit_are_left_alone() {
+        let messages = vec![ChatMessage::user("hello"), ChatMessage::assistant("hi")];
+        let (built, report) = builder(100, all_strategies(10)).build(messages.clone());
+
+        assert_eq!(built, messages);
+        assert!(!report.elided_anything());
+        assert_eq!(report.tokens_before, 9 + 6);
+        assert_eq!(report.tokens_after, report.tokens_before);
+        assert!(report.strategies.is_empty());
+    }
+
+    #[test]
+    fn oldest_turns_go_first_and_leave_a_marker() {
+        let messages = vec![
+            ChatMessage::user("a".repeat(100)),
+            ChatMessage::assistant("b".repeat(100)),
+            ChatMessage::user("c".repeat(100)),
+            ChatMessage::assistant("d".repeat(100)),
+        ];
+        let (built, report) = builder(300, vec![Box::new(DropOldest)]).build(messages);
+
+        // each message is 104 tokens and the marker takes room too, so two have to go
+        assert_eq!(built.len(), 3);
+        assert!(built[0].content.contains("2 earlier messages were omitted"));
+        assert_eq!(
+            contents(&built[1..]),
+            vec!["c".repeat(100), "d".repeat(100)]
+        );
+        assert_eq!(report.dropped_messages, 2);
+        assert_eq!(report.strategies, vec!["drop_oldest"]);
+        assert!(report.tokens_after <= 300);
+    }
+
+    #[test]
+    fn first_prompt_is_pinned() {
+        let messages = vec![
+            ChatMessage::user("goal"),
+            ChatMessage::assistant("a".repeat(40)),
+            ChatMessage::user("b".repeat(40)),
+            ChatMessage::assistant("latest"),
+        ];
+        let (built, report) = builder(80, all_strategies(1_000)).build(messages);
+
+        assert_eq!(built.first().unwrap().content, "goal");
+        assert_eq!(built.last().unwrap().content, "latest");
+        assert!(built.iter().any(|msg| msg.content.contains("omitted")));
+        assert!(!built.iter().any(|msg| msg.content == "a".repeat(40)));
+        assert_eq!(report.strategies, vec!["drop_oldest"]);
+    }
+
+    #[test]
+    fn tool_calls_are_dropped_together_with_their_results() {
+        let messages = vec![
+            call("call_1"),
+            ChatMessage::tool_result("call_1", "x".repeat(50)),
+            ChatMessage::tool_result("call_1", "y".repeat(50)),
+            ChatMessage::user("next"),
+        ];
+        let (built, report) = builder(40, vec![Box::new(DropOldest)]).build(messages);
+
+        assert_eq!(report.dropped_messages, 3);
+        assert_eq!(built.len(), 2);
+        assert!(built.iter().all(|msg| msg.role != Role::Tool));
+        assert_eq!(built[1].content, "next");
+    }
+
+    #[test]
+    fn newest_turn_is_kept_even_over_budget() {
+        let messages = vec![ChatMessage::user("old"), ChatMessage::user("n".repeat(100))];
+        let (built, report) = builder(10, vec![Box::new(DropOldest)]).build(messages);
+
+        assert_eq!(built.last().unwrap().content, "n".repeat(100));
+        assert!(report.tokens_after > report.budget);
+    }
+
+    #[test]
+    fn large_outputs_lose_their_middle() {
+        let output = format!("{}{}{}", "h".repeat(100), "m".repeat(100), "t".repeat(100));
+        let messages = vec![
+            ChatMessage::user("u".repeat(300)),
+            call("call_1"),
+            ChatMessage::tool_result("call_1", output),
+        ];
+        let (built, report) = builder(450, all_strategies(60)).build(messages);
+
+        // only tool output is cut, the user's message stays whole
+        assert_eq!(built[0].content, "u".repeat(300));
+        let cut = &built[2].content;
+        assert!(cut.starts_with("hhh"), "{}", cut);
+        assert!(cut.ends_with("ttt"), "{}", cut);
+        assert!(
+            cut.contains("[... 240 tokens of output elided ...]"),
+            "{}",
+            cut
+        );
+        assert!(!cut.contains('m'), "{}", cut);
+        assert_eq!(report.truncated_messages, 1);
+        assert_eq!(report.dropped_messages, 0);
+        assert_eq!(report.strategies, vec!["truncate_large_outputs"]);
+        assert!(report.tokens_after <= report.budget);
+    }
+
+    #[test]
+    fn budget_leaves_room_for_the_system_prompt_tools_and_reply() {
+        let config = ContextConfig {
+            max_context_tokens: Some(10_000),
+            reserved_output_tokens: 1_000,
+            ..ContextConfig::default()
+        };
+        let counter = counter_for_model("gpt-4o");
+        let system_prompt = "s".repeat(380);
+        let tools = vec![crate::llm::tools::run_command_tool()];
+        let tool = &tools[0];
+        let tool_tokens = counter.count(&tool.name)
+            + counter.count(&tool.description)
+            + counter.count(&tool.parameters.to_string());
+
+        let builder = ContextBuilder::for_model("gpt-4o", &config, &system_prompt, &tools);
+        assert_eq!(builder.budget(), 10_000 - 1_000 - 100 - tool_tokens);
+
+        // a window smaller than what's fixed leaves nothing, rather than underflowing
+        let tiny = ContextConfig {
+            max_context_tokens: Some(10),
+            ..config
+        };
+        assert_eq!(
+            ContextBuilder::for_model("gpt-4o", &tiny, &system_prompt, &[]).budget(),
+            0
+        );
+    }
+}
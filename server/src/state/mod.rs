@@ -1,2 +1,7 @@
 // exports state
 pub mod app_state;
+pub mod compaction;
+pub mod context_window;
+pub mod plan;
+pub mod redaction;
+pub mod usage;
@@ -0,0 +1,285 @@
+// plan-then-execute: the assistant proposes numbered steps, the user edits and approves them, and
+// the chat actions that follow report progress against them
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+use crate::error::IronError;
+use crate::llm::provider::ProviderError;
+
+// "1. do x", "2) do y"
+static NUMBERED_STEP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*\d+[.)]\s+(.+)$").expect("numbered step pattern"));
+// what the assistant writes to report progress, e.g. [step 2: done]
+static STEP_MARKER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\[step (\d+): (in_progress|done|skipped|failed)\]")
+        .expect("step marker pattern")
+});
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanStatus {
+    // waiting for the user to approve it
+    Proposed,
+    // being carried out
+    Approved,
+    // every step is done, skipped or failed
+    Completed,
+    // the user turned it down; only ever sent to the FE, the session forgets the plan
+    Rejected,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Pending,
+    InProgress,
+    Done,
+    Skipped,
+    Failed,
+}
+
+impl StepStatus {
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            StepStatus::Done | StepStatus::Skipped | StepStatus::Failed
+        )
+    }
+
+    // as it appears in step markers and the serialized plan
+    fn name(self) -> &'static str {
+        match self {
+            StepStatus::Pending => "pending",
+            StepStatus::InProgress => "in_progress",
+            StepStatus::Done => "done",
+            StepStatus::Skipped => "skipped",
+            StepStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        [
+            StepStatus::InProgress,
+            StepStatus::Done,
+            StepStatus::Skipped,
+            StepStatus::Failed,
+        ]
+        .into_iter()
+        .find(|status| status.name() == text.to_lowercase())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PlanStep {
+    // 1-based, as the assistant and the user refer to it
+    pub number: usize,
+    pub description: String,
+    pub status: StepStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Plan {
+    // the FE approves a plan by id, so a stale approval can't start a plan that was since replaced
+    pub id: Uuid,
+    pub status: PlanStatus,
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    // the numbered lines of the assistant's reply; anything around them is ignored
+    pub fn parse(reply: &str) -> Result<Self, IronError> {
+        let descriptions: Vec<String> = reply
+            .lines()
+            .filter_map(|line| NUMBERED_STEP.captures(line))
+            .map(|captures| captures[1].trim().to_string())
+            .collect();
+        if descriptions.is_empty() {
+            return Err(ProviderError::InvalidResponse(
+                "the assistant's plan didn't contain any numbered steps".into(),
+            )
+            .into());
+        }
+        Ok(Self::proposed(descriptions))
+    }
+
+    pub fn proposed(descriptions: Vec<String>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            status: PlanStatus::Proposed,
+            steps: descriptions
+                .into_iter()
+                .enumerate()
+                .map(|(i, description)| PlanStep {
+                    number: i + 1,
+                    description,
+                    status: StepStatus::Pending,
+                })
+                .collect(),
+        }
+    }
+
+    // the user's edits replace the steps wholesale
+    pub fn approve(&mut self, edited_steps: Option<Vec<String>>) -> Result<(), IronError> {
+        if let Some(descriptions) = edited_steps {
+            let descriptions: Vec<String> = descriptions
+                .into_iter()
+                .map(|step| step.trim().to_string())
+                .filter(|step| !step.is_empty())
+                .collect();
+            if descriptions.is_empty() {
+                return Err(IronError::Protocol("a plan needs at least one step".into()));
+            }
+            self.steps = Plan::proposed(descriptions).steps;
+        }
+        self.status = PlanStatus::Approved;
+        Ok(())
+    }
+
+    // applies every [step N: status] marker in `reply`; returns whether anything changed
+    pub fn track(&mut self, reply: &str) -> bool {
+        let mut changed = false;
+        for captures in STEP_MARKER.captures_iter(reply) {
+            let (Ok(number), Some(status)) = (
+                captures[1].parse::<usize>(),
+                StepStatus::parse(&captures[2]),
+            ) else {
+                continue;
+            };
+            if let Some(step) = self.steps.iter_mut().find(|step| step.number == number) {
+                changed |= step.status != status;
+                step.status = status;
+            }
+        }
+        if self.steps.iter().all(|step| step.status.is_finished()) {
+            changed |= self.status != PlanStatus::Completed;
+            self.status = PlanStatus::Completed;
+        }
+        changed
+    }
+
+    // the numbered steps, as the user gets to see them in the chat
+    pub fn numbered_steps(&self) -> String {
+        self.steps
+            .iter()
+            .map(|step| format!("{}. {}", step.number, step.description))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // appended to the system prompt while the plan is being carried out
+    pub fn prompt_section(&self) -> String {
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| {
+                format!(
+                    "{}. [{}] {}",
+                    step.number,
+                    step.status.name(),
+                    step.description
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "You are carrying out a plan the user approved:\n{}\n\
+Work through the steps in order. Whenever a step starts or finishes, say so in your reply with a marker \
+of the form [step N: in_progress], [step N: done], [step N: skipped] or [step N: failed].",
+            steps
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(plan: &Plan) -> Vec<StepStatus> {
+        plan.steps.iter().map(|step| step.status).collect()
+    }
+
+    #[test]
+    fn numbered_lines_become_steps() {
+        let plan = Plan::parse(
+            "Here's the plan:\n1. Check the logs\n2) Restart the service\n   3.  Verify it's up  \nLet me know!",
+        )
+        .unwrap();
+        assert_eq!(plan.status, PlanStatus::Proposed);
+        assert_eq!(
+            plan.numbered_steps(),
+            "1. Check the logs\n2. Restart the service\n3. Verify it's up"
+        );
+        assert_eq!(statuses(&plan), vec![StepStatus::Pending; 3]);
+    }
+
+    #[test]
+    fn reply_without_numbered_steps_is_not_a_plan() {
+        let err = Plan::parse("Sure, the answer is 42.").unwrap_err();
+        assert!(err.to_string().contains("numbered steps"), "{}", err);
+        // a number has to be followed by . or ) and a space
+        assert!(Plan::parse("2024 was a good year\n1.5 liters").is_err());
+    }
+
+    #[test]
+    fn approving_with_edits_renumbers_the_steps() {
+        let mut plan = Plan::parse("1. a\n2. b").unwrap();
+        plan.approve(Some(vec![
+            " c ".to_string(),
+            "".to_string(),
+            "d".to_string(),
+        ]))
+        .unwrap();
+        assert_eq!(plan.status, PlanStatus::Approved);
+        assert_eq!(plan.numbered_steps(), "1. c\n2. d");
+    }
+
+    #[test]
+    fn approving_without_any_steps_is_refused() {
+        let mut plan = Plan::parse("1. a").unwrap();
+        let err = plan.approve(Some(vec!["  ".to_string()])).unwrap_err();
+        assert!(matches!(err, IronError::Protocol(_)), "{}", err);
+        assert_eq!(plan.status, PlanStatus::Proposed);
+    }
+
+    #[test]
+    fn markers_move_steps_along() {
+        let mut plan = Plan::parse("1. a\n2. b\n3. c").unwrap();
+        plan.approve(None).unwrap();
+
+        assert!(plan.track("Starting. [step 1: in_progress]"));
+        assert_eq!(
+            statuses(&plan),
+            vec![
+                StepStatus::InProgress,
+                StepStatus::Pending,
+                StepStatus::Pending
+            ]
+        );
+        assert!(plan.track("[Step 1: DONE] and [step 2: skipped]"));
+        assert_eq!(plan.status, PlanStatus::Approved);
+        // nothing new, unknown steps and statuses are ignored
+        assert!(!plan.track("[step 2: skipped] [step 9: done] [step 3: maybe]"));
+
+        assert!(plan.track("[step 3: failed]"));
+        assert_eq!(
+            statuses(&plan),
+            vec![StepStatus::Done, StepStatus::Skipped, StepStatus::Failed]
+        );
+        assert_eq!(plan.status, PlanStatus::Completed);
+    }
+
+    #[test]
+    fn prompt_section_shows_each_steps_status() {
+        let mut plan = Plan::parse("1. a\n2. b").unwrap();
+        plan.approve(None).unwrap();
+        plan.track("[step 1: done]");
+        let section = plan.prompt_section();
+        assert!(
+            section.contains("1. [done] a\n2. [pending] b"),
+            "{}",
+            section
+        );
+    }
+}
@@ -0,0 +1,206 @@
+// the secrets a session has masked, so the same secret gets the same placeholder in every request
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::config::redaction::{placeholder, RedactionRules};
+
+// what the FE hears about a masked secret; never the secret itself
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct RedactedSecret {
+    pub placeholder: String,
+    pub kind: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct RedactionReport {
+    // masked for the first time since the last report
+    pub masked: Vec<RedactedSecret>,
+    // everything the session has masked so far
+    pub total: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct SecretLedger {
+    // secret -> index into `secrets`
+    placeholders: HashMap<String, usize>,
+    secrets: Vec<RedactedSecret>,
+    // how many secrets of each kind there are, to number the placeholders
+    counts: HashMap<String, usize>,
+    reported: usize,
+}
+
+impl SecretLedger {
+    pub fn redact(&mut self, rules: &RedactionRules, text: &str) -> String {
+        let findings = rules.find(text);
+        if findings.is_empty() {
+            return text.to_string();
+        }
+        let mut redacted = String::with_capacity(text.len());
+        let mut last = 0;
+        for finding in findings {
+            redacted.push_str(&text[last..finding.range.start]);
+            redacted.push_str(self.placeholder_for(&text[finding.range.clone()], &finding.kind));
+            last = finding.range.end;
+        }
+        redacted.push_str(&text[last..]);
+        redacted
+    }
+
+    // for tool call arguments: each string in the json is redacted on its own, so a placeholder can't break
+    // the json around it. arguments that aren't json are redacted as plain text, and arguments without
+    // secrets come back exactly as they were
+    pub fn redact_json(&mut self, rules: &RedactionRules, json: &str) -> String {
+        let Ok(mut value) = serde_json::from_str::<Value>(json) else {
+            return self.redact(rules, json);
+        };
+        if self.redact_value(rules, &mut value) {
+            value.to_string()
+        } else {
+            json.to_string()
+        }
+    }
+
+    // true when anything was masked
+    fn redact_value(&mut self, rules: &RedactionRules, value: &mut Value) -> bool {
+        match value {
+            Value::String(text) => {
+                let redacted = self.redact(rules, text);
+                let changed = redacted != *text;
+                *text = redacted;
+                changed
+            }
+            // every element, not just up to the first one with a secret
+            Value::Array(items) => {
+                let mut changed = false;
+                for item in items {
+                    changed |= self.redact_value(rules, item);
+                }
+                changed
+            }
+            Value::Object(fields) => {
+                let mut changed = false;
+                for field in fields.values_mut() {
+                    changed |= self.redact_value(rules, field);
+                }
+                changed
+            }
+            _ => false,
+        }
+    }
+
+    fn placeholder_for(&mut self, secret: &str, kind: &str) -> &str {
+        let index = match self.placeholders.get(secret) {
+            Some(&index) => index,
+            None => {
+                let number = self.counts.entry(kind.to_string()).or_default();
+                *number += 1;
+                self.secrets.push(RedactedSecret {
+                    placeholder: placeholder(kind, *number),
+                    kind: kind.to_string(),
+                });
+                self.placeholders
+                    .insert(secret.to_string(), self.secrets.len() - 1);
+                self.secrets.len() - 1
+            }
+        };
+        &self.secrets[index].placeholder
+    }
+
+    // None when nothing new was masked since the last report
+    pub fn take_report(&mut self) -> Option<RedactionReport> {
+        if self.reported == self.secrets.len() {
+            return None;
+        }
+        let masked = self.secrets[self.reported..].to_vec();
+        self.reported = self.secrets.len();
+        Some(RedactionReport {
+            masked,
+            total: self.secrets.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "sk-abcdefghijklmnopqrstuvwx";
+    const OTHER_KEY: &str = "sk-zyxwvutsrqponmlkjihgfedc";
+
+    #[test]
+    fn same_secret_keeps_its_placeholder() {
+        let rules = RedactionRules::default();
+        let mut ledger = SecretLedger::default();
+
+        let first = ledger.redact(&rules, &format!("key {} and {}", KEY, OTHER_KEY));
+        let again = ledger.redact(&rules, &format!("still {}", KEY));
+
+        assert_eq!(first, "key [REDACTED_API_KEY_1] and [REDACTED_API_KEY_2]");
+        assert_eq!(again, "still [REDACTED_API_KEY_1]");
+    }
+
+    #[test]
+    fn redacting_redacted_text_changes_nothing() {
+        let rules = RedactionRules::default();
+        let mut ledger = SecretLedger::default();
+        let once = ledger.redact(&rules, &format!("export OPENAI_API_KEY={}", KEY));
+        assert_eq!(ledger.redact(&rules, &once), once);
+        assert_eq!(ledger.take_report().unwrap().total, 1);
+    }
+
+    #[test]
+    fn reports_only_what_is_new() {
+        let rules = RedactionRules::default();
+        let mut ledger = SecretLedger::default();
+        assert!(ledger.take_report().is_none());
+
+        ledger.redact(&rules, KEY);
+        let report = ledger.take_report().unwrap();
+        assert_eq!(
+            report.masked,
+            vec![RedactedSecret {
+                placeholder: "[REDACTED_API_KEY_1]".to_string(),
+                kind: "api_key".to_string(),
+            }]
+        );
+        ledger.redact(&rules, KEY);
+        assert!(ledger.take_report().is_none());
+
+        ledger.redact(&rules, OTHER_KEY);
+        let report = ledger.take_report().unwrap();
+        assert_eq!(report.masked.len(), 1);
+        assert_eq!(report.total, 2);
+    }
+
+    #[test]
+    fn json_stays_json() {
+        let rules = RedactionRules::default();
+        let mut ledger = SecretLedger::default();
+        let arguments = serde_json::json!({
+            "command": format!("curl -H 'Authorization: Bearer {}' https://api", KEY),
+            "kind": "read_only",
+        })
+        .to_string();
+
+        let redacted: Value =
+            serde_json::from_str(&ledger.redact_json(&rules, &arguments)).unwrap();
+        assert_eq!(
+            redacted["command"],
+            "curl -H 'Authorization: Bearer [REDACTED_API_KEY_1]' https://api"
+        );
+        assert_eq!(redacted["kind"], "read_only");
+    }
+
+    #[test]
+    fn json_without_secrets_is_left_as_is() {
+        let rules = RedactionRules::default();
+        let mut ledger = SecretLedger::default();
+        let arguments = r#"{"kind":"read_only","command":"ls"}"#;
+        assert_eq!(ledger.redact_json(&rules, arguments), arguments);
+        assert_eq!(
+            ledger.redact_json(&rules, &format!("not json {}", KEY)),
+            "not json [REDACTED_API_KEY_1]"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// token usage and cost of every llm call a chat has made
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::llm::provider::TokenUsage;
+
+// who the call was made for
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageSource {
+    // the assistant answering the user (or a tool result)
+    Assistant,
+    // the autorun continuation standing in for the user
+    MockUser,
+    // summarizing older history to fit the context window
+    Compaction,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UsageRecord {
+    // the chat action the call belongs to; None for calls made between actions (e.g. the mock user)
+    pub action_id: Option<Uuid>,
+    pub source: UsageSource,
+    pub model: String,
+    pub usage: TokenUsage,
+    // None when the model isn't in the price table
+    pub cost_usd: Option<f64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq)]
+pub struct UsageTotals {
+    pub calls: usize,
+    pub usage: TokenUsage,
+    pub cost_usd: f64,
+    // calls whose cost isn't included in cost_usd because their model has no price
+    pub unpriced_calls: usize,
+}
+
+impl UsageTotals {
+    fn add(&mut self, record: &UsageRecord) {
+        self.calls += 1;
+        self.usage += record.usage;
+        match record.cost_usd {
+            Some(cost) => self.cost_usd += cost,
+            None => self.unpriced_calls += 1,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct UsageSummary {
+    pub total: UsageTotals,
+    pub by_source: HashMap<UsageSource, UsageTotals>,
+    pub by_model: HashMap<String, UsageTotals>,
+}
+
+#[derive(Debug, Default)]
+pub struct UsageLedger {
+    records: Vec<UsageRecord>,
+}
+
+impl UsageLedger {
+    pub fn record(&mut self, record: UsageRecord) {
+        self.records.push(record);
+    }
+
+    pub fn records(&self) -> &[UsageRecord] {
+        &self.records
+    }
+
+    pub fn summary(&self) -> UsageSummary {
+        let mut summary = UsageSummary::default();
+        for record in &self.records {
+            summary.total.add(record);
+            summary
+                .by_source
+                .entry(record.source)
+                .or_default()
+                .add(record);
+            summary
+                .by_model
+                .entry(record.model.clone())
+                .or_default()
+                .add(record);
+        }
+        summary
+    }
+
+    pub fn action_totals(&self, action_id: Uuid) -> UsageTotals {
+        let mut totals = UsageTotals::default();
+        for record in self
+            .records
+            .iter()
+            .filter(|record| record.action_id == Some(action_id))
+        {
+            totals.add(record);
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(
+        action_id: Option<Uuid>,
+        source: UsageSource,
+        model: &str,
+        cost_usd: Option<f64>,
+    ) -> UsageRecord {
+        UsageRecord {
+            action_id,
+            source,
+            model: model.to_string(),
+            usage: TokenUsage {
+                prompt_tokens: 100,
+                completion_tokens: 10,
+                cached_prompt_tokens: 40,
+                cache_write_prompt_tokens: 20,
+            },
+            cost_usd,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn summary_adds_up_by_source_and_model() {
+        let action = Uuid::new_v4();
+        let mut ledger = UsageLedger::default();
+        ledger.record(record(
+            Some(action),
+            UsageSource::Assistant,
+            "gpt-4o",
+            Some(0.5),
+        ));
+        ledger.record(record(None, UsageSource::MockUser, "gpt-4o", Some(0.25)));
+        ledger.record(record(Some(action), UsageSource::Compaction, "llama", None));
+
+        let summary = ledger.summary();
+        assert_eq!(summary.total.calls, 3);
+        assert_eq!(summary.total.usage.prompt_tokens, 300);
+        assert_eq!(summary.total.usage.cache_write_prompt_tokens, 60);
+        assert_eq!(summary.total.cost_usd, 0.75);
+        assert_eq!(summary.total.unpriced_calls, 1);
+        assert_eq!(summary.by_model["gpt-4o"].calls, 2);
+        assert_eq!(summary.by_model["llama"].unpriced_calls, 1);
+        assert_eq!(summary.by_source[&UsageSource::MockUser].cost_usd, 0.25);
+    }
+
+    #[test]
+    fn action_totals_only_count_that_action() {
+        let action = Uuid::new_v4();
+        let mut ledger = UsageLedger::default();
+        ledger.record(record(
+            Some(action),
+            UsageSource::Assistant,
+            "gpt-4o",
+            Some(0.5),
+        ));
+        ledger.record(record(
+            Some(Uuid::new_v4()),
+            UsageSource::Assistant,
+            "gpt-4o",
+            Some(1.0),
+        ));
+        ledger.record(record(None, UsageSource::MockUser, "gpt-4o", Some(2.0)));
+
+        let totals = ledger.action_totals(action);
+        assert_eq!(totals.calls, 1);
+        assert_eq!(totals.cost_usd, 0.5);
+        assert_eq!(ledger.action_totals(Uuid::new_v4()), UsageTotals::default());
+    }
+}
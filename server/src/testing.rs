@@ -0,0 +1,197 @@
+// test-only plumbing: a chat backed by whatever provider the test hands it, and a websocket standing in for the FE
+use futures_util::stream::SplitStream;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+use crate::config::app_config::{AppConfig, ProviderKind};
+use crate::llm::provider::LlmProvider;
+use crate::llm::registry::ProviderRegistry;
+use crate::prompts::library::PromptLibrary;
+use crate::protocol::WsWriteStream;
+use crate::state::app_state::ChatState;
+
+// the prompts that ship with the server, so rendering works the way it does in a real session
+pub(crate) fn config() -> AppConfig {
+    let prompts = Path::new(env!("CARGO_MANIFEST_DIR")).join("prompts");
+    AppConfig {
+        prompts: PromptLibrary::load(&prompts, "default").expect("bundled prompts load"),
+        ..AppConfig::default()
+    }
+}
+
+pub(crate) fn chat_state(provider: Arc<dyn LlmProvider>) -> Arc<ChatState> {
+    chat_state_with(provider, config())
+}
+
+// every call goes to `provider`, registered as the mock so no api key is needed
+pub(crate) fn chat_state_with(provider: Arc<dyn LlmProvider>, config: AppConfig) -> Arc<ChatState> {
+    let providers = HashMap::from([(ProviderKind::Mock, provider)]);
+    Arc::new(ChatState::new(
+        Uuid::new_v4(),
+        Arc::new(ProviderRegistry::new(ProviderKind::Mock, providers)),
+        Arc::new(config),
+    ))
+}
+
+// the server's end of a localhost websocket, plus the FE's end to read what it was sent
+pub(crate) struct Frontend {
+    pub write: WsWriteStream,
+    read: SplitStream<WebSocketStream<TcpStream>>,
+}
+
+impl Frontend {
+    pub async fn connect() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let tcp = TcpStream::connect(address).await.unwrap();
+            tokio_tungstenite::client_async(format!("ws://{}", address), tcp)
+                .await
+                .unwrap()
+                .0
+        });
+        let (tcp, _) = listener.accept().await.unwrap();
+        let server = tokio_tungstenite::accept_async(tcp).await.unwrap();
+        let (write, _) = server.split();
+        let (_, read) = client.await.unwrap().split();
+        Self {
+            write: Arc::new(Mutex::new(write)),
+            read,
+        }
+    }
+
+    // hangs up the server's end and returns every frame it sent, in order. the handler has to be done
+    // with its clones of `write` by now, or the connection stays open and this never returns
+    pub async fn frames(mut self) -> Vec<serde_json::Value> {
+        drop(self.write);
+        let mut frames = Vec::new();
+        while let Some(Ok(message)) = self.read.next().await {
+            if let Message::Text(text) = message {
+                frames.push(serde_json::from_str(&text).unwrap());
+            }
+        }
+        frames
+    }
+}
+
+pub(crate) fn frame_types(frames: &[serde_json::Value]) -> Vec<&str> {
+    frames
+        .iter()
+        .filter_map(|frame| frame["type"].as_str())
+        .collect()
+}
+
+// one canned http response of a stand-in provider
+pub(crate) struct Canned {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Canned {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            headers: vec![("content-type", "application/json".to_string())],
+            body: body.to_string(),
+        }
+    }
+
+    // `events` are (event name, data) pairs; the body ends wherever the last one does
+    pub fn event_stream(events: &[(&str, serde_json::Value)]) -> Self {
+        let body = events
+            .iter()
+            .map(|(event, data)| match event {
+                &"" => format!("data: {}\n\n", data),
+                event => format!("event: {}\ndata: {}\n\n", event, data),
+            })
+            .collect();
+        Self::sse_body(body)
+    }
+
+    pub fn sse_body(body: String) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("content-type", "text/event-stream".to_string())],
+            body,
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+// a provider on localhost that answers the requests it gets with `responses`, in order, and closes each
+// connection after answering. returns its base url and the bodies of the requests it got
+pub(crate) async fn stand_in(
+    responses: Vec<Canned>,
+) -> (String, Arc<std::sync::Mutex<Vec<serde_json::Value>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = Arc::clone(&requests);
+    tokio::spawn(async move {
+        for canned in responses {
+            let Ok((mut tcp, _)) = listener.accept().await else {
+                return;
+            };
+            // headers, then as much body as content-length says
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            let body_at = loop {
+                let read = tcp.read(&mut chunk).await.unwrap_or(0);
+                if read == 0 {
+                    break None;
+                }
+                request.extend_from_slice(&chunk[..read]);
+                if let Some(at) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break Some(at + 4);
+                }
+            };
+            let Some(body_at) = body_at else { continue };
+            let head = String::from_utf8_lossy(&request[..body_at]).to_lowercase();
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|length| length.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            while request.len() < body_at + length {
+                let read = tcp.read(&mut chunk).await.unwrap_or(0);
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&chunk[..read]);
+            }
+            if let Ok(body) = serde_json::from_slice(&request[body_at..]) {
+                received.lock().unwrap().push(body);
+            }
+
+            let mut response = format!(
+                "HTTP/1.1 {} Stand-in\r\nconnection: close\r\n",
+                canned.status
+            );
+            for (name, value) in &canned.headers {
+                response.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            response.push_str(&format!(
+                "content-length: {}\r\n\r\n{}",
+                canned.body.len(),
+                canned.body
+            ));
+            let _ = tcp.write_all(response.as_bytes()).await;
+            let _ = tcp.shutdown().await;
+        }
+    });
+    (base_url, requests)
+}
@@ -1,16 +1,22 @@
 use crate::handlers::chat::handle_openai_call_as_mock_user;
 use std::collections::HashMap;
-use std::fs;
 // websocket server entry point
-use crate::handlers::handler::{handle_chat_action, openai_message, ChatActionOutcome};
+use crate::error::IronError;
+use crate::exec::runner::TerminalSize;
+use crate::handlers::handler::{handle_chat_action, ChatActionOutcome};
+use crate::prompts::profile::PromptProfile;
+use crate::protocol::{
+    parse_frontend_frame, send_frame, ControlFrame, ErrorEvent, FrontendFrame, IncomingFrame,
+    ProfileEvent, WsWriteStream,
+};
 use crate::state::app_state::{
     ChatState, ContextMessage, MessageType, SharedChatState, UserChatPreferences,
 };
-use actix_web::body::MessageBody;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use log::info;
 use std::io::Error;
-use std::sync::{Arc, Mutex as SyncMutex};
+use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 use tokio::sync::Mutex as AsyncMutex;
@@ -19,7 +25,6 @@ use tokio_tungstenite::{accept_async, WebSocketStream};
 use uuid::Uuid;
 
 type ThreadWsMap = Arc<AsyncMutex<HashMap<Uuid, WebSocketPair>>>;
-type BoxError = Box<dyn std::error::Error + std::marker::Send + Sync + 'static>;
 
 #[derive(Debug)]
 struct WebSocketPair {
@@ -27,16 +32,12 @@ struct WebSocketPair {
     fe_ws: Option<WebSocketStream<TcpStream>>,
 }
 
-type ChatActionSender = mpsc::Sender<ChatActionOutcome>;
-type ChatActionReceiver = mpsc::Receiver<ChatActionOutcome>;
-
 // websocket server setup
 // #[tokio::main] i think this should be removed since tokio main is already in main.rs?
 pub async fn start_websocket_server(chat_state: Arc<ChatState>) -> Result<(), Error> {
     let _ = env_logger::try_init();
     let addr = "127.0.0.1:8008".to_string();
-    let try_socket = TcpListener::bind(&addr).await;
-    let listener = try_socket.expect("Failed to bind");
+    let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
 
     // we can't guarantee only one thread is only running this server, have to make it multithreaded
@@ -67,8 +68,10 @@ async fn handle_connection(
     stream: TcpStream,
     thread_ws_map: ThreadWsMap,
     chat_state: SharedChatState,
-) -> Result<(), BoxError> {
-    let addr = stream.peer_addr()?;
+) -> Result<(), IronError> {
+    let addr = stream
+        .peer_addr()
+        .map_err(|e| IronError::Protocol(e.to_string()))?;
     info!("New websocket peer address from: {}", addr);
 
     let ws_stream = accept_async(stream).await?;
@@ -98,17 +101,22 @@ async fn handle_connection(
 
         if entry.cli_ws.is_none() {
             println!("CLI connected for session: {}", id);
-            entry.cli_ws = Some(write.reunite(read).unwrap());
+            entry.cli_ws = Some(reunite(write, read)?);
         } else if entry.fe_ws.is_none() {
             println!("Frontend connected for session: {}", id);
-            entry.fe_ws = Some(write.reunite(read).unwrap());
+            entry.fe_ws = Some(reunite(write, read)?);
         }
 
-        if let (Some(fe_ws), Some(cli_ws)) = (entry.fe_ws.take(), entry.cli_ws.take()) {
+        if entry.fe_ws.is_some() && entry.cli_ws.is_some() {
             // we're in business, spawn a new child thread to handle the pair of websockets
             println!("Paired CLI and FE, starting handler...");
-            let pair = map.remove(&id).unwrap();
-            tokio::spawn(handle_cli_fe_pair(pair, chat_state));
+            if let Some(pair) = map.remove(&id) {
+                tokio::spawn(async move {
+                    if let Err(e) = handle_cli_fe_pair(pair, chat_state).await {
+                        eprintln!("Error handling session {}: {}", id, e);
+                    }
+                });
+            }
         }
     }
 
@@ -119,59 +127,139 @@ async fn handle_connection(
 async fn handle_cli_fe_pair(
     cli_fe_pair: WebSocketPair,
     chat_state: Arc<ChatState>,
-) -> Result<(), BoxError> {
+) -> Result<(), IronError> {
     // Your websocket handling logic here
-    let (mut cli_write_stream, mut cli_read_stream) = cli_fe_pair.cli_ws.unwrap().split();
-    let (mut fe_write_stream, mut fe_read_stream) = cli_fe_pair.fe_ws.unwrap().split();
+    let (cli_ws, fe_ws) = match (cli_fe_pair.cli_ws, cli_fe_pair.fe_ws) {
+        (Some(cli_ws), Some(fe_ws)) => (cli_ws, fe_ws),
+        _ => {
+            return Err(IronError::Protocol(
+                "session is missing its CLI or FE socket".into(),
+            ))
+        }
+    };
+    // commands run in the server's own shell, so nothing is ever written to the CLI; its half is just kept
+    // open for as long as the session is
+    let (_cli_write_stream, mut cli_read_stream) = cli_ws.split();
+    let (fe_write_stream, mut fe_read_stream) = fe_ws.split();
 
-    // Wrap the write streams in Arc<Mutex<>> to allow sharing across async tasks
-    let cli_write_stream = Arc::new(AsyncMutex::new(cli_write_stream));
+    // Wrap the write stream in Arc<Mutex<>> to allow sharing across async tasks
     let fe_write_stream = Arc::new(AsyncMutex::new(fe_write_stream));
 
     let (auto_run_tx, mut auto_run_rx) = mpsc::channel::<ChatActionOutcome>(1);
-    let current_chat_depth: u16 = 0;
-    let user_preferences = chat_state.user_preferences.lock().unwrap();
+    let mut current_chat_depth: u16 = 0;
+    let mut user_preferences = chat_state.get_preferences()?;
+    send_frame(
+        &fe_write_stream,
+        &FrontendFrame::Preferences(user_preferences.clone()),
+    )
+    .await?;
+    send_profile(&chat_state, chat_state.get_profile()?, &fe_write_stream).await?;
+    // a reconnecting FE picks up where the plan was
+    if let Some(plan) = chat_state.get_plan()? {
+        send_frame(&fe_write_stream, &FrontendFrame::Plan(plan)).await?;
+    }
     // let autorun_all = user_preferences.autorun_all;
     // let depth = user_preferences.autorun_all;
     // let autorun_readonly = user_preferences.autorun_readonly;
 
-    let mut autorun_enabled = determine_autorun_status(*user_preferences, current_chat_depth);
+    let mut autorun_enabled =
+        determine_autorun_status(user_preferences.clone(), current_chat_depth);
 
     loop {
         tokio::select! {
-            Some(fe_msg) = fe_read_stream.next() => {
+            fe_msg = fe_read_stream.next() => {
+                // the session lasts as long as the FE stays connected
+                let Some(fe_msg) = fe_msg else {
+                    break;
+                };
                 if let Ok(msg) = fe_msg {
                     if let Ok(text) = msg.into_text() {
-                        current_chat_depth += 1;
-                        if let Ok(typed_msg) = serde_json::from_str::<ContextMessage>(&text) {
-                            // hand over cli execution to an execution thread
-                            println!("FE message type: {:?}", typed_msg.message_type);
-                            println!("FE sent: {:?}", typed_msg.content);
-                            match typed_msg.message_type {
-                                MessageType::UserCancelCmd => {
-                                    // TODO: implement kill all children threads of the current thread which are executing chat actions
-                                    break;
+                        let typed_msg = match parse_frontend_frame(&text) {
+                            Ok(IncomingFrame::Message(typed_msg)) => typed_msg,
+                            Ok(IncomingFrame::Control(ControlFrame::SetPreferences { preferences })) => {
+                                match update_preferences(&chat_state, preferences, &fe_write_stream).await {
+                                    Ok(updated) => {
+                                        user_preferences = updated;
+                                        autorun_enabled = determine_autorun_status(user_preferences.clone(), current_chat_depth);
+                                    }
+                                    Err(err) => send_error(&fe_write_stream, &err).await,
+                                }
+                                continue;
+                            }
+                            Ok(IncomingFrame::Control(ControlFrame::SelectProfile { profile })) => {
+                                match select_profile(&chat_state, &profile, &fe_write_stream).await {
+                                    Ok(updated) => {
+                                        user_preferences = updated;
+                                        autorun_enabled = determine_autorun_status(user_preferences.clone(), current_chat_depth);
+                                    }
+                                    Err(err) => send_error(&fe_write_stream, &err).await,
                                 }
-                                MessageType::UserPrompt => {
-                                    // Handle UserPrompt
-                                    autorun_enabled = determine_autorun_status(user_preferences, current_chat_depth);
-                                    let chat_state_clone = Arc::clone(&chat_state);
-                                    let autorun_tx_clone = auto_run_tx.clone();
-
-                                    tokio::spawn(async move {
-                                        let outcome = handle_chat_action(typed_msg, chat_state, fe_write_stream, cli_write_stream).await;
-                                        if let Ok(outcome_status) = outcome {
-                                            let _ = autorun_tx_clone.send(outcome_status).await;
-                                        }
-                                    });
+                                continue;
+                            }
+                            // approving a plan is what sets it going, as if the user had asked for it
+                            Ok(IncomingFrame::Control(ControlFrame::ApprovePlan { plan_id, steps })) => {
+                                match approve_plan(&chat_state, plan_id, steps, &fe_write_stream).await {
+                                    Ok(typed_msg) => {
+                                        current_chat_depth += 1;
+                                        autorun_enabled = determine_autorun_status(user_preferences.clone(), current_chat_depth);
+                                        spawn_chat_action(typed_msg, &chat_state, &fe_write_stream, &auto_run_tx);
+                                    }
+                                    Err(err) => send_error(&fe_write_stream, &err).await,
                                 }
-                                MessageType::UserAckCmd => {
-                                    // Handle UserAckCmd
+                                continue;
+                            }
+                            Ok(IncomingFrame::Control(ControlFrame::RejectPlan { plan_id })) => {
+                                if let Err(err) = reject_plan(&chat_state, plan_id, &fe_write_stream).await {
+                                    send_error(&fe_write_stream, &err).await;
                                 }
-                                _ => {
-                                    // Handle other cases
-                                    // should throw an err here, since we shouldn't expect to see anything else from the FE
+                                continue;
+                            }
+                            Ok(IncomingFrame::Control(ControlFrame::ResizeTerminal { cols, rows })) => {
+                                if let Err(err) = chat_state.resize_terminal(TerminalSize { cols, rows }) {
+                                    send_error(&fe_write_stream, &err).await;
                                 }
+                                continue;
+                            }
+                            Ok(IncomingFrame::Control(ControlFrame::CommandInput { command_id, data })) => {
+                                if let Err(err) = chat_state.send_command_input(&command_id, data) {
+                                    send_error(&fe_write_stream, &err).await;
+                                }
+                                continue;
+                            }
+                            Err(err) => {
+                                send_error(&fe_write_stream, &err).await;
+                                continue;
+                            }
+                        };
+                        current_chat_depth += 1;
+                        // hand over cli execution to an execution thread
+                        println!("FE message type: {:?}", typed_msg.message_type);
+                        println!("FE sent: {:?}", typed_msg.content);
+                        match typed_msg.message_type {
+                            MessageType::UserCancelCmd => {
+                                // running commands get killed and report back as cancelled; the session stays open
+                                // TODO: also stop the chat actions themselves, not just their commands
+                                chat_state.cancel_commands();
+                            }
+                            MessageType::UserPrompt => {
+                                // Handle UserPrompt
+                                autorun_enabled = determine_autorun_status(user_preferences.clone(), current_chat_depth);
+                                spawn_chat_action(typed_msg, &chat_state, &fe_write_stream, &auto_run_tx);
+                            }
+                            // runs a command that was waiting for approval
+                            MessageType::UserAckCmd => {
+                                let approved = typed_msg
+                                    .tool_call_id
+                                    .as_deref()
+                                    .ok_or_else(|| IronError::Protocol("UserAckCmd needs the tool_call_id of the command it approves".into()))
+                                    .and_then(|command_id| chat_state.approve_command(command_id));
+                                if let Err(err) = approved {
+                                    send_error(&fe_write_stream, &err).await;
+                                }
+                            }
+                            _ => {
+                                // Handle other cases
+                                // should throw an err here, since we shouldn't expect to see anything else from the FE
                             }
                         }
                     }
@@ -196,8 +284,9 @@ async fn handle_cli_fe_pair(
                         let next_msg = match get_next_mock_user_message_autorun_mode(&chat_state).await {
                             Ok(completion) => completion,
                             Err(err) => {
-                                // quit this
-                                err.to_string()
+                                // quit autorun and hand control back to the user
+                                send_error(&fe_write_stream, &err).await;
+                                continue;
                             }
                         };
 
@@ -205,18 +294,12 @@ async fn handle_cli_fe_pair(
                             message_type: MessageType::UserPrompt,
                             content: next_msg,
                             timestamp: Some(chrono::Utc::now()),
+                            tool_call_id: None,
+                            command_result: None,
                         };
 
-                        autorun_enabled = determine_autorun_status(user_preferences, current_chat_depth);
-                        let chat_state_clone = Arc::clone(&chat_state);
-                        let autorun_tx_clone = auto_run_tx.clone();
-
-                        tokio::spawn(async move {
-                            let outcome = handle_chat_action(typed_msg, chat_state, fe_write_stream, cli_write_stream).await;
-                            if let Ok(outcome_status) = outcome {
-                                let _ = autorun_tx_clone.send(outcome_status).await;
-                            }
-                        });
+                        autorun_enabled = determine_autorun_status(user_preferences.clone(), current_chat_depth);
+                        spawn_chat_action(typed_msg, &chat_state, &fe_write_stream, &auto_run_tx);
                     },
                     ChatActionOutcome::Stop => {
                         // we should hand control back to the frontend, and await further things
@@ -233,26 +316,143 @@ async fn handle_cli_fe_pair(
 }
 
 // ========================= UTIL FUNCTIONS ==============================
+// runs a chat action in the background; a failed action is reported to the FE instead of taking the session down
+fn spawn_chat_action(
+    typed_msg: ContextMessage,
+    chat_state: &SharedChatState,
+    fe_write_stream: &WsWriteStream,
+    autorun_tx: &mpsc::Sender<ChatActionOutcome>,
+) {
+    let chat_state = Arc::clone(chat_state);
+    let fe_write_stream = Arc::clone(fe_write_stream);
+    let autorun_tx = autorun_tx.clone();
+
+    tokio::spawn(async move {
+        match handle_chat_action(typed_msg, chat_state, Arc::clone(&fe_write_stream)).await {
+            Ok(outcome_status) => {
+                let _ = autorun_tx.send(outcome_status).await;
+            }
+            Err(err) => send_error(&fe_write_stream, &err).await,
+        }
+    });
+}
+
+// echoes the preferences back, so the FE always shows what the server is actually using
+async fn update_preferences(
+    chat_state: &ChatState,
+    preferences: UserChatPreferences,
+    fe_write_stream: &WsWriteStream,
+) -> Result<UserChatPreferences, IronError> {
+    chat_state.set_preferences(preferences)?;
+    let updated = chat_state.get_preferences()?;
+    println!(
+        "Updated preferences for chat {}: {:?}",
+        chat_state.chat_id, updated
+    );
+    send_frame(
+        fe_write_stream,
+        &FrontendFrame::Preferences(updated.clone()),
+    )
+    .await?;
+    Ok(updated)
+}
+
+// returns the preferences the profile left the session with
+async fn select_profile(
+    chat_state: &ChatState,
+    name: &str,
+    fe_write_stream: &WsWriteStream,
+) -> Result<UserChatPreferences, IronError> {
+    let profile = chat_state.set_profile(name)?;
+    println!(
+        "Chat {} switched to profile {}",
+        chat_state.chat_id, profile.name
+    );
+    send_profile(chat_state, profile, fe_write_stream).await?;
+    let preferences = chat_state.get_preferences()?;
+    send_frame(
+        fe_write_stream,
+        &FrontendFrame::Preferences(preferences.clone()),
+    )
+    .await?;
+    Ok(preferences)
+}
+
+// returns the message that starts carrying out the plan; it spells out the steps, so the chat shows
+// what was approved
+async fn approve_plan(
+    chat_state: &ChatState,
+    plan_id: Uuid,
+    steps: Option<Vec<String>>,
+    fe_write_stream: &WsWriteStream,
+) -> Result<ContextMessage, IronError> {
+    let plan = chat_state.approve_plan(plan_id, steps)?;
+    println!("Chat {} approved plan {}", chat_state.chat_id, plan.id);
+    send_frame(fe_write_stream, &FrontendFrame::Plan(plan.clone())).await?;
+    Ok(ContextMessage {
+        message_type: MessageType::UserPrompt,
+        content: format!(
+            "I approved this plan, please carry it out:\n{}",
+            plan.numbered_steps()
+        ),
+        timestamp: Some(chrono::Utc::now()),
+        tool_call_id: None,
+        command_result: None,
+    })
+}
+
+async fn reject_plan(
+    chat_state: &ChatState,
+    plan_id: Uuid,
+    fe_write_stream: &WsWriteStream,
+) -> Result<(), IronError> {
+    let plan = chat_state.reject_plan(plan_id)?;
+    println!("Chat {} rejected plan {}", chat_state.chat_id, plan.id);
+    send_frame(fe_write_stream, &FrontendFrame::Plan(plan)).await
+}
+
+async fn send_profile(
+    chat_state: &ChatState,
+    profile: PromptProfile,
+    fe_write_stream: &WsWriteStream,
+) -> Result<(), IronError> {
+    let frame = FrontendFrame::Profile(ProfileEvent {
+        active: profile,
+        available: chat_state.config.prompts.profile_names(),
+    });
+    send_frame(fe_write_stream, &frame).await
+}
+
+async fn send_error(fe_write_stream: &WsWriteStream, err: &IronError) {
+    let frame = FrontendFrame::Error(ErrorEvent::from(err));
+    if let Err(send_err) = send_frame(fe_write_stream, &frame).await {
+        eprintln!("Error reporting \"{}\" to the frontend: {}", err, send_err);
+    }
+}
+
+fn reunite(
+    write: SplitSink<WebSocketStream<TcpStream>, Message>,
+    read: SplitStream<WebSocketStream<TcpStream>>,
+) -> Result<WebSocketStream<TcpStream>, IronError> {
+    write
+        .reunite(read)
+        .map_err(|e| IronError::Protocol(e.to_string()))
+}
+
 fn determine_autorun_status(
     user_preferences: UserChatPreferences,
     current_chat_depth: u16,
 ) -> bool {
-    let depth = user_preferences.depth;
-    let autorun_readonly = user_preferences.autorun_readonly;
-    let autorun_all = user_preferences.autorun_all;
-    return true;
+    let within_depth = current_chat_depth <= user_preferences.depth;
+    within_depth && (user_preferences.autorun_readonly || user_preferences.autorun_all)
 }
 
-async fn get_next_mock_user_message_autorun_mode(
-    state: &ChatState,
-) -> Result<String, Box<dyn std::error::Error + 'static>> {
+async fn get_next_mock_user_message_autorun_mode(state: &ChatState) -> Result<String, IronError> {
     match handle_openai_call_as_mock_user(state).await {
-        Ok(completion) => {
-            return Ok(completion);
-        }
+        Ok(completion) => Ok(completion),
         Err(err) => {
             eprintln!("Error triggering a mock user message in autorun: {}", err);
-            return Err(err.into());
+            Err(err)
         }
-    };
+    }
 }